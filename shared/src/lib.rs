@@ -0,0 +1,299 @@
+//! Types shared by the server and the frontend.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Piconeros in one XMR.
+const PICONEROS_PER_XMR: u64 = 1_000_000_000_000;
+
+/// Version of [`InvoiceUpdate`]. Bump it whenever the meaning or shape of an update changes, so
+/// a page loaded before a deploy can tell it is talking to a newer server.
+pub const INVOICE_UPDATE_VERSION: u32 = 2;
+
+/// The state of an AcceptXMR demo invoice, as sent to the buyer's browser by `/update` and the
+/// invoice websocket.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InvoiceUpdate {
+    pub version: u32,
+    pub status: InvoiceStatus,
+    pub address: String,
+    /// Piconeros received so far.
+    pub amount_paid: u64,
+    /// Piconeros requested.
+    pub amount_requested: u64,
+    /// `monero:` URI for wallets, as shown in the QR code.
+    pub uri: String,
+    /// Confirmations of the payment, once paid in full.
+    pub confirmations: Option<u64>,
+    pub confirmations_required: u64,
+    /// Blocks until the invoice expires.
+    pub expiration_in: u64,
+    /// Rate the invoice was priced at. Missing for invoices priced in XMR.
+    pub exchange_rate: Option<LockedRate>,
+}
+
+impl InvoiceUpdate {
+    /// Parse an update, checking its version before anything else so that updates from a newer
+    /// server are recognized even if their shape has changed.
+    pub fn from_json(json: &str) -> Result<Self, UpdateError> {
+        let value: Value = serde_json::from_str(json).map_err(|_| UpdateError::Invalid)?;
+        match value.get("version").and_then(Value::as_u64) {
+            Some(version) if version == u64::from(INVOICE_UPDATE_VERSION) => {
+                serde_json::from_value(value).map_err(|_| UpdateError::Invalid)
+            }
+            Some(version) => Err(UpdateError::UnsupportedVersion(version)),
+            None => Err(UpdateError::Invalid),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UpdateError {
+    UnsupportedVersion(u64),
+    Invalid,
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported invoice update version {version}")
+            }
+            Self::Invalid => write!(f, "invalid invoice update"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InvoiceStatus {
+    /// Nothing received yet.
+    Pending,
+    /// Part of the amount received, still waiting for the rest.
+    Underpaid,
+    /// Paid in full, waiting for confirmations.
+    PaidUnconfirmed,
+    /// Paid in full with enough confirmations.
+    Confirmed,
+    /// Paid more than requested, with enough confirmations.
+    Overpaid,
+    /// Paid in full after it expired, with enough confirmations.
+    PaidLate,
+    /// Expired before anything was paid.
+    Expired,
+    /// Expired with only part of the amount paid.
+    ExpiredUnderpaid,
+}
+
+impl InvoiceStatus {
+    /// Every status, in the order an invoice goes through them.
+    pub const ALL: [Self; 8] = [
+        Self::Pending,
+        Self::Underpaid,
+        Self::PaidUnconfirmed,
+        Self::Confirmed,
+        Self::Overpaid,
+        Self::PaidLate,
+        Self::Expired,
+        Self::ExpiredUnderpaid,
+    ];
+
+    /// The status of an invoice. `paid_late` is whether it was only paid in full after it
+    /// expired.
+    pub fn new(
+        amount_paid: u64,
+        amount_requested: u64,
+        confirmations: Option<u64>,
+        confirmations_required: u64,
+        expired: bool,
+        paid_late: bool,
+    ) -> Self {
+        if amount_paid >= amount_requested {
+            let confirmed =
+                confirmations.is_some_and(|confirmations| confirmations >= confirmations_required);
+            if !confirmed {
+                Self::PaidUnconfirmed
+            } else if paid_late {
+                Self::PaidLate
+            } else if amount_paid > amount_requested {
+                Self::Overpaid
+            } else {
+                Self::Confirmed
+            }
+        } else if expired && amount_paid > 0 {
+            Self::ExpiredUnderpaid
+        } else if expired {
+            Self::Expired
+        } else if amount_paid > 0 {
+            Self::Underpaid
+        } else {
+            Self::Pending
+        }
+    }
+
+    /// The status as it appears in updates, e.g. `paid_unconfirmed`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Underpaid => "underpaid",
+            Self::PaidUnconfirmed => "paid_unconfirmed",
+            Self::Confirmed => "confirmed",
+            Self::Overpaid => "overpaid",
+            Self::PaidLate => "paid_late",
+            Self::Expired => "expired",
+            Self::ExpiredUnderpaid => "expired_underpaid",
+        }
+    }
+
+    /// Whether the buyer's page can stop watching the invoice. Expired invoices aren't final:
+    /// the server keeps watching them for a while, and a late payment can still complete them.
+    pub fn is_final(self) -> bool {
+        self.is_complete()
+    }
+
+    /// Whether the invoice has been paid in full, with enough confirmations.
+    pub fn is_complete(self) -> bool {
+        matches!(self, Self::Confirmed | Self::Overpaid | Self::PaidLate)
+    }
+
+    /// Whether the invoice expired before it was paid in full.
+    pub fn is_expired(self) -> bool {
+        matches!(self, Self::Expired | Self::ExpiredUnderpaid)
+    }
+}
+
+/// The exchange rate an invoice was priced at.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LockedRate {
+    pub currency: String,
+    /// Price of one XMR in `currency`.
+    pub rate: f64,
+    /// Invoice total, in hundredths of `currency`.
+    pub total_cents: u64,
+}
+
+/// Format an amount of piconeros as XMR, keeping every digit.
+pub fn format_xmr(piconeros: u64) -> String {
+    format!(
+        "{}.{:012}",
+        piconeros / PICONEROS_PER_XMR,
+        piconeros % PICONEROS_PER_XMR
+    )
+}
+
+/// Format an amount of piconeros as XMR without trailing zeros, e.g. "0.001".
+pub fn format_xmr_short(piconeros: u64) -> String {
+    let xmr = format_xmr(piconeros);
+    xmr.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Format hundredths of a currency, e.g. 1250 as "12.50".
+pub fn format_cents(cents: u64) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(status: InvoiceStatus, exchange_rate: Option<LockedRate>) -> InvoiceUpdate {
+        InvoiceUpdate {
+            version: INVOICE_UPDATE_VERSION,
+            status,
+            address: "84Ph3RzZVa8cNRLnp4rJz6dc4JHKnNcxo1zwNAgrdmGMzVwbewDHcYRFSBgpCHQaDQNjNGmQJqZ9pzdmNuHtREvBKPQvnsu".to_string(),
+            amount_paid: 250_000_000,
+            amount_requested: 1_000_000_000,
+            uri: "monero:84Ph3RzZ?tx_amount=0.001".to_string(),
+            confirmations: None,
+            confirmations_required: 2,
+            expiration_in: 5,
+            exchange_rate,
+        }
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let rate = LockedRate {
+            currency: "USD".to_string(),
+            rate: 150.25,
+            total_cents: 25,
+        };
+        for status in InvoiceStatus::ALL {
+            for exchange_rate in [None, Some(rate.clone())] {
+                let update = update(status, exchange_rate);
+                let json = serde_json::to_string(&update).unwrap();
+                assert_eq!(
+                    serde_json::from_str::<InvoiceUpdate>(&json).unwrap(),
+                    update
+                );
+            }
+            assert_eq!(serde_json::to_value(status).unwrap(), status.as_str());
+        }
+    }
+
+    #[test]
+    fn wire_format_is_stable() {
+        let json = serde_json::to_value(update(InvoiceStatus::PaidUnconfirmed, None)).unwrap();
+        assert_eq!(json["version"], 2);
+        assert_eq!(json["status"], "paid_unconfirmed");
+        assert_eq!(json["confirmations"], serde_json::Value::Null);
+        assert_eq!(json["exchange_rate"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn checks_version_before_parsing() {
+        let update = update(InvoiceStatus::Pending, None);
+        let json = serde_json::to_string(&update).unwrap();
+        assert_eq!(InvoiceUpdate::from_json(&json), Ok(update));
+        assert_eq!(
+            InvoiceUpdate::from_json(r#"{"version": 1, "state": "new"}"#),
+            Err(UpdateError::UnsupportedVersion(1))
+        );
+        assert_eq!(
+            InvoiceUpdate::from_json(r#"{"address": "84Ph"}"#),
+            Err(UpdateError::Invalid)
+        );
+    }
+
+    #[test]
+    fn formats_amounts_exactly() {
+        assert_eq!(format_xmr(1_000_000_000), "0.001000000000");
+        assert_eq!(format_xmr(u64::MAX), "18446744.073709551615");
+        assert_eq!(format_xmr_short(1_000_000_000), "0.001");
+        assert_eq!(format_xmr_short(2 * PICONEROS_PER_XMR), "2");
+        assert_eq!(format_xmr_short(0), "0");
+        assert_eq!(format_xmr_short(u64::MAX), "18446744.073709551615");
+        assert_eq!(format_cents(1250), "12.50");
+        assert_eq!(format_cents(7), "0.07");
+    }
+
+    #[test]
+    fn status_follows_payment() {
+        let status = |paid, confirmations, expired, paid_late| {
+            InvoiceStatus::new(paid, 100, confirmations, 2, expired, paid_late)
+        };
+        assert_eq!(status(0, None, false, false), InvoiceStatus::Pending);
+        assert_eq!(status(40, None, false, false), InvoiceStatus::Underpaid);
+        assert_eq!(status(0, None, true, false), InvoiceStatus::Expired);
+        assert_eq!(
+            status(40, None, true, false),
+            InvoiceStatus::ExpiredUnderpaid
+        );
+        assert_eq!(
+            status(100, Some(1), false, false),
+            InvoiceStatus::PaidUnconfirmed
+        );
+        assert_eq!(status(100, Some(2), true, false), InvoiceStatus::Confirmed);
+        assert_eq!(status(120, Some(2), true, false), InvoiceStatus::Overpaid);
+        assert_eq!(
+            status(100, Some(0), true, true),
+            InvoiceStatus::PaidUnconfirmed
+        );
+        assert_eq!(status(120, Some(3), true, true), InvoiceStatus::PaidLate);
+        assert!(!InvoiceStatus::PaidUnconfirmed.is_final());
+        assert!(!InvoiceStatus::ExpiredUnderpaid.is_final());
+        assert!(InvoiceStatus::PaidLate.is_final());
+    }
+}
@@ -0,0 +1,161 @@
+use std::future::{ready, Ready};
+
+use actix_web::{
+    dev::Payload,
+    error::{ErrorForbidden, ErrorNotFound, InternalError},
+    http::{
+        header::{self, CacheControl, CacheDirective},
+        Method,
+    },
+    web, FromRequest, HttpRequest, HttpResponse,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::warn;
+use serde::Deserialize;
+
+use crate::email::escape_html;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct AdminSettings {
+    pub username: String,
+}
+
+impl Default for AdminSettings {
+    fn default() -> Self {
+        Self {
+            username: "admin".to_string(),
+        }
+    }
+}
+
+/// Credentials for the admin area. The admin area is disabled if no password is set.
+pub struct AdminCredentials {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+/// Extractor that only succeeds for requests carrying the admin's HTTP basic auth credentials.
+/// Add it as an argument to any handler that should be restricted to the site owner.
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authorize(req))
+    }
+}
+
+fn authorize(req: &HttpRequest) -> Result<Admin, actix_web::Error> {
+    let credentials = req
+        .app_data::<web::Data<AdminCredentials>>()
+        .expect("Admin credentials not registered");
+    let Some(password) = &credentials.password else {
+        return Err(ErrorNotFound("Not found"));
+    };
+
+    let authorized = basic_auth(req).is_some_and(|(user, pass)| {
+        // `blake3::Hash` comparisons are constant time.
+        blake3::hash(user.as_bytes()) == blake3::hash(credentials.username.as_bytes())
+            && blake3::hash(pass.as_bytes()) == blake3::hash(password.as_bytes())
+    });
+    if !authorized {
+        warn!("Unauthorized admin request to {}", req.path());
+        let mut response = HttpResponse::Unauthorized();
+        response.insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"Admin\""));
+        return Err(InternalError::from_response("Unauthorized", response.finish()).into());
+    }
+
+    // Browsers send basic auth credentials with cross-site requests too, so make sure anything
+    // that changes state came from one of our own pages.
+    if req.method() != Method::GET && req.method() != Method::HEAD && !same_origin(req) {
+        warn!("Rejected cross-origin admin request to {}", req.path());
+        return Err(ErrorForbidden("Cross-origin request"));
+    }
+
+    Ok(Admin)
+}
+
+fn basic_auth(req: &HttpRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, pass) = decoded.split_once(':')?;
+    Some((user.to_string(), pass.to_string()))
+}
+
+fn same_origin(req: &HttpRequest) -> bool {
+    let host = req.connection_info().host().to_string();
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .or_else(|| req.headers().get(header::REFERER))
+        .and_then(|origin| origin.to_str().ok());
+    match origin {
+        Some(origin) => origin
+            .split_once("://")
+            .map(|(_, rest)| rest.split('/').next() == Some(host.as_str()))
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Wrap the body of an admin page in a minimal HTML document.
+pub fn page(title: &str, body: &str) -> HttpResponse {
+    let title = escape_html(title);
+    HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\">
+<title>{title} | Admin</title>
+<style>
+body {{ font-family: sans-serif; margin: 1em 2em; background: #161616; color: #e0e0e0; }}
+a {{ color: #0a9dff; }}
+nav a {{ margin-right: 1em; }}
+table {{ border-collapse: collapse; width: 100%; }}
+th, td {{ border-bottom: 1px solid #404040; padding: 0.4em; text-align: left; vertical-align: top; }}
+.message {{ white-space: pre-wrap; max-width: 60ch; }}
+form.inline {{ display: inline; }}
+</style>
+</head>
+<body>
+<nav><a href=\"/admin/inbox\">Inbox</a><a href=\"/admin/invoices\">Invoices</a></nav>
+<h1>{title}</h1>
+{body}
+</body>
+</html>"
+        ))
+}
+
+/// Quote a value for a CSV file if it contains anything that would otherwise break the row. Text
+/// that a spreadsheet would interpret as a formula is prefixed with an apostrophe.
+pub fn csv_field(value: &str) -> String {
+    let is_formula = value.starts_with(['=', '+', '@', '\t', '\r'])
+        || (value.starts_with('-') && value.parse::<f64>().is_err());
+    let value = if is_formula {
+        format!("'{value}")
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// Join values into one CSV line, including the trailing line break.
+pub fn csv_row(values: &[&str]) -> String {
+    values
+        .iter()
+        .map(|value| csv_field(value))
+        .collect::<Vec<_>>()
+        .join(",")
+        + "\r\n"
+}
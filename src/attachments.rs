@@ -0,0 +1,246 @@
+use actix_multipart::{Field, MultipartError};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lettre::message::{header::ContentType, Attachment as AttachmentPart, SinglePart};
+use serde::Deserialize;
+
+use crate::email::header_summary;
+
+/// Longest attachment file name kept. Longer names are shortened.
+const MAX_FILENAME_LEN: usize = 100;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct AttachmentSettings {
+    /// Largest accepted file, in bytes.
+    pub max_size: usize,
+    /// Most files accepted with one submission. Zero disables attachments.
+    pub max_count: usize,
+    /// MIME types accepted, as detected from the file's content.
+    pub allowed_types: Vec<String>,
+}
+
+impl Default for AttachmentSettings {
+    fn default() -> Self {
+        Self {
+            max_size: 5 * 1024 * 1024,
+            max_count: 3,
+            allowed_types: [
+                "application/pdf",
+                "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+                "application/vnd.oasis.opendocument.text",
+                "application/zip",
+                "image/png",
+                "image/jpeg",
+                "text/plain",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+        }
+    }
+}
+
+/// A file uploaded through the contact form, already checked against [`AttachmentSettings`].
+pub struct Attachment {
+    pub filename: String,
+    /// MIME type detected from the file's content.
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// A file sent with a JSON submission.
+#[derive(Deserialize)]
+pub struct EncodedAttachment {
+    pub filename: String,
+    /// The file's content, base64 encoded.
+    pub data: String,
+}
+
+pub enum AttachmentError {
+    /// The file was rejected. The message is meant for the person who uploaded it.
+    Invalid(String),
+    Multipart(MultipartError),
+}
+
+impl Attachment {
+    /// Read a file field from a multipart form. Returns `None` for a file input left empty.
+    pub async fn read(
+        field: &mut Field,
+        settings: &AttachmentSettings,
+    ) -> Result<Option<Self>, AttachmentError> {
+        let filename = field
+            .content_disposition()
+            .and_then(|disposition| disposition.get_filename())
+            .map(str::to_string);
+        let data = match field.bytes(settings.max_size).await {
+            Ok(data) => data.map_err(AttachmentError::Multipart)?,
+            Err(_) => {
+                return Err(AttachmentError::Invalid(format!(
+                    "{} is larger than {}.",
+                    sanitize_filename(filename.as_deref().unwrap_or_default()),
+                    format_size(settings.max_size)
+                )))
+            }
+        };
+        // Browsers send an empty, unnamed file when nothing was chosen.
+        if data.is_empty() && filename.as_deref().unwrap_or_default().is_empty() {
+            return Ok(None);
+        }
+        Self::new(filename.as_deref(), data.to_vec(), settings)
+            .map(Some)
+            .map_err(AttachmentError::Invalid)
+    }
+
+    /// Decode a file from a JSON submission.
+    pub fn decode(file: &EncodedAttachment, settings: &AttachmentSettings) -> Result<Self, String> {
+        // Refuse oversized files before decoding them.
+        if file.data.len() / 4 * 3 > settings.max_size.saturating_add(2) {
+            return Err(format!(
+                "{} is larger than {}.",
+                sanitize_filename(&file.filename),
+                format_size(settings.max_size)
+            ));
+        }
+        let data = STANDARD
+            .decode(file.data.trim())
+            .map_err(|_| format!("{} could not be read.", sanitize_filename(&file.filename)))?;
+        Self::new(Some(&file.filename), data, settings)
+    }
+
+    /// Check an uploaded file's size and content. The name and type sent by the browser are not
+    /// trusted: the type comes from the content and the name is only used for display.
+    fn new(
+        filename: Option<&str>,
+        data: Vec<u8>,
+        settings: &AttachmentSettings,
+    ) -> Result<Self, String> {
+        let filename = sanitize_filename(filename.unwrap_or_default());
+        if data.is_empty() {
+            return Err(format!("{filename} is empty."));
+        }
+        if data.len() > settings.max_size {
+            return Err(format!(
+                "{filename} is larger than {}.",
+                format_size(settings.max_size)
+            ));
+        }
+        let content_type = sniff(&data);
+        if !settings
+            .allowed_types
+            .iter()
+            .any(|allowed| allowed == content_type)
+        {
+            return Err(format!("{filename} is not an accepted type of file."));
+        }
+        Ok(Self {
+            filename,
+            content_type: content_type.to_string(),
+            data,
+        })
+    }
+
+    /// The attachment as an email part.
+    pub fn part(&self) -> SinglePart {
+        let content_type = ContentType::parse(&self.content_type)
+            .unwrap_or_else(|_| ContentType::parse("application/octet-stream").unwrap());
+        AttachmentPart::new(self.filename.clone()).body(self.data.clone(), content_type)
+    }
+}
+
+/// Detect a file's MIME type from its content. Anything unrecognised that is valid UTF-8 without
+/// NUL bytes is treated as plain text.
+fn sniff(data: &[u8]) -> &'static str {
+    match infer::get(data) {
+        Some(kind) => kind.mime_type(),
+        None if !data.contains(&0) && std::str::from_utf8(data).is_ok() => "text/plain",
+        None => "application/octet-stream",
+    }
+}
+
+/// Reduce a file name from the client to a short, single line, with any directories removed.
+fn sanitize_filename(filename: &str) -> String {
+    let filename = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let filename = header_summary(filename, MAX_FILENAME_LEN);
+    if filename.is_empty() || filename.starts_with('.') {
+        format!("attachment{filename}")
+    } else {
+        filename
+    }
+}
+
+fn format_size(bytes: usize) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{} MiB", bytes / (1024 * 1024))
+    } else if bytes >= 1024 {
+        format!("{} KiB", bytes / 1024)
+    } else {
+        format!("{bytes} bytes")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01\x08\x06\0\0\0";
+
+    #[test]
+    fn type_comes_from_content_not_name() {
+        let settings = AttachmentSettings::default();
+        let png = Attachment::new(Some("notes.txt"), PNG.to_vec(), &settings).unwrap();
+        assert_eq!(png.content_type, "image/png");
+
+        let exe = b"MZ\x90\0\x03\0\0\0\x04\0\0\0\xff\xff\0\0".to_vec();
+        assert_eq!(
+            Attachment::new(Some("cv.pdf"), exe, &settings)
+                .err()
+                .as_deref(),
+            Some("cv.pdf is not an accepted type of file.")
+        );
+
+        let log = Attachment::new(Some("boot.log"), b"ok\n".to_vec(), &settings).unwrap();
+        assert_eq!(log.content_type, "text/plain");
+    }
+
+    #[test]
+    fn rejects_oversized_files() {
+        let settings = AttachmentSettings {
+            max_size: 2048,
+            ..AttachmentSettings::default()
+        };
+        assert_eq!(
+            Attachment::new(Some("big.txt"), vec![b'a'; 2049], &settings)
+                .err()
+                .as_deref(),
+            Some("big.txt is larger than 2 KiB.")
+        );
+    }
+
+    #[test]
+    fn decodes_base64_files() {
+        let settings = AttachmentSettings::default();
+        let file = |data: &str| EncodedAttachment {
+            filename: "dot.png".to_string(),
+            data: data.to_string(),
+        };
+        let png = Attachment::decode(&file(&STANDARD.encode(PNG)), &settings).unwrap();
+        assert_eq!(
+            (png.content_type.as_str(), png.data.as_slice()),
+            ("image/png", PNG)
+        );
+        assert_eq!(
+            Attachment::decode(&file("not base64!"), &settings)
+                .err()
+                .as_deref(),
+            Some("dot.png could not be read.")
+        );
+    }
+
+    #[test]
+    fn filenames_are_sanitized() {
+        assert_eq!(sanitize_filename("C:\\Users\\me\\cv.pdf"), "cv.pdf");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("a\r\nBcc: x.txt"), "a Bcc: x.txt");
+        assert_eq!(sanitize_filename(".bashrc"), "attachment.bashrc");
+        assert_eq!(sanitize_filename(""), "attachment");
+    }
+}
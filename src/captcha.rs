@@ -1,8 +1,9 @@
-use std::convert::TryInto;
+use std::collections::HashMap;
 use std::sync::Mutex;
 
 use actix_session::Session;
 use actix_web::{
+    error::ErrorInternalServerError,
     get,
     http::{
         header::{CacheControl, CacheDirective},
@@ -11,32 +12,221 @@ use actix_web::{
     web, HttpResponse, Result,
 };
 use captcha::{filters, Captcha};
-use log::info;
+use log::{error, info};
 use rand::{rng, Rng};
 use serde::Deserialize;
+use time::OffsetDateTime;
 
-use crate::SharedAppData;
+use crate::{Settings, SharedAppData};
 
-pub const CAPTCHA_LEN: usize = 8;
 pub const CAPTCHA_ID_LEN: usize = 16;
+/// Session key holding when the visitor last solved a captcha, as a Unix timestamp.
+const CAPTCHA_PASSED_KEY: &str = "captcha_passed_at";
+
+/// Characters of the default font, minus any that are easily mistaken for one another (`1/l/i`,
+/// `5/S`, `2/Z`, `8/B`, `9/g/q`, `6/b`, ...) and lowercase letters shaped like their capitals.
+const DEFAULT_ALPHABET: &str = "347ACDEFGHJKMNPRTUVWXYadefhmnrt";
+const DEFAULT_PROFILE: &str = "normal";
+
+/// Groups of characters that users commonly mistake for one another. When comparing a guess to a
+/// solution, every character in a group is treated as the first character of that group.
+const CONFUSABLES: &[&str] = &["0Oo", "1lIi|", "2Zz", "5Ss", "8B"];
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CaptchaSettings {
+    /// Characters captchas are drawn from. Must be supported by the captcha font.
+    pub alphabet: String,
+    /// Whether "a" should be accepted for "A" and vice versa.
+    pub case_insensitive: bool,
+    /// Name of the profile in `profiles` used to render captchas.
+    pub profile: String,
+    /// Named difficulty profiles. Entries here replace the built-in profile of the same name.
+    pub profiles: HashMap<String, CaptchaProfile>,
+    /// How long a solved captcha keeps revealing captcha-protected contact methods.
+    pub pass_ttl_secs: i64,
+}
+
+impl Default for CaptchaSettings {
+    fn default() -> Self {
+        Self {
+            alphabet: DEFAULT_ALPHABET.to_string(),
+            case_insensitive: false,
+            profile: DEFAULT_PROFILE.to_string(),
+            profiles: HashMap::new(),
+            pass_ttl_secs: 60 * 60,
+        }
+    }
+}
+
+impl CaptchaSettings {
+    /// Returns the selected profile, falling back to the built-in profiles when it is not
+    /// defined in config.
+    pub fn active_profile(&self) -> Result<CaptchaProfile, String> {
+        self.profiles
+            .get(&self.profile)
+            .cloned()
+            .or_else(|| CaptchaProfile::builtin(&self.profile))
+            .ok_or_else(|| format!("Unknown captcha profile \"{}\"", self.profile))
+    }
+
+    /// Checks that the alphabet can be rendered and that the selected profile exists, so that
+    /// misconfiguration is caught at startup rather than on the first captcha request.
+    pub fn validate(&self) -> Result<(), String> {
+        let supported = Captcha::new().supported_chars();
+        if self.alphabet.is_empty() {
+            return Err("Captcha alphabet must not be empty".to_string());
+        }
+        if let Some(c) = self.alphabet.chars().find(|c| !supported.contains(c)) {
+            return Err(format!(
+                "Captcha alphabet contains '{c}', which the captcha font can't render"
+            ));
+        }
+        let profile = self.active_profile()?;
+        if profile.length == 0 {
+            return Err(format!(
+                "Captcha profile \"{}\" has a length of zero",
+                self.profile
+            ));
+        }
+        Ok(())
+    }
+
+    /// Compare a user's guess with the captcha solution, tolerating commonly confused characters
+    /// and (if enabled) differences in case.
+    pub fn matches(&self, guess: &str, solution: &str) -> bool {
+        let guess = self.normalize(guess.trim());
+        let solution = self.normalize(solution);
+        !solution.is_empty() && guess == solution
+    }
+
+    /// Whether the visitor solved a captcha recently enough to see captcha-protected contact
+    /// methods.
+    pub fn passed(&self, session: &Session) -> bool {
+        session
+            .get::<i64>(CAPTCHA_PASSED_KEY)
+            .unwrap_or_default()
+            .is_some_and(|passed_at| self.pass_is_fresh(passed_at, now()))
+    }
+
+    fn pass_is_fresh(&self, passed_at: i64, now: i64) -> bool {
+        passed_at <= now && now - passed_at < self.pass_ttl_secs
+    }
+
+    fn normalize(&self, text: &str) -> String {
+        text.chars()
+            .map(|c| {
+                let c = CONFUSABLES
+                    .iter()
+                    .find(|group| group.contains(c))
+                    .and_then(|group| group.chars().next())
+                    .unwrap_or(c);
+                if self.case_insensitive {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CaptchaProfile {
+    /// Number of characters in the captcha.
+    pub length: u32,
+    /// Probability that any given pixel is replaced by noise.
+    pub noise: f32,
+    pub horizontal_wave: WaveSettings,
+    pub vertical_wave: WaveSettings,
+    pub view_width: u32,
+    pub view_height: u32,
+}
+
+impl CaptchaProfile {
+    fn builtin(name: &str) -> Option<Self> {
+        let profile = match name {
+            "easy" => Self {
+                length: 6,
+                noise: 0.1,
+                horizontal_wave: WaveSettings::new(2.0, 6.0),
+                vertical_wave: WaveSettings::new(2.0, 6.0),
+                view_width: 240,
+                view_height: 84,
+            },
+            "normal" => Self {
+                length: 8,
+                noise: 0.3,
+                horizontal_wave: WaveSettings::new(2.5, 10.0),
+                vertical_wave: WaveSettings::new(3.0, 10.0),
+                view_width: 300,
+                view_height: 84,
+            },
+            "hard" => Self {
+                length: 10,
+                noise: 0.45,
+                horizontal_wave: WaveSettings::new(3.0, 14.0),
+                vertical_wave: WaveSettings::new(3.5, 14.0),
+                view_width: 360,
+                view_height: 96,
+            },
+            _ => return None,
+        };
+        Some(profile)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct WaveSettings {
+    pub frequency: f64,
+    pub amplitude: f64,
+}
+
+impl WaveSettings {
+    const fn new(frequency: f64, amplitude: f64) -> Self {
+        Self {
+            frequency,
+            amplitude,
+        }
+    }
+}
 
 /// Captcha generation handler
 #[get("/api/generate_captcha")]
 pub async fn generate_captcha(
     session: Session,
     app_data: web::Data<Mutex<SharedAppData>>,
+    settings: web::Data<Settings>,
 ) -> Result<HttpResponse> {
+    let alphabet: Vec<char> = settings.captcha.alphabet.chars().collect();
+    let profile = settings.captcha.active_profile().map_err(|e| {
+        error!("{e}");
+        ErrorInternalServerError("Captcha is misconfigured")
+    })?;
+
     let mut captcha = Captcha::new();
     captcha
-        .add_chars(CAPTCHA_LEN.try_into().expect("Captcha too long"))
-        .apply_filter(filters::Noise::new(0.3))
-        .apply_filter(filters::Wave::new(2.5, 10.0).horizontal())
-        .apply_filter(filters::Wave::new(3.0, 10.0).vertical())
-        .view(300, 84)
+        .set_chars(&alphabet)
+        .add_chars(profile.length)
+        .apply_filter(filters::Noise::new(profile.noise))
+        .apply_filter(
+            filters::Wave::new(
+                profile.horizontal_wave.frequency,
+                profile.horizontal_wave.amplitude,
+            )
+            .horizontal(),
+        )
+        .apply_filter(
+            filters::Wave::new(
+                profile.vertical_wave.frequency,
+                profile.vertical_wave.amplitude,
+            )
+            .vertical(),
+        )
+        .view(profile.view_width, profile.view_height)
         .apply_filter(filters::Cow::new().min_radius(60).max_radius(70).circles(1))
         .apply_filter(filters::Dots::new(7).min_radius(3).max_radius(5));
-    let mut solution: [char; CAPTCHA_LEN] = Default::default();
-    solution.copy_from_slice(&captcha.chars()[..]);
+    let solution = captcha.chars_as_string();
     let img = captcha.as_png().expect("Failed to generate captcha PNG");
 
     // Set random captcha ID.
@@ -46,12 +236,12 @@ pub async fn generate_captcha(
         .lock()
         .expect("Unable to get lock on captcha cache")
         .captcha_cache
-        .put(id, solution);
+        .put(id, solution.clone());
     info!("Put captcha ID = {id:?} and solution = {solution:?} in local cache");
 
     // Add captcha solution to private session cookie.
     session
-        .insert("captcha", captcha.chars_as_string())
+        .insert("captcha", solution)
         .expect("Unable to add captcha solution to session");
 
     // Add captcha id to private session cookie.
@@ -74,13 +264,18 @@ struct CaptchaSubmitQuery {
 #[get("/api/submit_captcha")]
 async fn submit_captcha(
     session: Session,
+    settings: web::Data<Settings>,
     web::Query(guess): web::Query<CaptchaSubmitQuery>,
 ) -> Result<HttpResponse> {
     let mut pass_status = "Fail";
 
-    let answer = session.get("captcha").unwrap_or_default();
-    if Some(guess.captcha) == answer {
-        pass_status = "Pass";
+    let answer: Option<String> = session.get("captcha").unwrap_or_default();
+    if let Some(answer) = answer {
+        if settings.captcha.matches(&guess.captcha, &answer) {
+            pass_status = "Pass";
+            // Remember the pass so that captcha-protected contact info can be revealed.
+            session.insert(CAPTCHA_PASSED_KEY, now())?;
+        }
     }
 
     Ok(HttpResponse::build(StatusCode::OK)
@@ -88,3 +283,61 @@ async fn submit_captcha(
         .content_type("text/plain; charset=utf-8")
         .body(pass_status))
 }
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_confusable_characters() {
+        let settings = CaptchaSettings::default();
+        assert!(settings.matches("O1Z5B", "0lz58"));
+        assert!(settings.matches("  |i2sB ", "1I2S8"));
+        assert!(!settings.matches("ABC", "ABD"));
+        assert!(!settings.matches("", ""));
+    }
+
+    #[test]
+    fn folds_case_only_when_enabled() {
+        let mut settings = CaptchaSettings::default();
+        assert!(!settings.matches("aDeF", "ADEF"));
+        settings.case_insensitive = true;
+        assert!(settings.matches("aDeF", "ADEF"));
+        // Confusables still apply on top of case folding.
+        assert!(settings.matches("o", "0"));
+    }
+
+    #[test]
+    fn passes_expire() {
+        let settings = CaptchaSettings::default();
+        assert!(settings.pass_is_fresh(1000, 1000));
+        assert!(settings.pass_is_fresh(1000, 1000 + 60 * 60 - 1));
+        assert!(!settings.pass_is_fresh(1000, 1000 + 60 * 60));
+        // A pass from the future was not issued by this clock.
+        assert!(!settings.pass_is_fresh(2000, 1000));
+    }
+
+    #[test]
+    fn reports_bad_config() {
+        assert!(CaptchaSettings::default().validate().is_ok());
+
+        let empty = CaptchaSettings {
+            alphabet: String::new(),
+            ..CaptchaSettings::default()
+        };
+        assert!(empty.validate().is_err());
+
+        let unknown = CaptchaSettings {
+            profile: "impossible".to_string(),
+            ..CaptchaSettings::default()
+        };
+        assert_eq!(
+            unknown.validate(),
+            Err("Unknown captcha profile \"impossible\"".to_string())
+        );
+    }
+}
@@ -1,39 +1,331 @@
-use std::sync::{Arc, Mutex};
+use std::{collections::BTreeMap, sync::Mutex};
 
+use actix_multipart::Multipart;
 use actix_session::Session;
-use actix_web::{get, http::StatusCode, post, web, HttpResponse, Result};
+use actix_web::{
+    get,
+    http::{
+        header::{self, CacheControl, CacheDirective},
+        StatusCode,
+    },
+    post, web, FromRequest, HttpRequest, HttpResponse, Result,
+};
+use futures::StreamExt;
 use lettre::{
-    message::{Mailbox, MultiPart, SinglePart},
-    Message, SmtpTransport, Transport,
+    message::{header::ContentType, Attachment as AttachmentPart, Mailbox},
+    Address, Message,
 };
 use log::{error, info, warn};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 
+use crate::attachments::{Attachment, AttachmentError, AttachmentSettings, EncodedAttachment};
 use crate::captcha::*;
+use crate::email::{
+    escape_html, header_value, render_text, MAX_ADDRESS_LEN, MAX_NAME_LEN, MAX_SUBJECT_LEN,
+};
+use crate::email_templates::{EmailTemplates, CONTACT_ADMIN, CONTACT_VERIFY};
+use crate::inbox::{Inbox, NewSubmission};
+use crate::mail::MailQueue;
+use crate::metrics::Metrics;
+use crate::notify::{Event, Notifier};
+use crate::pgp::Pgp;
+use crate::rate_limit::ClientIp;
+use crate::spam::{SpamFilter, TokenError};
 use crate::template_composition;
-use crate::SharedAppData;
+use crate::{Settings, SharedAppData};
+
+/// Window over which confirmation links sent to one address are counted.
+const VERIFICATION_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ContactSettings {
+    /// Ways to reach me, in the order they are listed on the contact page.
+    pub methods: Vec<ContactMethod>,
+    /// Limits on files attached to the contact form.
+    pub attachments: AttachmentSettings,
+    /// Address the site is served from, used in links sent by email.
+    pub public_url: String,
+    /// Confirmation links sent to any one address per day. Submissions past this are refused.
+    pub max_verifications_per_day: u32,
+}
+
+impl Default for ContactSettings {
+    fn default() -> Self {
+        Self {
+            methods: vec![
+                ContactMethod {
+                    label: "Email".to_string(),
+                    value: "charlie@busyboredom.com".to_string(),
+                    link: Some("mailto:charlie@busyboredom.com".to_string()),
+                    reveal: RevealPolicy::Plain,
+                },
+                ContactMethod {
+                    label: "Matrix".to_string(),
+                    value: "@busyboredom:tchncs.de".to_string(),
+                    link: Some("https://matrix.to/#/@busyboredom:tchncs.de".to_string()),
+                    reveal: RevealPolicy::Plain,
+                },
+                ContactMethod {
+                    label: "Linkedin".to_string(),
+                    value: "https://www.linkedin.com/in/charlie-wilkin-7b6027178/".to_string(),
+                    link: Some("https://www.linkedin.com/in/charlie-wilkin-7b6027178/".to_string()),
+                    reveal: RevealPolicy::Plain,
+                },
+                ContactMethod {
+                    label: "Phone".to_string(),
+                    value: "Dude, it's {{year}}.".to_string(),
+                    link: None,
+                    reveal: RevealPolicy::Plain,
+                },
+            ],
+            attachments: AttachmentSettings::default(),
+            public_url: "https://busyboredom.com".to_string(),
+            max_verifications_per_day: 3,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ContactMethod {
+    /// Name shown in the selector, also used to look the method up.
+    pub label: String,
+    /// The contact info itself. `{{year}}` is replaced with the current year.
+    pub value: String,
+    #[serde(default)]
+    pub link: Option<String>,
+    #[serde(default)]
+    pub reveal: RevealPolicy,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RevealPolicy {
+    /// Shown to anyone who selects it.
+    #[default]
+    Plain,
+    /// Hidden from scrapers until the visitor has solved a captcha.
+    Captcha,
+}
+
+#[derive(Serialize)]
+struct ContactMethodSummary<'a> {
+    label: &'a str,
+    reveal: RevealPolicy,
+}
+
+/// List the available contact methods, without their values.
+#[get("/api/contact_methods")]
+pub async fn contact_methods(settings: web::Data<Settings>) -> Result<HttpResponse> {
+    let methods: Vec<_> = settings
+        .contact
+        .methods
+        .iter()
+        .map(|method| ContactMethodSummary {
+            label: &method.label,
+            reveal: method.reveal,
+        })
+        .collect();
+
+    Ok(HttpResponse::build(StatusCode::OK).json(methods))
+}
 
 #[derive(Deserialize)]
 struct ContactInfoQuery {
     method: String,
 }
 
-/// Captcha submission handler
+#[derive(Serialize)]
+struct ContactInfo<'a> {
+    label: &'a str,
+    value: String,
+    link: Option<&'a str>,
+}
+
+/// Contact info handler
 #[get("/api/contact_info")]
-pub async fn contact_info(web::Query(query): web::Query<ContactInfoQuery>) -> Result<HttpResponse> {
-    let info = match &query.method[..] {
-        "Email" => "charlie@busyboredom.com",
-        "Matrix" => "@busyboredom:tchncs.de",
-        "Linkedin" => "https://www.linkedin.com/in/charlie-wilkin-7b6027178/",
-        _ => "Not found",
+pub async fn contact_info(
+    session: Session,
+    settings: web::Data<Settings>,
+    web::Query(query): web::Query<ContactInfoQuery>,
+) -> Result<HttpResponse> {
+    let Some(method) = settings
+        .contact
+        .methods
+        .iter()
+        .find(|method| method.label == query.method)
+    else {
+        return Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+            .content_type("text/plain; charset=utf-8")
+            .body("Not found"));
     };
 
+    if method.reveal == RevealPolicy::Captcha && !settings.captcha.passed(&session) {
+        return Ok(HttpResponse::build(StatusCode::FORBIDDEN)
+            .content_type("text/plain; charset=utf-8")
+            .body("Solve the captcha below to reveal."));
+    }
+
+    let year = OffsetDateTime::now_utc().year().to_string();
     Ok(HttpResponse::build(StatusCode::OK)
-        .content_type("text/plain; charset=utf-8")
-        .body(info))
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .json(ContactInfo {
+            label: &method.label,
+            value: render_text(&method.value, &[("year", &year)]),
+            link: method.link.as_deref(),
+        }))
 }
 
-#[derive(Deserialize)]
+/// Longest message accepted through the contact form.
+const MAX_MESSAGE_LEN: usize = 10_000;
+/// Most bytes read from any one text field of a multipart submission.
+const MAX_FIELD_LEN: usize = 64 * 1024;
+/// Longest PGP public key accepted from the sender.
+const MAX_PGP_KEY_LEN: usize = 32 * 1024;
+
+/// Error returned by contact endpoints. `fields` maps form field names to a message describing
+/// what is wrong with that field.
+#[derive(Serialize, Debug)]
+struct ContactError {
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    fields: BTreeMap<&'static str, String>,
+}
+
+impl ContactError {
+    fn validation(fields: BTreeMap<&'static str, String>) -> Self {
+        Self {
+            error: "validation_failed",
+            message: "Please correct the highlighted fields.".to_string(),
+            fields,
+        }
+    }
+
+    fn bad_request(message: String) -> Self {
+        Self {
+            error: "bad_request",
+            message,
+            fields: BTreeMap::new(),
+        }
+    }
+
+    fn form_token(e: &TokenError) -> Self {
+        let (error, message) = match e {
+            TokenError::TooFast => (
+                "too_fast",
+                "That was quick! Please wait a few seconds and submit again.",
+            ),
+            TokenError::Invalid | TokenError::Expired | TokenError::Reused => (
+                "form_expired",
+                "This form has expired. Please reload the page and try again.",
+            ),
+        };
+        Self {
+            error,
+            message: message.to_string(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    fn captcha() -> Self {
+        let message = "Captcha response didn't match what the server expected.".to_string();
+        Self {
+            error: "captcha_failed",
+            fields: BTreeMap::from([("captchachars", message.clone())]),
+            message,
+        }
+    }
+
+    /// The sender's key was refused. It is only checked once the captcha has passed, so the
+    /// captcha is spent.
+    fn pgp_key() -> Self {
+        Self {
+            error: "pgp_key_rejected",
+            message: "Please correct the highlighted fields.".to_string(),
+            fields: BTreeMap::from([(
+                "pgp_key",
+                "PGP key must be a single ASCII armored public key that can encrypt.".to_string(),
+            )]),
+        }
+    }
+
+    fn too_many_verifications() -> Self {
+        Self {
+            error: "too_many_verifications",
+            message: "Too many messages have been sent from this email address today. Please try \
+                again tomorrow."
+                .to_string(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Something went wrong on our side. The sender can try again later.
+    fn unavailable() -> Self {
+        Self {
+            error: "unavailable",
+            message: "Your message couldn't be sent right now. Please try again later.".to_string(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    fn invalid_link() -> Self {
+        Self {
+            error: "invalid_link",
+            message: "This confirmation link is invalid, has expired, or has already been used."
+                .to_string(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self.error {
+            "bad_request" => StatusCode::BAD_REQUEST,
+            "captcha_failed" => StatusCode::FORBIDDEN,
+            "invalid_link" => StatusCode::NOT_FOUND,
+            "too_many_verifications" => StatusCode::TOO_MANY_REQUESTS,
+            "unavailable" => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    /// Count the failed submission in the metrics.
+    fn record(&self, metrics: &Metrics) {
+        let outcome = match self.error {
+            "validation_failed" => "invalid",
+            error => error,
+        };
+        metrics.increment("contact_submissions_total", &[("outcome", outcome)]);
+    }
+
+    fn response(&self, metrics: &Metrics) -> HttpResponse {
+        self.record(metrics);
+        HttpResponse::build(self.status()).json(self)
+    }
+
+    /// The error as a page, for clients that posted the form without JavaScript.
+    fn page(&self, metrics: &Metrics) -> HttpResponse {
+        self.record(metrics);
+        let mut details = format!("<p>{}</p>", escape_html(&self.message));
+        if !self.fields.is_empty() {
+            details += "<ul>";
+            for message in self.fields.values() {
+                details += &format!("<li>{}</li>", escape_html(message));
+            }
+            details += "</ul>";
+        }
+        HttpResponse::build(self.status())
+            .content_type("text/html; charset=utf-8")
+            .body(
+                template_composition("base.html", "contact_error.html")
+                    .replace("<p id=\"contact-error-details\"></p>", &details),
+            )
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
 struct ContactForm {
     firstname: String,
     lastname: String,
@@ -41,31 +333,387 @@ struct ContactForm {
     subject: String,
     message: String,
     captchachars: String,
+    /// Honeypot field, hidden from humans.
+    website: String,
+    /// Signed token recording when the form was loaded.
+    form_token: String,
+    /// The sender's ASCII armored PGP public key, so replies can be encrypted.
+    pgp_key: String,
+}
+
+impl ContactForm {
+    /// Check every field, returning a message for each one that is invalid. Anything that ends up
+    /// in an email header is also checked for header injection.
+    fn validate(&self) -> Result<(), BTreeMap<&'static str, String>> {
+        let mut errors = BTreeMap::new();
+        let fields = [
+            ("firstname", "First name", &self.firstname, MAX_NAME_LEN),
+            ("lastname", "Last name", &self.lastname, MAX_NAME_LEN),
+            ("email", "Email", &self.email, MAX_ADDRESS_LEN),
+            ("subject", "Subject", &self.subject, MAX_SUBJECT_LEN),
+        ];
+        for (field, label, value, max_len) in fields {
+            if value.trim().is_empty() {
+                errors.insert(field, format!("{label} is required."));
+            } else if let Err(e) = header_value(value, max_len) {
+                errors.insert(field, format!("{label} {e}."));
+            }
+        }
+        if !errors.contains_key("email") && self.email.trim().parse::<Address>().is_err() {
+            errors.insert("email", "Email must be a valid email address.".to_string());
+        }
+
+        if self.message.trim().is_empty() {
+            errors.insert("message", "Message is required.".to_string());
+        } else if self.message.chars().count() > MAX_MESSAGE_LEN {
+            errors.insert(
+                "message",
+                format!("Message must be at most {MAX_MESSAGE_LEN} characters."),
+            );
+        }
+
+        if self.pgp_key.len() > MAX_PGP_KEY_LEN {
+            errors.insert(
+                "pgp_key",
+                format!("PGP key must be at most {} KiB.", MAX_PGP_KEY_LEN / 1024),
+            );
+        }
+
+        if self.captchachars.trim().is_empty() {
+            errors.insert("captchachars", "Captcha is required.".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn set_field(&mut self, name: &str, value: String) {
+        match name {
+            "firstname" => self.firstname = value,
+            "lastname" => self.lastname = value,
+            "email" => self.email = value,
+            "subject" => self.subject = value,
+            "message" => self.message = value,
+            "captchachars" => self.captchachars = value,
+            "website" => self.website = value,
+            "form_token" => self.form_token = value,
+            "pgp_key" => self.pgp_key = value,
+            _ => {}
+        }
+    }
+
+    /// Variables for the email to me. `pgp_key` describes the sender's key, if they gave one.
+    fn template_vars<'a>(&'a self, pgp_key: &'a str) -> [(&'a str, &'a str); 6] {
+        [
+            ("firstname", &self.firstname),
+            ("lastname", &self.lastname),
+            ("email", &self.email),
+            ("subject", &self.subject),
+            ("message", &self.message),
+            ("pgp_key", pgp_key),
+        ]
+    }
+}
+
+/// A contact form submission, with any attachments and the reasons files were rejected.
+struct Submission {
+    form: ContactForm,
+    attachments: Vec<Attachment>,
+    attachment_errors: Vec<String>,
+}
+
+/// Read the contact form from either a URL encoded or a multipart body. Only multipart bodies can
+/// carry attachments.
+async fn read_submission(
+    req: &HttpRequest,
+    payload: web::Payload,
+    settings: &AttachmentSettings,
+) -> Result<Submission, ContactError> {
+    let is_multipart = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("multipart/form-data"));
+    if !is_multipart {
+        let form = web::Form::<ContactForm>::from_request(req, &mut payload.into_inner())
+            .await
+            .map_err(|e| ContactError::bad_request(e.to_string()))?;
+        return Ok(Submission {
+            form: form.into_inner(),
+            attachments: Vec::new(),
+            attachment_errors: Vec::new(),
+        });
+    }
+
+    let mut submission = Submission {
+        form: ContactForm::default(),
+        attachments: Vec::new(),
+        attachment_errors: Vec::new(),
+    };
+    let mut multipart = Multipart::new(req.headers(), payload);
+    while let Some(field) = multipart.next().await {
+        let mut field = field.map_err(|e| ContactError::bad_request(e.to_string()))?;
+        let name = field.name().unwrap_or_default().to_string();
+        if name != "attachments" {
+            let value = field
+                .bytes(MAX_FIELD_LEN)
+                .await
+                .map_err(|_| ContactError::bad_request(format!("Field {name} is too long.")))?
+                .map_err(|e| ContactError::bad_request(e.to_string()))?;
+            submission
+                .form
+                .set_field(&name, String::from_utf8_lossy(&value).into_owned());
+            continue;
+        }
+
+        match Attachment::read(&mut field, settings).await {
+            Ok(None) => {}
+            Ok(Some(_)) if settings.max_count == 0 => {
+                submission
+                    .attachment_errors
+                    .push("Attachments are not accepted.".to_string());
+            }
+            Ok(Some(_)) if submission.attachments.len() == settings.max_count => {
+                submission.attachment_errors.push(format!(
+                    "At most {} files can be attached.",
+                    settings.max_count
+                ));
+            }
+            Ok(Some(attachment)) => submission.attachments.push(attachment),
+            Err(AttachmentError::Invalid(e)) => submission.attachment_errors.push(e),
+            Err(AttachmentError::Multipart(e)) => {
+                return Err(ContactError::bad_request(e.to_string()))
+            }
+        }
+        // Anything past this point won't be accepted, so stop reading.
+        if !submission.attachment_errors.is_empty() {
+            break;
+        }
+    }
+    Ok(submission)
+}
+
+#[derive(Deserialize)]
+struct ContactRequest {
+    #[serde(flatten)]
+    form: ContactForm,
+    #[serde(default)]
+    attachments: Vec<EncodedAttachment>,
+}
+
+/// Read the contact form from a JSON body.
+async fn read_json_submission(
+    payload: web::Payload,
+    settings: &AttachmentSettings,
+) -> Result<Submission, ContactError> {
+    // Room for the text fields, plus every allowed file after base64 encoding.
+    let limit = settings
+        .max_size
+        .saturating_add(2)
+        .saturating_mul(4)
+        .saturating_div(3)
+        .saturating_mul(settings.max_count)
+        .saturating_add(MAX_FIELD_LEN);
+    let body = payload
+        .to_bytes_limited(limit)
+        .await
+        .map_err(|_| ContactError::bad_request("Submission is too large.".to_string()))?
+        .map_err(|e| ContactError::bad_request(e.to_string()))?;
+    let request: ContactRequest =
+        serde_json::from_slice(&body).map_err(|e| ContactError::bad_request(e.to_string()))?;
+
+    let mut submission = Submission {
+        form: request.form,
+        attachments: Vec::new(),
+        attachment_errors: Vec::new(),
+    };
+    if !request.attachments.is_empty() && settings.max_count == 0 {
+        submission
+            .attachment_errors
+            .push("Attachments are not accepted.".to_string());
+    } else if request.attachments.len() > settings.max_count {
+        submission.attachment_errors.push(format!(
+            "At most {} files can be attached.",
+            settings.max_count
+        ));
+    } else {
+        for file in &request.attachments {
+            match Attachment::decode(file, settings) {
+                Ok(attachment) => submission.attachments.push(attachment),
+                Err(e) => submission.attachment_errors.push(e),
+            }
+        }
+    }
+    Ok(submission)
 }
 
-/// Contact form handler
+/// Classic contact form handler, for browsers without JavaScript. Takes a URL encoded or multipart
+/// form and answers with a page.
 #[post("/contact-submitted")]
+#[allow(clippy::too_many_arguments)]
 pub async fn contact_submitted(
-    mailer: web::Data<Arc<SmtpTransport>>,
+    mail_queue: web::Data<MailQueue>,
+    templates: web::Data<EmailTemplates>,
+    inbox: web::Data<Inbox>,
+    spam_filter: web::Data<SpamFilter>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    pgp: web::Data<Pgp>,
     shared_data: web::Data<Mutex<SharedAppData>>,
-    form: web::Form<ContactForm>,
+    settings: web::Data<Settings>,
+    payload: web::Payload,
     session: Session,
+    req: HttpRequest,
 ) -> Result<HttpResponse> {
+    let submission = match read_submission(&req, payload, &settings.contact.attachments).await {
+        Ok(submission) => submission,
+        Err(e) => {
+            warn!("Could not read contact form submission: {}", e.message);
+            return Ok(e.page(&metrics));
+        }
+    };
+    let result = submit(
+        submission,
+        &mail_queue,
+        &templates,
+        &inbox,
+        &spam_filter,
+        &metrics,
+        &notifier,
+        &pgp,
+        &shared_data,
+        &settings,
+        &session,
+        &req,
+    )
+    .await;
+    Ok(match result {
+        Ok(()) => submitted_page(),
+        Err(e) => e.page(&metrics),
+    })
+}
+
+/// JSON contact form handler, used by the frontend. Attachments are sent base64 encoded.
+#[post("/api/contact")]
+#[allow(clippy::too_many_arguments)]
+pub async fn contact_api(
+    mail_queue: web::Data<MailQueue>,
+    templates: web::Data<EmailTemplates>,
+    inbox: web::Data<Inbox>,
+    spam_filter: web::Data<SpamFilter>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    pgp: web::Data<Pgp>,
+    shared_data: web::Data<Mutex<SharedAppData>>,
+    settings: web::Data<Settings>,
+    payload: web::Payload,
+    session: Session,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let submission = match read_json_submission(payload, &settings.contact.attachments).await {
+        Ok(submission) => submission,
+        Err(e) => {
+            warn!("Could not read contact form submission: {}", e.message);
+            return Ok(e.response(&metrics));
+        }
+    };
+    let result = submit(
+        submission,
+        &mail_queue,
+        &templates,
+        &inbox,
+        &spam_filter,
+        &metrics,
+        &notifier,
+        &pgp,
+        &shared_data,
+        &settings,
+        &session,
+        &req,
+    )
+    .await;
+    Ok(match result {
+        Ok(()) => HttpResponse::Ok().json(ContactSubmitted {
+            status: "confirmation_sent",
+            message: "Thanks! Check your email for a link to confirm your message.",
+        }),
+        Err(e) => e.response(&metrics),
+    })
+}
+
+#[derive(Serialize)]
+struct ContactSubmitted {
+    status: &'static str,
+    message: &'static str,
+}
+
+/// Check a submission and, if it passes, hold it until the sender confirms their address.
+#[allow(clippy::too_many_arguments)]
+async fn submit(
+    submission: Submission,
+    mail_queue: &MailQueue,
+    templates: &EmailTemplates,
+    inbox: &Inbox,
+    spam_filter: &SpamFilter,
+    metrics: &Metrics,
+    notifier: &Notifier,
+    pgp: &Pgp,
+    shared_data: &Mutex<SharedAppData>,
+    settings: &Settings,
+    session: &Session,
+    req: &HttpRequest,
+) -> Result<(), ContactError> {
+    let Submission {
+        form,
+        attachments,
+        attachment_errors,
+    } = submission;
+
+    // Validate before spending the captcha, so the user can fix their input and resubmit.
+    let mut fields = form.validate().err().unwrap_or_default();
+    if !attachment_errors.is_empty() {
+        fields.insert("attachments", attachment_errors.join(" "));
+    }
+    if !fields.is_empty() {
+        warn!("Rejected contact form submission: {fields:?}");
+        return Err(ContactError::validation(fields));
+    }
+    if let Err(e) = spam_filter.check_token(&form.form_token, session) {
+        warn!("Rejected contact form submission: {e}");
+        return Err(ContactError::form_token(&e));
+    }
+    // Checked before the captcha too, so a refused sender can keep their captcha for later.
+    let verifications_sent = inbox
+        .verifications_sent(&form.email, VERIFICATION_WINDOW_SECS)
+        .unwrap_or_else(|e| {
+            error!("Could not count confirmation links sent: {e}");
+            0
+        });
+    if verifications_sent >= i64::from(settings.contact.max_verifications_per_day) {
+        warn!(
+            "Rejected contact form submission: too many confirmation links sent to {}",
+            form.email
+        );
+        return Err(ContactError::too_many_verifications());
+    }
+
     // Get solution from session cookie.
     let solution: Option<String> = session.get("captcha").unwrap_or_default();
     // Get the local cached solution.
-    let maybe_cached_solution: Option<[char; 8]> =
+    let maybe_cached_solution: Option<String> =
         match session.get::<[u8; CAPTCHA_ID_LEN]>("captcha_id") {
             Ok(Some(id)) => {
                 let cache = &mut shared_data
                     .lock()
                     .expect("Unable to get lock on captcha cache")
                     .captcha_cache;
-                match cache.get(&id) {
-                    Some(&chars) => {
+                // Remove the locally cached solution to prevent double submission.
+                match cache.pop(&id) {
+                    Some(chars) => {
                         info!("Got captcha ID = {id:?} and solution = {chars:?} in local cache.");
-                        // Remove the locally cached solution to prevent double submission.
-                        cache.pop(&id);
                         Some(chars)
                     }
                     None => {
@@ -86,95 +734,333 @@ pub async fn contact_submitted(
     // Make sure there IS a local hached solution.
     if let Some(cached_solution) = maybe_cached_solution {
         // Make sure the guess matches the session cookie solution and the locally cached one.
-        if Some(form.captchachars.clone()) != solution
-            || form.captchachars.clone() != cached_solution.iter().collect::<String>()
+        let matches_session = solution
+            .as_deref()
+            .is_some_and(|solution| settings.captcha.matches(&form.captchachars, solution));
+        if !matches_session
+            || !settings
+                .captcha
+                .matches(&form.captchachars, &cached_solution)
         {
             // Otherwise, fail it and return.
             error!("Could not send email, captcha not passed");
-            return Ok(HttpResponse::build(StatusCode::OK)
-                .content_type("text/plain; charset=utf-8")
-                .body("Captcha response didn't match what the server expected."));
+            return Err(ContactError::captcha());
         }
     } else {
         error!("Could not send email, captcha ID/solution not in local cache");
-        return Ok(HttpResponse::build(StatusCode::OK)
-            .content_type("text/plain; charset=utf-8")
-            .body("Captcha response didn't match what the server expected."));
+        return Err(ContactError::captcha());
+    }
+    // Only run GnuPG for senders who got past the captcha. The token isn't spent yet, so a
+    // sender whose key is refused can fix it and resubmit with a new captcha.
+    let mut pgp_fingerprint = None;
+    if !form.pgp_key.trim().is_empty() {
+        match pgp.inspect(form.pgp_key.clone()).await {
+            Ok(fingerprint) => pgp_fingerprint = Some(fingerprint),
+            Err(e) => {
+                warn!("Rejected PGP key sent with contact form: {e}");
+                return Err(ContactError::pgp_key());
+            }
+        }
+    }
+    // Only now that the submission is going through, so a failed captcha doesn't cost the token.
+    if let Err(e) = spam_filter.consume_token(&form.form_token) {
+        warn!("Rejected contact form submission: {e}");
+        return Err(ContactError::form_token(&e));
     }
 
-    let html_message = format!(
-        "<b>First Name: </b>{}<br>
-        <b>Last Name: </b>{}<br>
-        <b>Email: </b>{}<br>
-        <br>
-        <b>Message:</b><br>
-        {}",
-        form.firstname, form.lastname, form.email, form.message
+    let spam = spam_filter.check(
+        &form.website,
+        &[
+            &form.firstname,
+            &form.lastname,
+            &form.subject,
+            &form.message,
+        ],
     );
+    let spam_reasons = spam.reasons.join(", ");
 
-    let plain_message = format!(
-        "First Name: {}\nLast Name: {}\nEmail: {}\n\nMessage:\n{}",
-        form.firstname, form.lastname, form.email, form.message
-    );
+    // Keep a copy of the submission in case email fails.
+    let submission_id = inbox
+        .record(&NewSubmission {
+            firstname: &form.firstname,
+            lastname: &form.lastname,
+            email: &form.email,
+            subject: &form.subject,
+            message: &form.message,
+            client_ip: ClientIp::of(req),
+            spam_score: spam.score,
+            spam_reasons: &spam_reasons,
+            quarantined: spam.quarantine,
+            pgp_key: if pgp_fingerprint.is_some() {
+                &form.pgp_key
+            } else {
+                ""
+            },
+        })
+        .map_err(|e| error!("Could not store contact form submission: {e}"))
+        .ok();
 
-    let email = Message::builder()
-        .from("Contact Form <donotreply@busyboredom.com>".parse().unwrap())
-        .to("Charlie Wilkin <charlie@busyboredom.com>".parse().unwrap())
-        .subject("Contact Form Submission: ".to_owned() + &form.subject)
-        .multipart(
-            MultiPart::alternative()
-                // Plain text version.
-                .singlepart(SinglePart::plain(plain_message))
-                // HTML version.
-                .singlepart(SinglePart::html(html_message)),
-        )
-        .expect("failed to build email");
+    // Spam is kept for review, but nobody is emailed about it. The sender isn't told.
+    if spam.quarantine {
+        warn!(
+            "Quarantined contact form submission {submission_id:?} with spam score {}: {spam_reasons}",
+            spam.score
+        );
+        metrics.increment("contact_submissions_total", &[("outcome", "quarantined")]);
+        return Ok(());
+    }
 
-    // Send the email to myself.
-    match mailer.send(&email) {
-        Ok(_) => info!("Email sent successfully!"),
-        Err(e) => {
-            error!("Could not send email: {e:?}");
+    // Claim one of the address's confirmation links before holding anything for it. The earlier
+    // count only spares the captcha of a sender who is already over the limit; this is what
+    // enforces it.
+    if let Some(submission_id) = submission_id {
+        match inbox.claim_verification(
+            submission_id,
+            settings.contact.max_verifications_per_day,
+            VERIFICATION_WINDOW_SECS,
+        ) {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!(
+                    "Not sending confirmation link for submission {submission_id}: too many sent \
+                    to {}",
+                    form.email
+                );
+                return Err(ContactError::too_many_verifications());
+            }
+            Err(e) => {
+                error!("Could not record confirmation link for submission {submission_id}: {e}");
+                return Err(ContactError::unavailable());
+            }
         }
     }
 
-    // Build an autoreply.
-    if let Ok(autoreply_to) =
-        format!("{} {} <{}>", form.firstname, form.lastname, form.email).parse::<Mailbox>()
-    {
-        let autoreply_message = format!(
-            "Hello {},\n
-        Your message has been received and you can expect a response within
-        the next few days. Please have patience if my response time is slow
-        (especially on weekdays).",
-            form.firstname
+    metrics.increment("contact_submissions_total", &[("outcome", "accepted")]);
+
+    // Attach the sender's key, so I can encrypt my reply.
+    let mut parts: Vec<_> = attachments.iter().map(Attachment::part).collect();
+    if pgp_fingerprint.is_some() {
+        parts.push(
+            AttachmentPart::new("sender-public-key.asc".to_string()).body(
+                form.pgp_key.trim().to_string(),
+                ContentType::parse("application/pgp-keys").unwrap(),
+            ),
         );
-        let autoreply = Message::builder()
-            .from(
-                "Charlie Wilkin (Do Not Reply) <donotreply@busyboredom.com>"
-                    .parse()
-                    .unwrap(),
-            )
-            .to(autoreply_to)
-            .subject("Auto-Reply for: ".to_owned() + &form.subject)
-            .body(autoreply_message)
-            .expect("failed to build email");
-
-        // Send the autoreply.
-        match mailer.send(&autoreply) {
-            Ok(_) => info!("Autoreply sent successfully!"),
-            Err(e) => {
-                error!("Could not send autoreply: {e:?}");
+    }
+    let email = pgp
+        .build_for_owner(
+            Message::builder()
+                .from("Contact Form <donotreply@busyboredom.com>".parse().unwrap())
+                .to("Charlie Wilkin <charlie@busyboredom.com>".parse().unwrap()),
+            templates.render(
+                CONTACT_ADMIN,
+                &form.template_vars(pgp_fingerprint.as_deref().unwrap_or("none")),
+            ),
+            parts,
+        )
+        .await
+        .map_err(|e| error!("Could not build email about contact form submission: {e}"))
+        .ok();
+
+    // Without a stored submission there is nothing to confirm, so let it through. The sender
+    // isn't emailed, so this can't be used to send mail to anyone else.
+    let Some(submission_id) = submission_id else {
+        if let Some(Err(e)) = email.as_ref().map(|email| mail_queue.enqueue(email)) {
+            error!("Could not queue email: {e:?}");
+        }
+        notifier.publish(Event::ContactSubmitted {
+            submission_id: None,
+            firstname: form.firstname,
+            lastname: form.lastname,
+            email: form.email,
+            subject: form.subject,
+            message: form.message,
+        });
+        return Ok(());
+    };
+
+    // Hold the email to myself until the sender confirms their address.
+    match email.as_ref().map(|email| mail_queue.hold(email)) {
+        Some(Ok(mail_id)) => {
+            if let Err(e) = inbox.set_mail_id(submission_id, mail_id) {
+                error!("Could not link submission {submission_id} to email {mail_id}: {e}");
             }
         }
-    } else {
-        error!(
-            "Failed to parse email address submitted in contact form: {} {} <{}>",
-            form.firstname, form.lastname, form.email
+        Some(Err(e)) => error!("Could not hold email: {e:?}"),
+        None => {}
+    }
+
+    // Send a confirmation link. Nothing the sender typed is echoed back, not even their name.
+    let address: Address = form.email.trim().parse().expect("Email was validated");
+    let link = format!(
+        "{}/contact-verify?token={}",
+        settings.contact.public_url.trim_end_matches('/'),
+        inbox.verification_token(submission_id)
+    );
+    let verification = templates
+        .render(CONTACT_VERIFY, &[("link", &link)])
+        .build(
+            Message::builder()
+                .from(
+                    "Charlie Wilkin (Do Not Reply) <donotreply@busyboredom.com>"
+                        .parse()
+                        .unwrap(),
+                )
+                .to(Mailbox::new(None, address)),
         )
+        .expect("failed to build email");
+    if let Err(e) = mail_queue.enqueue(&verification) {
+        error!("Could not queue confirmation link: {e:?}");
     }
 
-    Ok(HttpResponse::build(StatusCode::OK)
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct VerifyForm {
+    token: String,
+}
+
+/// Deliver a held submission once its sender follows the link they were emailed. This is a POST
+/// made from the confirmation page, so link scanners that fetch every URL in an email can't
+/// confirm submissions on their own.
+#[post("/api/contact_verify")]
+pub async fn contact_verify(
+    mail_queue: web::Data<MailQueue>,
+    inbox: web::Data<Inbox>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    web::Form(form): web::Form<VerifyForm>,
+) -> Result<HttpResponse> {
+    let verified = match inbox.verify(form.token.trim()) {
+        Ok(Some(verified)) => verified,
+        Ok(None) => {
+            warn!("Rejected invalid contact form confirmation link");
+            metrics.increment("contact_verifications_total", &[("outcome", "invalid")]);
+            let e = ContactError::invalid_link();
+            return Ok(HttpResponse::build(e.status()).json(e));
+        }
+        Err(e) => {
+            error!("Could not verify contact form submission: {e}");
+            return Err(actix_web::error::ErrorInternalServerError(
+                "Could not confirm message",
+            ));
+        }
+    };
+    metrics.increment("contact_verifications_total", &[("outcome", "verified")]);
+
+    if let Some(mail_id) = verified.mail_id {
+        match mail_queue.release(mail_id) {
+            Ok(true) => {}
+            Ok(false) => warn!(
+                "Email {mail_id} for submission {} was not held",
+                verified.id
+            ),
+            Err(e) => error!("Could not release email {mail_id}: {e}"),
+        }
+    }
+    notifier.publish(Event::ContactSubmitted {
+        submission_id: Some(verified.id),
+        firstname: verified.firstname,
+        lastname: verified.lastname,
+        email: verified.email,
+        subject: verified.subject,
+        message: verified.message,
+    });
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Thanks! Your message has been delivered.",
+    })))
+}
+
+fn submitted_page() -> HttpResponse {
+    HttpResponse::build(StatusCode::OK)
         .content_type("text/html; charset=utf-8")
-        .body(template_composition("base.html", "contact_submitted.html")))
+        .body(template_composition("base.html", "contact_submitted.html"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form(subject: &str, message: &str) -> ContactForm {
+        ContactForm {
+            firstname: "Mallory".to_string(),
+            lastname: "<b>Evil</b>".to_string(),
+            email: "mallory@example.com".to_string(),
+            subject: subject.to_string(),
+            message: message.to_string(),
+            captchachars: "ABCD".to_string(),
+            ..ContactForm::default()
+        }
+    }
+
+    #[test]
+    fn rejects_injected_subject() {
+        let errors = form("Hi\r\nBcc: victim@example.com", "Hello")
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            errors.get("subject").map(String::as_str),
+            Some("Subject must be a single line of text.")
+        );
+    }
+
+    #[test]
+    fn reports_every_invalid_field() {
+        let form = ContactForm {
+            email: "not an email".to_string(),
+            lastname: "x".repeat(MAX_NAME_LEN + 1),
+            message: " \n ".to_string(),
+            ..ContactForm::default()
+        };
+        let errors = form.validate().unwrap_err();
+        assert_eq!(
+            errors.keys().copied().collect::<Vec<_>>(),
+            [
+                "captchachars",
+                "email",
+                "firstname",
+                "lastname",
+                "message",
+                "subject"
+            ]
+        );
+        assert_eq!(errors["email"], "Email must be a valid email address.");
+        assert_eq!(
+            errors["lastname"],
+            "Last name must be at most 100 characters."
+        );
+    }
+
+    #[test]
+    fn reads_json_submission() {
+        let request: ContactRequest = serde_json::from_str(
+            r#"{"firstname": "Alice", "email": "alice@example.com", "message": "Hi",
+            "attachments": [{"filename": "a.txt", "data": "aGk="}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            (
+                request.form.firstname.as_str(),
+                request.form.subject.as_str()
+            ),
+            ("Alice", "")
+        );
+        assert_eq!(request.attachments[0].filename, "a.txt");
+    }
+
+    #[test]
+    fn admin_email_escapes_submission() {
+        let form = form("Hi", "<a href=\"https://evil.example\">click</a>");
+        assert!(form.validate().is_ok());
+        let templates = EmailTemplates::load("/nonexistent").unwrap();
+        let html = templates
+            .render(CONTACT_ADMIN, &form.template_vars("none"))
+            .html
+            .unwrap();
+        assert!(html.contains("&lt;b&gt;Evil&lt;/b&gt;"));
+        assert!(html.contains("&lt;a href=&quot;https://evil.example&quot;&gt;"));
+        assert!(!html.contains("<a href"));
+    }
 }
@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use rand::{rng, Rng};
+use sqlite::{Connection, ConnectionThreadSafe, State, Value};
+
+use crate::Settings;
+
+/// How long to wait for a lock held by another connection, e.g. a CLI command run while the
+/// server is up.
+const BUSY_TIMEOUT_MS: usize = 5000;
+
+/// Connection to the website's own SQLite database, shared by every store that lives in it.
+pub type Db = Arc<ConnectionThreadSafe>;
+
+/// Open the website's database, creating it if it doesn't exist.
+pub fn open(settings: &Settings) -> Result<Db, sqlite::Error> {
+    open_path(&settings.database_path())
+}
+
+/// Open the database at `path`, which may be `:memory:`.
+pub fn open_path(path: &str) -> Result<Db, sqlite::Error> {
+    let mut db = Connection::open_thread_safe(path)?;
+    db.set_busy_timeout(BUSY_TIMEOUT_MS)?;
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS keys (
+            name TEXT PRIMARY KEY,
+            key  BLOB NOT NULL
+        );",
+    )?;
+    Ok(Arc::new(db))
+}
+
+/// Get the named secret key, generating and persisting a random one on first use. Keys survive
+/// restarts, so anything hashed or signed with them stays valid.
+pub fn key(db: &Db, name: &str) -> Result<[u8; 32], sqlite::Error> {
+    let mut key = [0u8; 32];
+    rng().fill(&mut key[..]);
+
+    let mut statement = db.prepare(
+        "INSERT INTO keys (name, key) VALUES (:name, :key)
+        ON CONFLICT (name) DO UPDATE SET name = name
+        RETURNING key",
+    )?;
+    statement.bind::<&[(_, Value)]>(&[(":name", name.into()), (":key", key[..].into())][..])?;
+    if statement.next()? == State::Row {
+        let stored = statement.read::<Vec<u8>, _>("key")?;
+        key.copy_from_slice(&stored);
+    }
+    Ok(key)
+}
+
+/// Add a column to an existing table, unless it is already there. Used to upgrade databases
+/// created before the column existed.
+pub fn add_column(
+    db: &Db,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlite::Error> {
+    let mut statement = db.prepare(format!("PRAGMA table_info(\"{table}\")"))?;
+    while statement.next()? == State::Row {
+        if statement.read::<String, _>("name")? == column {
+            return Ok(());
+        }
+    }
+    db.execute(format!(
+        "ALTER TABLE \"{table}\" ADD COLUMN {column} {definition}"
+    ))
+}
@@ -0,0 +1,148 @@
+use std::{convert::TryInto, fs, io};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lettre::{
+    message::dkim::{DkimConfig, DkimSigningAlgorithm, DkimSigningKey},
+    Message,
+};
+use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs8::EncodePublicKey, RsaPrivateKey};
+use serde::Deserialize;
+
+/// Longest string allowed in a DNS TXT record. Longer values are split into several strings.
+const MAX_TXT_STRING_LEN: usize = 255;
+
+#[derive(Deserialize, Clone)]
+pub struct DkimSettings {
+    /// Name of the key in DNS, published at `<selector>._domainkey.<domain>`.
+    pub selector: String,
+    /// Domain mail is signed for. Should match the domain of the `From` address.
+    pub domain: String,
+    /// PEM encoded PKCS#1 RSA private key, or a base64 encoded Ed25519 seed.
+    pub private_key_path: String,
+    #[serde(default)]
+    pub algorithm: DkimAlgorithm,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimAlgorithm {
+    #[default]
+    Rsa,
+    Ed25519,
+}
+
+/// Signs outgoing mail with the configured DKIM key.
+pub struct DkimSigner {
+    config: DkimConfig,
+    selector: String,
+    domain: String,
+    algorithm: DkimAlgorithm,
+    /// Public key as published in the `p=` tag of the DNS record.
+    public_key: Vec<u8>,
+}
+
+impl DkimSigner {
+    pub fn load(settings: &DkimSettings) -> io::Result<Self> {
+        let private_key = fs::read_to_string(&settings.private_key_path)?;
+        let private_key = private_key.trim();
+        let (algorithm, public_key) = match settings.algorithm {
+            DkimAlgorithm::Rsa => {
+                let key = RsaPrivateKey::from_pkcs1_pem(private_key).map_err(io::Error::other)?;
+                let public_key = key
+                    .to_public_key()
+                    .to_public_key_der()
+                    .map_err(io::Error::other)?;
+                (DkimSigningAlgorithm::Rsa, public_key.into_vec())
+            }
+            DkimAlgorithm::Ed25519 => {
+                let seed: [u8; 32] = STANDARD
+                    .decode(private_key)
+                    .map_err(io::Error::other)?
+                    .try_into()
+                    .map_err(|_| io::Error::other("Ed25519 seed must be 32 bytes"))?;
+                let key = ed25519_dalek::SigningKey::from_bytes(&seed);
+                (
+                    DkimSigningAlgorithm::Ed25519,
+                    key.verifying_key().to_bytes().to_vec(),
+                )
+            }
+        };
+        let signing_key = DkimSigningKey::new(private_key, algorithm).map_err(io::Error::other)?;
+
+        Ok(Self {
+            config: DkimConfig::default_config(
+                settings.selector.clone(),
+                settings.domain.clone(),
+                signing_key,
+            ),
+            selector: settings.selector.clone(),
+            domain: settings.domain.clone(),
+            algorithm: settings.algorithm,
+            public_key,
+        })
+    }
+
+    pub fn sign(&self, message: &mut Message) {
+        message.sign(&self.config);
+    }
+
+    /// Name of the TXT record the public key must be published under.
+    pub fn record_name(&self) -> String {
+        format!("{}._domainkey.{}", self.selector, self.domain)
+    }
+
+    /// Value of the TXT record, split into quoted strings short enough for DNS.
+    pub fn record_value(&self) -> String {
+        let k = match self.algorithm {
+            DkimAlgorithm::Rsa => "rsa",
+            DkimAlgorithm::Ed25519 => "ed25519",
+        };
+        let value = format!("v=DKIM1; k={k}; p={}", STANDARD.encode(&self.public_key));
+        value
+            .as_bytes()
+            .chunks(MAX_TXT_STRING_LEN)
+            .map(|chunk| format!("\"{}\"", String::from_utf8_lossy(chunk)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_with_ed25519_key() {
+        let path = std::env::temp_dir().join(format!("dkim-{}.key", std::process::id()));
+        fs::write(&path, STANDARD.encode([7u8; 32])).unwrap();
+        let signer = DkimSigner::load(&DkimSettings {
+            selector: "mail".to_string(),
+            domain: "example.com".to_string(),
+            private_key_path: path.to_str().unwrap().to_string(),
+            algorithm: DkimAlgorithm::Ed25519,
+        })
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut message = Message::builder()
+            .from("Alice <alice@example.com>".parse().unwrap())
+            .to("bob@example.org".parse().unwrap())
+            .subject("Hi")
+            .body("Hello".to_string())
+            .unwrap();
+        signer.sign(&mut message);
+        let signature = message.headers().get_raw("DKIM-Signature").unwrap();
+        assert!(signature.contains("a=ed25519-sha256"), "{}", signature);
+        assert!(signature.contains("d=example.com"), "{}", signature);
+        assert!(signature.contains("s=mail"), "{}", signature);
+
+        assert_eq!(signer.record_name(), "mail._domainkey.example.com");
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32])
+            .verifying_key()
+            .to_bytes();
+        assert_eq!(
+            signer.record_value(),
+            format!("\"v=DKIM1; k=ed25519; p={}\"", STANDARD.encode(public_key))
+        );
+    }
+}
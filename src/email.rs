@@ -0,0 +1,187 @@
+use std::fmt;
+
+/// Longest name (first or last) accepted in a header.
+pub const MAX_NAME_LEN: usize = 100;
+/// Longest email address accepted, per RFC 5321.
+pub const MAX_ADDRESS_LEN: usize = 254;
+/// Longest subject accepted.
+pub const MAX_SUBJECT_LEN: usize = 200;
+/// Longest line allowed in an email, per RFC 5322.
+pub const MAX_HEADER_LINE_LEN: usize = 998;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The value contains a line break or other control character, which could be used to inject
+    /// additional headers.
+    ControlCharacter,
+    TooLong {
+        max: usize,
+    },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::ControlCharacter => write!(f, "must be a single line of text"),
+            HeaderError::TooLong { max } => write!(f, "must be at most {max} characters"),
+        }
+    }
+}
+
+/// Check that user input is safe to place in an email header.
+pub fn header_value(value: &str, max_len: usize) -> Result<&str, HeaderError> {
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        return Err(HeaderError::ControlCharacter);
+    }
+    if value.chars().count() > max_len {
+        return Err(HeaderError::TooLong { max: max_len });
+    }
+    Ok(value)
+}
+
+/// Collapse arbitrary user input into a single header-safe line of at most `max_len` characters,
+/// for use where rejecting the input isn't an option.
+pub fn header_summary(value: &str, max_len: usize) -> String {
+    let line = value
+        .split(|c: char| c.is_control() || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if line.chars().count() <= max_len {
+        return line;
+    }
+    line.chars()
+        .take(max_len.saturating_sub(3))
+        .collect::<String>()
+        + "..."
+}
+
+/// Escape text for inclusion in HTML element content or a quoted attribute.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Fill in `{{name}}` placeholders in an HTML template. Values are always escaped, so user input
+/// can't add markup or links to the email.
+pub fn render_html(template: &str, vars: &[(&str, &str)]) -> String {
+    render(template, vars, escape_html)
+}
+
+/// Fill in `{{name}}` placeholders in a plain text template.
+pub fn render_text(template: &str, vars: &[(&str, &str)]) -> String {
+    render(template, vars, str::to_string)
+}
+
+fn render(template: &str, vars: &[(&str, &str)], escape: impl Fn(&str) -> String) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after_open = &rest[start + 2..];
+        let Some(end) = after_open.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = after_open[..end].trim();
+        match vars.iter().find(|(var, _)| *var == name) {
+            Some((_, value)) => rendered.push_str(&escape(value)),
+            // Leave unknown placeholders as they are so mistakes are visible.
+            None => rendered.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after_open[end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_markup() {
+        assert_eq!(
+            escape_html(r#"<a href="https://evil.example">'click'</a> & more"#),
+            "&lt;a href=&quot;https://evil.example&quot;&gt;&#x27;click&#x27;&lt;/a&gt; &amp; more"
+        );
+    }
+
+    #[test]
+    fn html_template_escapes_values() {
+        let html = render_html(
+            "<b>Name: </b>{{name}}<br>{{ message }}",
+            &[
+                ("name", "<script>alert(1)</script>"),
+                ("message", "<img src=x>"),
+            ],
+        );
+        assert_eq!(
+            html,
+            "<b>Name: </b>&lt;script&gt;alert(1)&lt;/script&gt;<br>&lt;img src=x&gt;"
+        );
+    }
+
+    #[test]
+    fn values_are_not_expanded_recursively() {
+        let text = render_text("{{a}} {{b}}", &[("a", "{{b}}"), ("b", "B")]);
+        assert_eq!(text, "{{b}} B");
+    }
+
+    #[test]
+    fn unknown_and_unclosed_placeholders_are_kept() {
+        assert_eq!(render_text("{{missing}} {{open", &[]), "{{missing}} {{open");
+    }
+
+    #[test]
+    fn rejects_header_injection() {
+        for hostile in [
+            "Hello\r\nBcc: victim@example.com",
+            "Hello\nBcc: victim@example.com",
+            "Hello\rBcc: victim@example.com",
+            "Hello\0",
+            "Hello\u{85}Bcc: victim@example.com",
+        ] {
+            assert_eq!(
+                header_value(hostile, MAX_SUBJECT_LEN),
+                Err(HeaderError::ControlCharacter),
+                "{hostile:?} was accepted"
+            );
+        }
+        assert_eq!(
+            header_value("Hello\tthere", MAX_SUBJECT_LEN),
+            Ok("Hello\tthere")
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_headers() {
+        let long = "a".repeat(MAX_SUBJECT_LEN + 1);
+        assert_eq!(
+            header_value(&long, MAX_SUBJECT_LEN),
+            Err(HeaderError::TooLong {
+                max: MAX_SUBJECT_LEN
+            })
+        );
+        // Length is counted in characters, not bytes.
+        let accented = "é".repeat(MAX_NAME_LEN);
+        assert!(header_value(&accented, MAX_NAME_LEN).is_ok());
+    }
+
+    #[test]
+    fn summary_is_single_line_and_bounded() {
+        let summary = header_summary("Hi\r\nBcc: victim@example.com\n\nmore text", 20);
+        assert_eq!(summary, "Hi Bcc: victim@ex...");
+        assert_eq!(header_summary("  short  ", 20), "short");
+    }
+}
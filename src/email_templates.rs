@@ -0,0 +1,289 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use clap::Subcommand;
+use lettre::{
+    message::{MessageBuilder, MultiPart, SinglePart},
+    Message,
+};
+use log::{info, warn};
+use rust_embed::RustEmbed;
+
+use crate::email::{header_summary, render_html, render_text, MAX_HEADER_LINE_LEN};
+
+/// Notification to me about a contact form submission.
+pub const CONTACT_ADMIN: &str = "contact_admin";
+/// Link sent to whoever submitted the contact form, to confirm the address is theirs. Only
+/// `{{link}}` is available: nothing the sender typed is echoed back.
+pub const CONTACT_VERIFY: &str = "contact_verify";
+/// Notification to me about a paid AcceptXMR demo invoice.
+pub const ACCEPTXMR_ADMIN: &str = "acceptxmr_admin";
+/// Receipt for whoever paid an AcceptXMR demo invoice.
+pub const ACCEPTXMR_USER: &str = "acceptxmr_user";
+/// Notification to me about an AcceptXMR demo invoice that expired partly paid.
+pub const ACCEPTXMR_SHORTFALL_ADMIN: &str = "acceptxmr_shortfall_admin";
+/// Tells whoever partly paid an AcceptXMR demo invoice how much was missing when it expired.
+pub const ACCEPTXMR_SHORTFALL_USER: &str = "acceptxmr_shortfall_user";
+
+/// Variables used when previewing templates.
+const SAMPLE_VARS: &[(&str, &str)] = &[
+    ("firstname", "Alice"),
+    ("lastname", "Example"),
+    ("email", "alice@example.com"),
+    ("subject", "Hello there"),
+    ("message", "Hi Charlie,\n\nJust wanted to say <b>hello</b>."),
+    ("items", "1 x AcceptXMR Demo at 0.25 USD"),
+    ("amount", "0.001000000000"),
+    ("amount_requested", "0.001000000000"),
+    ("shortfall", "0.000250000000"),
+    ("payment_note", "Overpaid by 0.000010000000 XMR."),
+    ("late_payment_grace_blocks", "30"),
+    ("confirmations", "2"),
+    ("confirmations_required", "2"),
+    (
+        "link",
+        "https://busyboredom.com/contact-verify?token=1.0000000000000000",
+    ),
+];
+
+/// Default templates. Each template is a `<name>.subject` file, a `<name>.txt` file, and optionally
+/// a `<name>.html` file.
+#[derive(RustEmbed)]
+#[folder = "email_templates/"]
+struct DefaultTemplates;
+
+#[derive(Clone)]
+struct EmailTemplate {
+    subject: String,
+    text: String,
+    html: Option<String>,
+}
+
+/// Templates for all outgoing email. Files in `<data_dir>/email_templates/` replace the embedded
+/// default file of the same name.
+#[derive(Clone)]
+pub struct EmailTemplates {
+    templates: BTreeMap<String, EmailTemplate>,
+}
+
+impl EmailTemplates {
+    pub fn load(data_dir: &str) -> io::Result<Self> {
+        let mut templates = BTreeMap::new();
+        for file in DefaultTemplates::iter() {
+            let Some((name, _)) = file.split_once('.') else {
+                continue;
+            };
+            if templates.contains_key(name) {
+                continue;
+            }
+            let part = |extension: &str| {
+                DefaultTemplates::get(&format!("{name}.{extension}"))
+                    .map(|file| String::from_utf8_lossy(&file.data).into_owned())
+            };
+            templates.insert(
+                name.to_string(),
+                EmailTemplate {
+                    subject: part("subject").expect("Default email template is missing a subject"),
+                    text: part("txt").expect("Default email template is missing a text part"),
+                    html: part("html"),
+                },
+            );
+        }
+
+        let override_dir = Path::new(data_dir).join("email_templates");
+        if override_dir.is_dir() {
+            for entry in fs::read_dir(&override_dir)? {
+                let path = entry?.path();
+                let (Some(name), Some(extension)) = (
+                    path.file_stem().and_then(|name| name.to_str()),
+                    path.extension().and_then(|extension| extension.to_str()),
+                ) else {
+                    continue;
+                };
+                let Some(template) = templates.get_mut(name) else {
+                    warn!("Ignoring unknown email template {}", path.display());
+                    continue;
+                };
+                let content = fs::read_to_string(&path)?;
+                match extension {
+                    "subject" => template.subject = content,
+                    "txt" => template.text = content,
+                    "html" => template.html = Some(content),
+                    _ => {
+                        warn!("Ignoring unknown email template {}", path.display());
+                        continue;
+                    }
+                }
+                info!("Using email template {}", path.display());
+            }
+        }
+
+        Ok(Self { templates })
+    }
+
+    /// Render the named template. Panics if there is no such template, since the names are
+    /// constants defined above.
+    pub fn render(&self, name: &str, vars: &[(&str, &str)]) -> RenderedEmail {
+        let template = self
+            .templates
+            .get(name)
+            .unwrap_or_else(|| panic!("No email template named {}", name));
+        RenderedEmail {
+            // Values are free-form, so flatten the subject into something that can't inject
+            // headers.
+            subject: header_summary(&render_text(&template.subject, vars), MAX_HEADER_LINE_LEN),
+            text: render_text(&template.text, vars),
+            html: template.html.as_ref().map(|html| render_html(html, vars)),
+        }
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.templates.keys().map(String::as_str)
+    }
+}
+
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+impl RenderedEmail {
+    /// Set the subject and body of a message. The body is the text part alone, or text and HTML
+    /// alternatives if the template has an HTML part.
+    pub fn build(self, builder: MessageBuilder) -> Result<Message, lettre::error::Error> {
+        self.build_with_attachments(builder, Vec::new())
+    }
+
+    /// Like [`RenderedEmail::build`], with files attached after the body.
+    pub fn build_with_attachments(
+        self,
+        builder: MessageBuilder,
+        attachments: Vec<SinglePart>,
+    ) -> Result<Message, lettre::error::Error> {
+        let builder = builder.subject(self.subject.clone());
+        if self.html.is_none() && attachments.is_empty() {
+            return builder.singlepart(SinglePart::plain(self.text));
+        }
+        builder.multipart(self.body(attachments))
+    }
+
+    /// The body with any attachments as a single MIME entity, without the subject. Used to wrap
+    /// the body in another entity, such as an encrypted one.
+    pub fn body(self, attachments: Vec<SinglePart>) -> MultiPart {
+        let text = SinglePart::plain(self.text);
+        let mixed = match self.html {
+            Some(html) => {
+                let alternative = MultiPart::alternative()
+                    .singlepart(text)
+                    .singlepart(SinglePart::html(html));
+                if attachments.is_empty() {
+                    return alternative;
+                }
+                MultiPart::mixed().multipart(alternative)
+            }
+            None => MultiPart::mixed().singlepart(text),
+        };
+        attachments
+            .into_iter()
+            .fold(mixed, |mixed, attachment| mixed.singlepart(attachment))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TemplateCommand {
+    /// List available email templates.
+    List,
+    /// Render a template with sample data.
+    Preview {
+        /// Name of the template.
+        name: String,
+        /// Override a sample variable, as `name=value`. May be repeated.
+        #[arg(long = "var", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+    },
+}
+
+fn parse_var(var: &str) -> Result<(String, String), String> {
+    var.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected name=value, got {var:?}"))
+}
+
+pub fn run_command(command: TemplateCommand, templates: &EmailTemplates) -> Result<(), String> {
+    match command {
+        TemplateCommand::List => {
+            for name in templates.names() {
+                println!("{name}");
+            }
+        }
+        TemplateCommand::Preview { name, vars } => {
+            if !templates.templates.contains_key(&name) {
+                return Err(format!("No email template named {name:?}"));
+            }
+            let mut all_vars: BTreeMap<&str, &str> = SAMPLE_VARS.iter().copied().collect();
+            for (var, value) in &vars {
+                all_vars.insert(var, value);
+            }
+            let all_vars: Vec<_> = all_vars.into_iter().collect();
+            let email = templates.render(&name, &all_vars);
+            println!("Subject: {}\n", email.subject);
+            println!("{}", email.text);
+            if let Some(html) = email.html {
+                println!("\n----- HTML -----\n{html}");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_dir_overrides_default_parts() {
+        let data_dir = std::env::temp_dir().join(format!("email-templates-{}", std::process::id()));
+        fs::create_dir_all(data_dir.join("email_templates")).unwrap();
+        fs::write(
+            data_dir.join("email_templates/contact_admin.html"),
+            "<p>Hi {{firstname}}</p>",
+        )
+        .unwrap();
+
+        let templates = EmailTemplates::load(data_dir.to_str().unwrap()).unwrap();
+        let email = templates.render(
+            CONTACT_ADMIN,
+            &[("firstname", "<i>Al</i>"), ("subject", "Hi\r\nBcc: x")],
+        );
+        fs::remove_dir_all(&data_dir).unwrap();
+
+        assert_eq!(email.subject, "Contact Form Submission: Hi Bcc: x");
+        assert!(email.text.starts_with("First Name: <i>Al</i>"));
+        assert_eq!(
+            email.html.as_deref(),
+            Some("<p>Hi &lt;i&gt;Al&lt;/i&gt;</p>")
+        );
+    }
+
+    #[test]
+    fn text_body_is_not_nested_under_attachments() {
+        let email = RenderedEmail {
+            subject: "Hi".to_string(),
+            text: "Hello".to_string(),
+            html: None,
+        };
+        let attachment = lettre::message::Attachment::new("notes.txt".to_string())
+            .body(b"notes".to_vec(), "text/plain".parse().unwrap());
+        let message = email
+            .build_with_attachments(
+                Message::builder()
+                    .from("a@example.com".parse().unwrap())
+                    .to("b@example.com".parse().unwrap()),
+                vec![attachment],
+            )
+            .unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert_eq!(formatted.matches("multipart/mixed").count(), 1);
+        assert!(formatted.contains("Hello"));
+    }
+}
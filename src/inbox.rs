@@ -0,0 +1,733 @@
+use std::{net::IpAddr, time::Duration};
+
+use actix_web::{
+    get,
+    http::header::{self, CacheControl, CacheDirective, ContentDisposition, DispositionParam},
+    post, web, HttpResponse, Result,
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sqlite::{State, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    admin::{self, csv_row, Admin},
+    db::{self, Db},
+    email::escape_html,
+    mail::MailQueue,
+};
+
+/// Number of hex characters of the keyed client IP hash that are stored.
+const IP_HASH_LEN: usize = 16;
+/// How long a sender has to confirm their address before the link stops working.
+const VERIFICATION_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+/// How often emails held for submissions that were never confirmed are cleared out.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A contact form submission about to be stored.
+pub struct NewSubmission<'a> {
+    pub firstname: &'a str,
+    pub lastname: &'a str,
+    pub email: &'a str,
+    pub subject: &'a str,
+    pub message: &'a str,
+    pub client_ip: Option<IpAddr>,
+    pub spam_score: u32,
+    /// Why the submission looks like spam, if it does.
+    pub spam_reasons: &'a str,
+    /// Quarantined submissions are kept here only; nobody is emailed about them.
+    pub quarantined: bool,
+    /// The sender's PGP public key, or empty if they didn't give one.
+    pub pgp_key: &'a str,
+}
+
+#[derive(Serialize)]
+pub struct Submission {
+    pub id: i64,
+    pub created_at: String,
+    pub firstname: String,
+    pub lastname: String,
+    pub email: String,
+    pub subject: String,
+    pub message: String,
+    pub client_ip_hash: Option<String>,
+    /// Status of the notification email in the outbound mail queue.
+    pub delivery_status: String,
+    pub handled: bool,
+    pub spam_score: i64,
+    pub spam_reasons: String,
+    pub quarantined: bool,
+    pub pgp_key: String,
+}
+
+/// A submission whose sender has just confirmed their address.
+pub struct VerifiedSubmission {
+    pub id: i64,
+    /// The held email notifying the site owner, if one was queued.
+    pub mail_id: Option<i64>,
+    pub firstname: String,
+    pub lastname: String,
+    pub email: String,
+    pub subject: String,
+    pub message: String,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HandledFilter {
+    #[default]
+    Open,
+    Handled,
+    Quarantined,
+    All,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct InboxQuery {
+    /// Text to look for in the sender's name, email, subject or message.
+    q: String,
+    status: HandledFilter,
+}
+
+/// Every contact form submission, kept regardless of whether notifying the site owner worked.
+#[derive(Clone)]
+pub struct Inbox {
+    db: Db,
+    ip_key: [u8; 32],
+    verification_key: [u8; 32],
+}
+
+impl Inbox {
+    pub fn open(db: Db) -> Result<Inbox, sqlite::Error> {
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS contact_submissions (
+                id             INTEGER PRIMARY KEY AUTOINCREMENT,
+                created_at     INTEGER NOT NULL,
+                firstname      TEXT NOT NULL,
+                lastname       TEXT NOT NULL,
+                email          TEXT NOT NULL,
+                subject        TEXT NOT NULL,
+                message        TEXT NOT NULL,
+                client_ip_hash TEXT,
+                mail_id        INTEGER,
+                handled        INTEGER NOT NULL DEFAULT 0
+            );",
+        )?;
+        db::add_column(
+            &db,
+            "contact_submissions",
+            "spam_score",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        db::add_column(
+            &db,
+            "contact_submissions",
+            "spam_reasons",
+            "TEXT NOT NULL DEFAULT ''",
+        )?;
+        db::add_column(
+            &db,
+            "contact_submissions",
+            "quarantined",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        db::add_column(
+            &db,
+            "contact_submissions",
+            "verification_sent_at",
+            "INTEGER",
+        )?;
+        db::add_column(&db, "contact_submissions", "verified_at", "INTEGER")?;
+        db::add_column(
+            &db,
+            "contact_submissions",
+            "pgp_key",
+            "TEXT NOT NULL DEFAULT ''",
+        )?;
+        let ip_key = db::key(&db, "client_ip")?;
+        let verification_key = db::key(&db, "contact_verification")?;
+        Ok(Inbox {
+            db,
+            ip_key,
+            verification_key,
+        })
+    }
+
+    /// Store a submission, returning its ID.
+    pub fn record(&self, submission: &NewSubmission) -> Result<i64, sqlite::Error> {
+        let mut statement = self.db.prepare(
+            "INSERT INTO contact_submissions
+                (created_at, firstname, lastname, email, subject, message, client_ip_hash,
+                spam_score, spam_reasons, quarantined, pgp_key)
+            VALUES (:created_at, :firstname, :lastname, :email, :subject, :message, :ip_hash,
+                :spam_score, :spam_reasons, :quarantined, :pgp_key)
+            RETURNING id",
+        )?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (
+                    ":created_at",
+                    OffsetDateTime::now_utc().unix_timestamp().into(),
+                ),
+                (":firstname", submission.firstname.into()),
+                (":lastname", submission.lastname.into()),
+                (":email", submission.email.into()),
+                (":subject", submission.subject.into()),
+                (":message", submission.message.into()),
+                (
+                    ":ip_hash",
+                    submission
+                        .client_ip
+                        .map_or(Value::Null, |ip| self.hash_ip(&ip.to_string()).into()),
+                ),
+                (":spam_score", i64::from(submission.spam_score).into()),
+                (":spam_reasons", submission.spam_reasons.into()),
+                (":quarantined", i64::from(submission.quarantined).into()),
+                (":pgp_key", submission.pgp_key.into()),
+            ][..],
+        )?;
+        statement.next()?;
+        let id = statement.read::<i64, _>("id")?;
+        if submission.quarantined {
+            info!("Quarantined contact form submission {id}");
+        } else {
+            info!("Stored contact form submission {id}");
+        }
+        Ok(id)
+    }
+
+    /// Link a submission to the queued email notifying the site owner of it.
+    pub fn set_mail_id(&self, id: i64, mail_id: i64) -> Result<(), sqlite::Error> {
+        let mut statement = self
+            .db
+            .prepare("UPDATE contact_submissions SET mail_id = :mail_id WHERE id = :id")?;
+        statement.bind::<&[(_, Value)]>(&[(":mail_id", mail_id.into()), (":id", id.into())][..])?;
+        while statement.next()? == State::Row {}
+        Ok(())
+    }
+
+    /// Signed token for the link that confirms a submission's sender owns the address they gave.
+    pub fn verification_token(&self, id: i64) -> String {
+        let mac = blake3::keyed_hash(&self.verification_key, id.to_string().as_bytes());
+        format!("{id}.{}", mac.to_hex())
+    }
+
+    /// ID of the submission a verification token was issued for, if the token is ours.
+    fn verification_token_id(&self, token: &str) -> Option<i64> {
+        let (id, mac) = token.split_once('.')?;
+        let id: i64 = id.parse().ok()?;
+        let mac = blake3::Hash::from_hex(mac).ok()?;
+        // `blake3::Hash` comparisons are constant time.
+        (mac == blake3::keyed_hash(&self.verification_key, id.to_string().as_bytes())).then_some(id)
+    }
+
+    /// Record that a verification link is being emailed for a submission, unless `max` links
+    /// were already sent to its address in the last `secs` seconds. Counting and recording happen
+    /// in one statement, so concurrent submissions can't all slip under the limit. Returns `false`
+    /// if the limit was reached.
+    pub fn claim_verification(&self, id: i64, max: u32, secs: i64) -> Result<bool, sqlite::Error> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut statement = self.db.prepare(
+            "UPDATE contact_submissions SET verification_sent_at = :now
+            WHERE id = :id AND (
+                SELECT COUNT(*) FROM contact_submissions AS sent
+                WHERE lower(trim(sent.email)) = lower(trim(contact_submissions.email))
+                    AND sent.verification_sent_at >= :since
+            ) < :max
+            RETURNING id",
+        )?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":now", now.into()),
+                (":id", id.into()),
+                (":since", (now - secs).into()),
+                (":max", i64::from(max).into()),
+            ][..],
+        )?;
+        Ok(statement.next()? == State::Row)
+    }
+
+    /// Number of verification links emailed to an address in the last `secs` seconds.
+    pub fn verifications_sent(&self, email: &str, secs: i64) -> Result<i64, sqlite::Error> {
+        let mut statement = self.db.prepare(
+            "SELECT COUNT(*) AS sent FROM contact_submissions
+            WHERE lower(trim(email)) = lower(trim(:email)) AND verification_sent_at >= :since",
+        )?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":email", email.into()),
+                (
+                    ":since",
+                    (OffsetDateTime::now_utc().unix_timestamp() - secs).into(),
+                ),
+            ][..],
+        )?;
+        statement.next()?;
+        statement.read("sent")
+    }
+
+    /// Mark the submission a verification token was issued for as verified. Returns `None` if the
+    /// token is invalid, has expired or was already used.
+    pub fn verify(&self, token: &str) -> Result<Option<VerifiedSubmission>, sqlite::Error> {
+        let Some(id) = self.verification_token_id(token) else {
+            return Ok(None);
+        };
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut statement = self.db.prepare(
+            "UPDATE contact_submissions SET verified_at = :now
+            WHERE id = :id AND verified_at IS NULL AND quarantined = 0
+                AND created_at >= :oldest
+            RETURNING mail_id, firstname, lastname, email, subject, message",
+        )?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":now", now.into()),
+                (":oldest", (now - VERIFICATION_TTL_SECS).into()),
+                (":id", id.into()),
+            ][..],
+        )?;
+        if statement.next()? != State::Row {
+            return Ok(None);
+        }
+        let verified = VerifiedSubmission {
+            id,
+            mail_id: statement.read("mail_id")?,
+            firstname: statement.read("firstname")?,
+            lastname: statement.read("lastname")?,
+            email: statement.read("email")?,
+            subject: statement.read("subject")?,
+            message: statement.read("message")?,
+        };
+        while statement.next()? == State::Row {}
+        info!("Contact form submission {id} verified");
+        Ok(Some(verified))
+    }
+
+    /// Discard the held emails of submissions whose sender didn't confirm their address in time,
+    /// since the links can no longer release them. Returns the number of emails discarded.
+    pub fn sweep_unverified(&self, mail_queue: &MailQueue) -> Result<usize, sqlite::Error> {
+        let oldest = OffsetDateTime::now_utc().unix_timestamp() - VERIFICATION_TTL_SECS;
+        let mut statement = self.db.prepare(
+            "SELECT id, mail_id FROM contact_submissions
+            WHERE verified_at IS NULL AND mail_id IS NOT NULL AND created_at < :oldest",
+        )?;
+        statement.bind((":oldest", oldest))?;
+        let mut expired = Vec::new();
+        while statement.next()? == State::Row {
+            expired.push((
+                statement.read::<i64, _>("id")?,
+                statement.read::<i64, _>("mail_id")?,
+            ));
+        }
+
+        let mut discarded = 0;
+        for (id, mail_id) in expired {
+            if mail_queue.discard(mail_id)? {
+                discarded += 1;
+            }
+            let mut statement = self
+                .db
+                .prepare("UPDATE contact_submissions SET mail_id = NULL WHERE id = :id")?;
+            statement.bind((":id", id))?;
+            while statement.next()? == State::Row {}
+        }
+        Ok(discarded)
+    }
+
+    /// Spawn the background task that periodically runs [`Inbox::sweep_unverified`].
+    pub fn start_sweeper(&self, mail_queue: MailQueue) {
+        let inbox = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match inbox.sweep_unverified(&mail_queue) {
+                    Ok(0) => {}
+                    Ok(discarded) => {
+                        info!("Discarded {discarded} email(s) of unconfirmed contact form submissions")
+                    }
+                    Err(e) => error!("Could not sweep unconfirmed contact form submissions: {e}"),
+                }
+                tokio::time::sleep(SWEEP_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Mark a submission as handled (or not). Returns `false` if there is no such submission.
+    pub fn set_handled(&self, id: i64, handled: bool) -> Result<bool, sqlite::Error> {
+        let mut statement = self.db.prepare(
+            "UPDATE contact_submissions SET handled = :handled WHERE id = :id RETURNING id",
+        )?;
+        statement.bind::<&[(_, Value)]>(
+            &[(":handled", i64::from(handled).into()), (":id", id.into())][..],
+        )?;
+        Ok(statement.next()? == State::Row)
+    }
+
+    /// Find submissions matching the query, most recent first.
+    pub fn search(&self, query: &InboxQuery) -> Result<Vec<Submission>, sqlite::Error> {
+        let mut statement = self.db.prepare(
+            "SELECT s.*, COALESCE(m.status, 'not queued') AS delivery_status
+            FROM contact_submissions s
+            LEFT JOIN outbound_mail m ON m.id = s.mail_id
+            WHERE (:handled IS NULL OR s.handled = :handled)
+                AND (:quarantined IS NULL OR s.quarantined = :quarantined)
+                AND (:q = '' OR s.firstname || ' ' || s.lastname LIKE :pattern ESCAPE '\\'
+                    OR s.email LIKE :pattern ESCAPE '\\'
+                    OR s.subject LIKE :pattern ESCAPE '\\'
+                    OR s.message LIKE :pattern ESCAPE '\\')
+            ORDER BY s.id DESC",
+        )?;
+        let (handled, quarantined) = match query.status {
+            HandledFilter::Open => (Value::Integer(0), Value::Integer(0)),
+            HandledFilter::Handled => (Value::Integer(1), Value::Null),
+            HandledFilter::Quarantined => (Value::Null, Value::Integer(1)),
+            HandledFilter::All => (Value::Null, Value::Null),
+        };
+        let q = query.q.trim();
+        let pattern = format!(
+            "%{}%",
+            q.replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":handled", handled),
+                (":quarantined", quarantined),
+                (":q", q.into()),
+                (":pattern", pattern.into()),
+            ][..],
+        )?;
+
+        let mut submissions = Vec::new();
+        while statement.next()? == State::Row {
+            let created_at = statement.read::<i64, _>("created_at")?;
+            submissions.push(Submission {
+                id: statement.read("id")?,
+                created_at: OffsetDateTime::from_unix_timestamp(created_at)
+                    .ok()
+                    .and_then(|t| t.format(&Rfc3339).ok())
+                    .unwrap_or_else(|| created_at.to_string()),
+                firstname: statement.read("firstname")?,
+                lastname: statement.read("lastname")?,
+                email: statement.read("email")?,
+                subject: statement.read("subject")?,
+                message: statement.read("message")?,
+                client_ip_hash: statement.read("client_ip_hash")?,
+                delivery_status: statement.read("delivery_status")?,
+                handled: statement.read::<i64, _>("handled")? != 0,
+                spam_score: statement.read("spam_score")?,
+                spam_reasons: statement.read("spam_reasons")?,
+                quarantined: statement.read::<i64, _>("quarantined")? != 0,
+                pgp_key: statement.read("pgp_key")?,
+            });
+        }
+        Ok(submissions)
+    }
+
+    /// Keyed hash of a client IP, so repeat senders can be recognized without storing the IP.
+    fn hash_ip(&self, ip: &str) -> String {
+        blake3::keyed_hash(&self.ip_key, ip.as_bytes()).to_hex()[..IP_HASH_LEN].to_string()
+    }
+}
+
+/// Admin page listing contact form submissions.
+#[get("/admin/inbox")]
+async fn inbox_page(
+    _admin: Admin,
+    inbox: web::Data<Inbox>,
+    web::Query(query): web::Query<InboxQuery>,
+) -> Result<HttpResponse> {
+    let submissions = inbox.search(&query).map_err(|e| {
+        error!("Failed to search inbox: {e}");
+        actix_web::error::ErrorInternalServerError("Failed to search inbox")
+    })?;
+
+    let q = escape_html(&query.q);
+    let selected = |filter| {
+        if query.status == filter {
+            " selected"
+        } else {
+            ""
+        }
+    };
+    let status = match query.status {
+        HandledFilter::Open => "open",
+        HandledFilter::Handled => "handled",
+        HandledFilter::Quarantined => "quarantined",
+        HandledFilter::All => "all",
+    };
+    let export_link = |format| {
+        let params =
+            serde_urlencoded::to_string([("format", format), ("status", status), ("q", &query.q)])
+                .unwrap_or_default();
+        escape_html(&format!("/admin/inbox/export?{params}"))
+    };
+    let mut body = format!(
+        "<form method=\"get\">
+<input type=\"search\" name=\"q\" value=\"{q}\" placeholder=\"Search\">
+<select name=\"status\">
+<option value=\"open\"{}>Open</option>
+<option value=\"handled\"{}>Handled</option>
+<option value=\"quarantined\"{}>Quarantined</option>
+<option value=\"all\"{}>All</option>
+</select>
+<button>Filter</button>
+</form>
+<p>Export: <a href=\"{}\">CSV</a> <a href=\"{}\">JSON</a></p>
+<table>
+<tr><th>Received</th><th>From</th><th>Subject</th><th>Message</th><th>Delivery</th><th></th></tr>\n",
+        selected(HandledFilter::Open),
+        selected(HandledFilter::Handled),
+        selected(HandledFilter::Quarantined),
+        selected(HandledFilter::All),
+        export_link("csv"),
+        export_link("json"),
+    );
+    for submission in &submissions {
+        let (action, label) = if submission.handled {
+            ("false", "Reopen")
+        } else {
+            ("true", "Mark handled")
+        };
+        let delivery = if submission.quarantined {
+            format!(
+                "quarantined<br><small>score {}: {}</small>",
+                submission.spam_score,
+                escape_html(&submission.spam_reasons)
+            )
+        } else if submission.delivery_status == "held" {
+            "awaiting confirmation".to_string()
+        } else {
+            escape_html(&submission.delivery_status)
+        };
+        body += &format!(
+            "<tr><td>{}</td><td>{} {}<br><a href=\"mailto:{}\">{}</a><br><small>IP hash: {}</small>{}</td>\
+            <td>{}</td><td class=\"message\">{}</td><td>{}</td>\
+            <td><form class=\"inline\" method=\"post\" action=\"/admin/inbox/{}/handled\">\
+            <input type=\"hidden\" name=\"handled\" value=\"{action}\"><button>{label}</button></form></td></tr>\n",
+            escape_html(&submission.created_at),
+            escape_html(&submission.firstname),
+            escape_html(&submission.lastname),
+            escape_html(&submission.email),
+            escape_html(&submission.email),
+            escape_html(submission.client_ip_hash.as_deref().unwrap_or("unknown")),
+            if submission.pgp_key.is_empty() {
+                ""
+            } else {
+                "<br><small>PGP key in export</small>"
+            },
+            escape_html(&submission.subject),
+            escape_html(&submission.message),
+            delivery,
+            submission.id,
+        );
+    }
+    body += "</table>";
+    if submissions.is_empty() {
+        body += "<p>No submissions.</p>";
+    }
+
+    Ok(admin::page("Inbox", &body))
+}
+
+#[derive(Deserialize)]
+struct HandledForm {
+    handled: bool,
+}
+
+/// Mark a submission as handled or reopen it.
+#[post("/admin/inbox/{id}/handled")]
+async fn set_handled(
+    _admin: Admin,
+    inbox: web::Data<Inbox>,
+    id: web::Path<i64>,
+    form: web::Form<HandledForm>,
+) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    match inbox.set_handled(id, form.handled) {
+        Ok(true) => Ok(HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, "/admin/inbox"))
+            .finish()),
+        Ok(false) => Ok(HttpResponse::NotFound().body("No such submission")),
+        Err(e) => {
+            error!("Failed to update submission {id}: {e}");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Failed to update submission",
+            ))
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: ExportFormat,
+}
+
+/// Download submissions matching a search as CSV or JSON.
+#[get("/admin/inbox/export")]
+async fn export(
+    _admin: Admin,
+    inbox: web::Data<Inbox>,
+    web::Query(export): web::Query<ExportQuery>,
+    web::Query(query): web::Query<InboxQuery>,
+) -> Result<HttpResponse> {
+    let submissions = inbox.search(&query).map_err(|e| {
+        error!("Failed to search inbox: {e}");
+        actix_web::error::ErrorInternalServerError("Failed to search inbox")
+    })?;
+
+    let (body, content_type, filename) = match export.format {
+        ExportFormat::Json => (
+            serde_json::to_string_pretty(&submissions)?,
+            "application/json",
+            "inbox.json",
+        ),
+        ExportFormat::Csv => {
+            let mut csv = csv_row(&[
+                "id",
+                "created_at",
+                "firstname",
+                "lastname",
+                "email",
+                "subject",
+                "message",
+                "client_ip_hash",
+                "delivery_status",
+                "handled",
+                "spam_score",
+                "spam_reasons",
+                "quarantined",
+                "pgp_key",
+            ]);
+            for s in &submissions {
+                csv += &csv_row(&[
+                    &s.id.to_string(),
+                    &s.created_at,
+                    &s.firstname,
+                    &s.lastname,
+                    &s.email,
+                    &s.subject,
+                    &s.message,
+                    s.client_ip_hash.as_deref().unwrap_or_default(),
+                    &s.delivery_status,
+                    &s.handled.to_string(),
+                    &s.spam_score.to_string(),
+                    &s.spam_reasons,
+                    &s.quarantined.to_string(),
+                    &s.pgp_key,
+                ]);
+            }
+            (csv, "text/csv; charset=utf-8", "inbox.csv")
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .insert_header(ContentDisposition {
+            disposition: header::DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename.to_string())],
+        })
+        .content_type(content_type)
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn submission<'a>(email: &'a str) -> NewSubmission<'a> {
+        NewSubmission {
+            firstname: "Alice",
+            lastname: "Example",
+            email,
+            subject: "Hello",
+            message: "Hi!",
+            client_ip: None,
+            spam_score: 0,
+            spam_reasons: "",
+            quarantined: false,
+            pgp_key: "",
+        }
+    }
+
+    #[test]
+    fn verification_link_works_once() {
+        let inbox = Inbox::open(db::open_path(":memory:").unwrap()).unwrap();
+        let id = inbox.record(&submission("alice@example.com")).unwrap();
+        let token = inbox.verification_token(id);
+
+        let forged = format!("{}{}", id + 1, &token[id.to_string().len()..]);
+        assert!(inbox.verify(&forged).unwrap().is_none());
+        assert!(inbox.verify("garbage").unwrap().is_none());
+
+        let verified = inbox.verify(&token).unwrap().unwrap();
+        assert_eq!((verified.id, verified.subject.as_str()), (id, "Hello"));
+        assert!(inbox.verify(&token).unwrap().is_none());
+    }
+
+    #[test]
+    fn counts_verifications_per_address() {
+        let inbox = Inbox::open(db::open_path(":memory:").unwrap()).unwrap();
+        for email in ["alice@example.com", " Alice@Example.com", "bob@example.com"] {
+            let id = inbox.record(&submission(email)).unwrap();
+            assert!(inbox.claim_verification(id, 2, 60).unwrap());
+        }
+        inbox.record(&submission("alice@example.com")).unwrap();
+        assert_eq!(
+            inbox.verifications_sent("ALICE@example.com", 60).unwrap(),
+            2
+        );
+
+        // The limit is enforced when the link is recorded, not just when it's counted.
+        let id = inbox.record(&submission("alice@example.com")).unwrap();
+        assert!(!inbox.claim_verification(id, 2, 60).unwrap());
+        assert!(inbox.claim_verification(id, 3, 60).unwrap());
+    }
+
+    #[test]
+    fn sweeps_mail_of_expired_submissions() {
+        let db = db::open_path(":memory:").unwrap();
+        let inbox = Inbox::open(db.clone()).unwrap();
+        let mail_queue = MailQueue::open(db.clone(), &Default::default(), None).unwrap();
+        let message = || {
+            lettre::Message::builder()
+                .from("sender@example.com".parse().unwrap())
+                .to("owner@example.com".parse().unwrap())
+                .body("Hi!".to_string())
+                .unwrap()
+        };
+        let mut held = Vec::new();
+        for _ in 0..3 {
+            let id = inbox.record(&submission("alice@example.com")).unwrap();
+            let mail_id = mail_queue.hold(&message()).unwrap();
+            inbox.set_mail_id(id, mail_id).unwrap();
+            held.push((id, mail_id));
+        }
+        // The first two are past their confirmation window, and the second was confirmed in time.
+        db.execute(format!(
+            "UPDATE contact_submissions SET created_at = created_at - {VERIFICATION_TTL_SECS} - 1
+            WHERE id IN ({}, {})",
+            held[0].0, held[1].0
+        ))
+        .unwrap();
+        db.execute(format!(
+            "UPDATE contact_submissions SET verified_at = created_at WHERE id = {}",
+            held[1].0
+        ))
+        .unwrap();
+
+        assert_eq!(inbox.sweep_unverified(&mail_queue).unwrap(), 1);
+        assert_eq!(inbox.sweep_unverified(&mail_queue).unwrap(), 0);
+        assert!(!mail_queue.release(held[0].1).unwrap());
+        assert!(mail_queue.release(held[1].1).unwrap());
+        assert!(mail_queue.release(held[2].1).unwrap());
+    }
+}
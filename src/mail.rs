@@ -0,0 +1,269 @@
+use std::sync::Arc;
+
+use clap::Subcommand;
+use futures::{future::BoxFuture, FutureExt};
+use lettre::{
+    address::Envelope, Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Deserialize;
+use sqlite::{Statement, Value};
+
+use crate::db::Db;
+use crate::dkim::{DkimSettings, DkimSigner};
+use crate::outbox::{self, Courier, Outbox, OutboxCommand, Outgoing, RetryPolicy};
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct MailSettings {
+    /// Attempts after which a message is moved to the dead-letter state.
+    pub max_attempts: u32,
+    /// Delay before the first retry. Doubles with each failed attempt.
+    pub initial_backoff_secs: u64,
+    /// Upper bound on the delay between retries.
+    pub max_backoff_secs: u64,
+    /// Sign every outgoing message with this DKIM key, if set.
+    pub dkim: Option<DkimSettings>,
+}
+
+impl Default for MailSettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_backoff_secs: 30,
+            max_backoff_secs: 6 * 60 * 60,
+            dkim: None,
+        }
+    }
+}
+
+impl MailSettings {
+    fn retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            initial_backoff_secs: self.initial_backoff_secs,
+            max_backoff_secs: self.max_backoff_secs,
+        }
+    }
+}
+
+/// A formatted message in the outbound queue.
+pub struct Email {
+    pub recipients: String,
+    pub subject: String,
+    sender: Option<String>,
+    message: Vec<u8>,
+}
+
+impl Email {
+    fn envelope(&self) -> Result<Envelope, String> {
+        let from = match &self.sender {
+            Some(sender) => Some(sender.parse::<Address>().map_err(|e| e.to_string())?),
+            None => None,
+        };
+        let to = self
+            .recipients
+            .split(", ")
+            .map(|to| to.parse::<Address>().map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Envelope::new(from, to).map_err(|e| e.to_string())
+    }
+}
+
+impl Outgoing for Email {
+    const NOUN: &'static str = "email";
+    const TABLE: &'static str = "outbound_mail";
+    const COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("sender", "TEXT"),
+        ("recipients", "TEXT NOT NULL"),
+        ("subject", "TEXT NOT NULL"),
+        ("message", "BLOB NOT NULL"),
+    ];
+
+    fn write(&self) -> Vec<Value> {
+        vec![
+            self.sender.clone().map_or(Value::Null, Into::into),
+            self.recipients.clone().into(),
+            self.subject.clone().into(),
+            self.message.clone().into(),
+        ]
+    }
+
+    fn read(statement: &Statement) -> Result<Self, sqlite::Error> {
+        Ok(Email {
+            sender: statement.read("sender")?,
+            recipients: statement.read("recipients")?,
+            subject: statement.read("subject")?,
+            message: statement.read("message")?,
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("to {}", self.recipients)
+    }
+
+    fn summary(&self) -> String {
+        format!("to: {}\tsubject: {}", self.recipients, self.subject)
+    }
+}
+
+impl Courier<Email> for AsyncSmtpTransport<Tokio1Executor> {
+    fn deliver<'a>(&'a self, _id: i64, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let envelope = email
+                .envelope()
+                .map_err(|e| format!("invalid envelope: {e}"))?;
+            self.send_raw(&envelope, &email.message)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        .boxed()
+    }
+}
+
+/// Outbound mail queue persisted in SQLite. Messages are DKIM signed as they are queued, if a key
+/// is configured, and delivered over SMTP by [`MailQueue::start_sender`].
+#[derive(Clone)]
+pub struct MailQueue {
+    outbox: Outbox<Email>,
+    dkim: Option<Arc<DkimSigner>>,
+}
+
+impl MailQueue {
+    pub fn open(
+        db: Db,
+        settings: &MailSettings,
+        dkim: Option<DkimSigner>,
+    ) -> Result<MailQueue, sqlite::Error> {
+        Ok(MailQueue {
+            outbox: Outbox::open(db, settings.retry())?,
+            dkim: dkim.map(Arc::new),
+        })
+    }
+
+    /// Persist a message for delivery by the background sender.
+    pub fn enqueue(&self, message: &Message) -> Result<i64, sqlite::Error> {
+        self.outbox.enqueue(&self.email(message))
+    }
+
+    /// Persist a message for delivery, unless a message with the same `key` was queued before.
+    /// Returns `false` if it was, so a caller that failed part way through can safely try again.
+    pub fn enqueue_once(&self, key: &str, message: &Message) -> Result<bool, sqlite::Error> {
+        self.outbox.enqueue_once(key, &self.email(message))
+    }
+
+    /// Persist a message without sending it until it is released.
+    pub fn hold(&self, message: &Message) -> Result<i64, sqlite::Error> {
+        self.outbox.hold(&self.email(message))
+    }
+
+    /// Queue a held message for delivery. Returns `false` if no such message is being held.
+    pub fn release(&self, id: i64) -> Result<bool, sqlite::Error> {
+        self.outbox.release(id)
+    }
+
+    /// Delete a held message that will never be released. Returns `false` if no such message is
+    /// being held.
+    pub fn discard(&self, id: i64) -> Result<bool, sqlite::Error> {
+        self.outbox.discard(id)
+    }
+
+    /// Spawn the background task that delivers queued messages.
+    pub fn start_sender(&self, transport: AsyncSmtpTransport<Tokio1Executor>) {
+        self.outbox.start_sender(transport);
+    }
+
+    fn email(&self, message: &Message) -> Email {
+        let envelope = message.envelope();
+        let recipients = envelope
+            .to()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let subject = message
+            .headers()
+            .get_raw("Subject")
+            .unwrap_or_default()
+            .to_string();
+        let formatted = match &self.dkim {
+            Some(dkim) => {
+                let mut signed = message.clone();
+                dkim.sign(&mut signed);
+                signed.formatted()
+            }
+            None => message.formatted(),
+        };
+        Email {
+            sender: envelope.from().map(ToString::to_string),
+            recipients,
+            subject,
+            message: formatted,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MailCommand {
+    #[command(flatten)]
+    Queue(OutboxCommand),
+    /// Print the DNS TXT record to publish for the configured DKIM key.
+    DkimRecord,
+}
+
+pub fn run_command(command: MailCommand, queue: &MailQueue) -> Result<(), sqlite::Error> {
+    match command {
+        MailCommand::Queue(command) => outbox::run_command(command, &queue.outbox)?,
+        MailCommand::DkimRecord => match &queue.dkim {
+            Some(dkim) => println!("{}\tTXT\t{}", dkim.record_name(), dkim.record_value()),
+            None => println!("DKIM signing is not configured. Set [mail.dkim] in config.toml."),
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::outbox::DeliveryStatus;
+
+    fn message() -> Message {
+        Message::builder()
+            .from("Sender <sender@example.com>".parse().unwrap())
+            .to("Recipient <recipient@example.com>".parse().unwrap())
+            .subject("Receipt")
+            .body("Thanks!".to_string())
+            .unwrap()
+    }
+
+    #[test]
+    fn keyed_messages_are_queued_once() {
+        let queue = MailQueue::open(
+            db::open_path(":memory:").unwrap(),
+            &MailSettings::default(),
+            None,
+        )
+        .unwrap();
+        assert!(queue
+            .enqueue_once("invoice 1 confirmed admin", &message())
+            .unwrap());
+        assert!(!queue
+            .enqueue_once("invoice 1 confirmed admin", &message())
+            .unwrap());
+        assert!(queue
+            .enqueue_once("invoice 1 confirmed user", &message())
+            .unwrap());
+        // Messages without a key never collide.
+        queue.enqueue(&message()).unwrap();
+        queue.enqueue(&message()).unwrap();
+        assert_eq!(
+            queue
+                .outbox
+                .list(Some(DeliveryStatus::Pending))
+                .unwrap()
+                .len(),
+            4
+        );
+    }
+}
@@ -13,27 +13,57 @@ use actix_web::{
     middleware, web, App, HttpRequest, HttpResponse, HttpServer, Result,
 };
 use cached::proc_macro::cached;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use config::Config;
-use lettre::{transport::smtp::authentication::Credentials, SmtpTransport};
+use lettre::{transport::smtp::authentication::Credentials, AsyncSmtpTransport, Tokio1Executor};
 use lru::LruCache;
 use mime_guess::from_path;
 use rand::{rng, Rng};
 use rust_embed::RustEmbed;
 use serde::Deserialize;
-use std::{
-    convert::TryInto,
-    env, io,
-    num::NonZeroUsize,
-    sync::{Arc, Mutex},
-};
+use std::{convert::TryInto, env, io, num::NonZeroUsize, sync::Mutex};
 use time::Duration;
 
+mod admin;
+mod attachments;
 mod captcha;
 mod contact;
+mod db;
+mod dkim;
+mod email;
+mod email_templates;
+mod inbox;
+mod mail;
+mod metrics;
+mod notify;
+mod outbox;
+mod pgp;
 mod projects;
+mod rate_limit;
+mod spam;
+#[cfg(test)]
+mod test_server;
+use crate::admin::{AdminCredentials, AdminSettings};
 use crate::captcha::*;
-use crate::contact::{contact_info, contact_submitted};
+use crate::contact::{
+    contact_api, contact_info, contact_methods, contact_submitted, contact_verify, ContactSettings,
+};
+use crate::dkim::DkimSigner;
+use crate::email_templates::{EmailTemplates, TemplateCommand};
+use crate::inbox::Inbox;
+use crate::mail::{MailCommand, MailQueue, MailSettings};
+use crate::metrics::Metrics;
+use crate::notify::{Notifier, NotifySettings};
+use crate::outbox::OutboxCommand;
+use crate::pgp::{Pgp, PgpSettings};
+use crate::projects::acceptxmr::{
+    archive::{self, ArchiveCommand, InvoiceArchive},
+    extensions::InvoiceExtensions,
+    ledger::NotificationLedger,
+    AcceptXmrSettings, Notices,
+};
+use crate::rate_limit::{RateLimitSettings, RateLimiter};
+use crate::spam::{SpamFilter, SpamSettings};
 
 const SESSION_KEY_LEN: usize = 64;
 // Safe because we know it's non-zero. Can remove after
@@ -125,7 +155,7 @@ async fn robots_txt() -> Result<HttpResponse> {
 }
 
 pub struct SharedAppData {
-    captcha_cache: LruCache<[u8; CAPTCHA_ID_LEN], [char; CAPTCHA_LEN]>,
+    captcha_cache: LruCache<[u8; CAPTCHA_ID_LEN], String>,
 }
 
 #[derive(Parser, Debug)]
@@ -134,11 +164,64 @@ struct Args {
     /// Path to config.toml file. Defaults to current directory.
     #[arg(short, long, default_value_t = String::from("./config.toml"))]
     config_file: String,
+
+    /// Run a maintenance command instead of starting the server.
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Deserialize)]
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspect and retry outbound email.
+    Mail {
+        #[command(subcommand)]
+        command: MailCommand,
+    },
+    /// List and preview email templates.
+    Template {
+        #[command(subcommand)]
+        command: TemplateCommand,
+    },
+    /// Export archived AcceptXMR invoices.
+    Invoices {
+        #[command(subcommand)]
+        command: ArchiveCommand,
+    },
+    /// Inspect and retry notification webhook deliveries.
+    Notify {
+        #[command(subcommand)]
+        command: OutboxCommand,
+    },
+}
+
+#[derive(Deserialize, Clone)]
 struct Settings {
     data_dir: String,
+    #[serde(default)]
+    captcha: CaptchaSettings,
+    #[serde(default)]
+    mail: MailSettings,
+    #[serde(default)]
+    admin: AdminSettings,
+    #[serde(default)]
+    contact: ContactSettings,
+    #[serde(default)]
+    spam: SpamSettings,
+    #[serde(default)]
+    rate_limit: RateLimitSettings,
+    #[serde(default)]
+    notify: NotifySettings,
+    #[serde(default)]
+    pgp: PgpSettings,
+    #[serde(default)]
+    acceptxmr: AcceptXmrSettings,
+}
+
+impl Settings {
+    /// Path of the SQLite database holding the website's own (non-AcceptXMR) data.
+    fn database_path(&self) -> String {
+        self.data_dir.clone() + "/busyboredom.db"
+    }
 }
 
 #[derive(Deserialize)]
@@ -146,6 +229,9 @@ struct Secrets {
     email_password: String,
     xmr_private_viewkey: String,
     daemon_password: String,
+    /// Password for the admin area. The admin area is disabled if unset.
+    #[serde(default)]
+    admin_password: Option<String>,
 }
 
 #[actix_web::main]
@@ -157,18 +243,59 @@ async fn main() -> io::Result<()> {
     env_logger::init();
 
     let args = Args::parse();
-    let secrets = Config::builder()
-        .add_source(config::Environment::default())
+    let settings = Config::builder()
+        .add_source(config::File::with_name(&args.config_file))
         .build()
         .unwrap()
-        .try_deserialize::<Secrets>()
+        .try_deserialize::<Settings>()
         .unwrap();
+    settings.captcha.validate().map_err(io::Error::other)?;
+    settings
+        .acceptxmr
+        .exchange_rate
+        .validate()
+        .map_err(io::Error::other)?;
 
-    let settings = Config::builder()
-        .add_source(config::File::with_name(&args.config_file))
+    let db = db::open(&settings).expect("Could not open database");
+    let dkim = settings
+        .mail
+        .dkim
+        .as_ref()
+        .map(|dkim| DkimSigner::load(dkim).expect("Could not load DKIM private key"));
+    let mail_queue = MailQueue::open(db.clone(), &settings.mail, dkim)
+        .expect("Could not open outbound mail queue");
+    let inbox = Inbox::open(db.clone()).expect("Could not open contact form inbox");
+    let templates =
+        EmailTemplates::load(&settings.data_dir).expect("Could not load email templates");
+    let metrics = web::Data::new(Metrics::default());
+    let notifier = Notifier::new(&settings.notify, db.clone(), metrics.clone())
+        .expect("Could not open webhook queue");
+    let invoice_archive = InvoiceArchive::open(db.clone()).expect("Could not open invoice archive");
+
+    // Run maintenance command, if any.
+    if let Some(command) = args.command {
+        match command {
+            Command::Mail { command } => {
+                mail::run_command(command, &mail_queue).map_err(io::Error::other)?
+            }
+            Command::Template { command } => {
+                email_templates::run_command(command, &templates).map_err(io::Error::other)?
+            }
+            Command::Invoices { command } => {
+                archive::run_command(command, &invoice_archive).map_err(io::Error::other)?
+            }
+            Command::Notify { command } => {
+                outbox::run_command(command, notifier.outbox()).map_err(io::Error::other)?
+            }
+        }
+        return Ok(());
+    }
+
+    let secrets = Config::builder()
+        .add_source(config::Environment::default())
         .build()
         .unwrap()
-        .try_deserialize::<Settings>()
+        .try_deserialize::<Secrets>()
         .unwrap();
 
     // Set random session key.
@@ -181,29 +308,85 @@ async fn main() -> io::Result<()> {
         captcha_cache: LruCache::new(CAPTCHA_CACHE_LEN),
     }));
 
-    // Make mailer.
-    let mailer = Arc::new(
-        SmtpTransport::relay("mail.privateemail.com")
-            .expect("Could not build mailer")
-            .credentials(Credentials::new(
-                "donotreply@busyboredom.com".to_string(),
-                secrets.email_password.clone(),
-            ))
-            .build(),
-    );
+    // Make mailer and start delivering queued email.
+    let mailer = AsyncSmtpTransport::<Tokio1Executor>::relay("mail.privateemail.com")
+        .expect("Could not build mailer")
+        .credentials(Credentials::new(
+            "donotreply@busyboredom.com".to_string(),
+            secrets.email_password.clone(),
+        ))
+        .build();
+    mail_queue.start_sender(mailer);
+    inbox.start_sweeper(mail_queue.clone());
+    notifier.start_sender();
+
+    let admin_credentials = web::Data::new(AdminCredentials {
+        username: settings.admin.username.clone(),
+        password: secrets.admin_password.clone(),
+    });
+
+    let pgp = Pgp::new(&settings.pgp, &settings.data_dir).expect("Could not set up PGP");
 
     // Start acceptxmr demo payment gateway.
-    let payment_gateway =
-        web::Data::new(projects::acceptxmr::setup(mailer.clone(), secrets, settings).await);
-    // Wrap mailer for use by actix.
-    let wrapped_mailer = web::Data::new(mailer);
+    let notices = Notices {
+        mail_queue: mail_queue.clone(),
+        templates: templates.clone(),
+        notifier: notifier.clone(),
+        pgp: pgp.clone(),
+        late_payment_grace_blocks: settings.acceptxmr.late_payment_grace_blocks,
+    };
+    let extensions =
+        InvoiceExtensions::open(db.clone()).expect("Could not open invoice extensions");
+    let payment_gateway = web::Data::new(
+        projects::acceptxmr::setup(
+            notices.clone(),
+            NotificationLedger::open(db.clone()).expect("Could not open notification ledger"),
+            extensions.clone(),
+            invoice_archive.clone(),
+            secrets,
+            settings.clone(),
+        )
+        .await,
+    );
+    let notices = web::Data::new(notices);
+    let extensions = web::Data::new(extensions);
+    let invoice_archive = web::Data::new(invoice_archive);
+    let exchange_rates = web::Data::from(projects::acceptxmr::exchange_rate::provider(
+        &settings.acceptxmr.exchange_rate,
+        &settings.acceptxmr.catalog.currency,
+    ));
+    // Wrap settings for use by actix.
+    let settings = web::Data::new(settings);
+    // Wrap mail queue, templates, inbox and notifier for use by actix.
+    let mail_queue = web::Data::new(mail_queue);
+    let inbox = web::Data::new(inbox);
+    let templates = web::Data::new(templates);
+    let spam_filter = web::Data::new(
+        SpamFilter::new(&db, settings.spam.clone()).expect("Could not set up spam filter"),
+    );
+    let notifier = web::Data::new(notifier);
+    let pgp = web::Data::new(pgp);
+    let rate_limiter = web::Data::new(RateLimiter::new(settings.rate_limit.clone()));
 
     HttpServer::new(move || {
         App::new()
             // Build application data.
-            .app_data(wrapped_mailer.clone())
+            .app_data(mail_queue.clone())
+            .app_data(inbox.clone())
+            .app_data(templates.clone())
+            .app_data(spam_filter.clone())
+            .app_data(metrics.clone())
+            .app_data(notifier.clone())
+            .app_data(pgp.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(admin_credentials.clone())
             .app_data(shared_data.clone())
+            .app_data(settings.clone())
             .app_data(payment_gateway.clone())
+            .app_data(exchange_rates.clone())
+            .app_data(notices.clone())
+            .app_data(extensions.clone())
+            .app_data(invoice_archive.clone())
             // Compression middleware
             .wrap(middleware::Compress::default())
             // Cookie session middleware
@@ -218,6 +401,8 @@ async fn main() -> io::Result<()> {
                     .cookie_same_site(cookie::SameSite::Strict)
                     .build(),
             )
+            // Per-client rate limits
+            .wrap(middleware::from_fn(rate_limit::rate_limit))
             // Enable logger - always register actix-web Logger middleware last
             .wrap(middleware::Logger::default())
             // Register bindings
@@ -227,19 +412,41 @@ async fn main() -> io::Result<()> {
             // Register robots.txt
             .service(robots_txt)
             // Contact info for contact page.
+            .service(contact_methods)
             .service(contact_info)
-            // Contact form submission
+            // Token proving when the contact form was loaded.
+            .service(spam::contact_token)
+            // Contact form submission, as a classic form post or JSON
             .service(contact_submitted)
+            .service(contact_api)
+            // Confirmation of a contact form sender's address
+            .service(contact_verify)
+            // Admin inbox of contact form submissions
+            .service(inbox::inbox_page)
+            .service(inbox::set_handled)
+            .service(inbox::export)
+            // Admin dashboard of AcceptXMR demo invoices
+            .service(projects::acceptxmr::admin::invoices_page)
+            .service(projects::acceptxmr::admin::cancel)
+            .service(projects::acceptxmr::admin::extend)
+            .service(projects::acceptxmr::admin::resend)
+            .service(projects::acceptxmr::admin::export)
+            // Admin metrics for Prometheus
+            .service(metrics::metrics)
             // Captcha generation
             .service(generate_captcha)
             // Captcha submission
             .service(submit_captcha)
+            // Items that can be checked out in the AcceptXMR demo.
+            .service(projects::acceptxmr::catalog_items)
             // AcceptXMR check out endpoint to submit message and prepare cookie.
             .service(projects::acceptxmr::checkout)
             // AcceptXMR gateway to get invoice updates.
             .service(projects::acceptxmr::update)
             // AcceptXMR websocket to get invoice updates.
             .service(projects::acceptxmr::websocket)
+            // AcceptXMR invoice updates as server-sent events, for when websockets are blocked.
+            .service(projects::acceptxmr::events::events)
             // Static directory
             .service(web::resource("/api/{_:.*}").route(web::get().to(dist)))
             // Default
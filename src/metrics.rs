@@ -0,0 +1,68 @@
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective},
+    web, HttpResponse,
+};
+
+use crate::admin::Admin;
+
+/// In-memory counters, served in the Prometheus text format. Counters reset when the server
+/// restarts.
+#[derive(Default)]
+pub struct Metrics {
+    /// Counter values by metric name, then by rendered label set.
+    counters: Mutex<BTreeMap<&'static str, BTreeMap<String, u64>>>,
+}
+
+impl Metrics {
+    /// Add one to the counter with the given name and labels.
+    pub fn increment(&self, name: &'static str, labels: &[(&str, &str)]) {
+        let labels = labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{}\"", escape_label(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        *self
+            .counters
+            .lock()
+            .expect("Unable to get lock on metrics")
+            .entry(name)
+            .or_default()
+            .entry(labels)
+            .or_default() += 1;
+    }
+
+    fn render(&self) -> String {
+        let counters = self.counters.lock().expect("Unable to get lock on metrics");
+        let mut rendered = String::new();
+        for (name, values) in counters.iter() {
+            writeln!(rendered, "# TYPE {name} counter").unwrap();
+            for (labels, value) in values {
+                if labels.is_empty() {
+                    writeln!(rendered, "{name} {value}").unwrap();
+                } else {
+                    writeln!(rendered, "{name}{{{labels}}} {value}").unwrap();
+                }
+            }
+        }
+        rendered
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counters for scraping by Prometheus, behind the admin credentials.
+#[get("/admin/metrics")]
+async fn metrics(_admin: Admin, metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
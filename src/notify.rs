@@ -0,0 +1,600 @@
+use std::{env, sync::Arc, time::Duration};
+
+use actix_web::web;
+use futures::{future::BoxFuture, FutureExt};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use shared::{format_xmr, InvoiceStatus, LockedRate};
+use sqlite::{Statement, Value};
+use time::OffsetDateTime;
+
+use crate::db::Db;
+use crate::metrics::Metrics;
+use crate::outbox::{Courier, Outbox, Outgoing, RetryPolicy};
+
+/// Time allowed for a webhook endpoint to answer.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    ContactSubmitted,
+    InvoiceCreated,
+    InvoicePaymentSeen,
+    InvoicePaid,
+    InvoiceExpired,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::ContactSubmitted => "contact_submitted",
+            EventKind::InvoiceCreated => "invoice_created",
+            EventKind::InvoicePaymentSeen => "invoice_payment_seen",
+            EventKind::InvoicePaid => "invoice_paid",
+            EventKind::InvoiceExpired => "invoice_expired",
+        }
+    }
+}
+
+/// Something worth telling the site owner about. Serialized as the body of webhook requests.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    ContactSubmitted {
+        /// ID of the submission in the admin inbox, if it was stored.
+        submission_id: Option<i64>,
+        firstname: String,
+        lastname: String,
+        email: String,
+        subject: String,
+        message: String,
+    },
+    InvoiceCreated(InvoiceDetails),
+    /// The first payment towards the invoice was seen, possibly still unconfirmed.
+    InvoicePaymentSeen(InvoiceDetails),
+    /// The invoice was paid in full and the payment confirmed.
+    InvoicePaid(InvoiceDetails),
+    /// The invoice expired before it was paid in full.
+    InvoiceExpired(InvoiceDetails),
+}
+
+/// The state of an AcceptXMR demo invoice when an event about it happened.
+#[derive(Serialize, Clone, Debug)]
+pub struct InvoiceDetails {
+    pub invoice_id: String,
+    pub status: InvoiceStatus,
+    pub address: String,
+    pub amount_requested: u64,
+    /// Amount paid so far, in piconeros.
+    pub amount_piconeros: u64,
+    /// Amount paid so far in XMR, with every digit.
+    pub amount_xmr: String,
+    pub confirmations: Option<u64>,
+    pub confirmations_required: u64,
+    pub creation_height: u64,
+    /// Height the invoice expires at, including any extension.
+    pub expiration_height: u64,
+    pub current_height: u64,
+    pub email: String,
+    pub message: String,
+    /// What was bought, one line per item, e.g. "2 x Sticker at 5.00 USD".
+    pub items: String,
+    pub exchange_rate: Option<LockedRate>,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::ContactSubmitted { .. } => EventKind::ContactSubmitted,
+            Event::InvoiceCreated(_) => EventKind::InvoiceCreated,
+            Event::InvoicePaymentSeen(_) => EventKind::InvoicePaymentSeen,
+            Event::InvoicePaid(_) => EventKind::InvoicePaid,
+            Event::InvoiceExpired(_) => EventKind::InvoiceExpired,
+        }
+    }
+
+    /// A short, human readable description for chat messages.
+    fn summary(&self) -> String {
+        match self {
+            Event::ContactSubmitted {
+                firstname,
+                lastname,
+                email,
+                subject,
+                ..
+            } => format!(
+                "New contact form submission from {firstname} {lastname} <{email}>: {subject}"
+            ),
+            Event::InvoiceCreated(invoice) => format!(
+                "AcceptXMR demo invoice {} created: {} XMR for {}",
+                invoice.invoice_id,
+                format_xmr(invoice.amount_requested),
+                invoice.email
+            ),
+            Event::InvoicePaymentSeen(invoice) => format!(
+                "AcceptXMR demo invoice {} payment seen: {} XMR from {}",
+                invoice.invoice_id, invoice.amount_xmr, invoice.email
+            ),
+            Event::InvoicePaid(invoice) => format!(
+                "AcceptXMR demo invoice {} paid: {} XMR from {}",
+                invoice.invoice_id, invoice.amount_xmr, invoice.email
+            ),
+            Event::InvoiceExpired(invoice) => format!(
+                "AcceptXMR demo invoice {} expired with {} of {} XMR paid",
+                invoice.invoice_id,
+                invoice.amount_xmr,
+                format_xmr(invoice.amount_requested)
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct NotifySettings {
+    pub webhooks: Vec<WebhookSettings>,
+    pub matrix: Vec<MatrixSettings>,
+    /// Attempts after which a webhook delivery is given up on.
+    pub max_attempts: u32,
+    /// Delay before the first retry of a webhook. Doubles with each failed attempt.
+    pub initial_backoff_secs: u64,
+    /// Upper bound on the delay between retries.
+    pub max_backoff_secs: u64,
+}
+
+impl Default for NotifySettings {
+    fn default() -> Self {
+        Self {
+            webhooks: Vec::new(),
+            matrix: Vec::new(),
+            max_attempts: 10,
+            initial_backoff_secs: 10,
+            max_backoff_secs: 60 * 60,
+        }
+    }
+}
+
+impl NotifySettings {
+    fn retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            initial_backoff_secs: self.initial_backoff_secs,
+            max_backoff_secs: self.max_backoff_secs,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct WebhookSettings {
+    pub url: String,
+    /// Name of the environment variable holding the HMAC key requests are signed with.
+    pub secret_env: String,
+    pub events: Vec<EventKind>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MatrixSettings {
+    /// Base URL of the homeserver, e.g. `https://matrix.org`.
+    pub homeserver: String,
+    pub room_id: String,
+    /// Name of the environment variable holding the bot account's access token.
+    pub access_token_env: String,
+    pub events: Vec<EventKind>,
+}
+
+struct Webhook {
+    url: Url,
+    secret: String,
+    events: Vec<EventKind>,
+}
+
+struct MatrixRoom {
+    send_url: Url,
+    access_token: String,
+    events: Vec<EventKind>,
+}
+
+impl MatrixRoom {
+    async fn send(&self, client: &Client, event: &Event) -> Result<(), reqwest::Error> {
+        // Each message needs a transaction ID of its own. Messages are sent once and never retried,
+        // so a random one will do.
+        let mut url = self.send_url.clone();
+        url.path_segments_mut()
+            .expect("Matrix URL is a base URL")
+            .push(&format!("{:032x}", rand::random::<u128>()));
+        client
+            .put(url)
+            .bearer_auth(&self.access_token)
+            .json(&json!({
+                "msgtype": "m.notice",
+                "body": event.summary(),
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// An event on its way to a webhook endpoint.
+pub struct WebhookDelivery {
+    url: String,
+    event: String,
+    /// The event as JSON, exactly as it is signed and sent.
+    body: String,
+}
+
+impl Outgoing for WebhookDelivery {
+    const NOUN: &'static str = "webhook";
+    const TABLE: &'static str = "outbound_webhooks";
+    const COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("url", "TEXT NOT NULL"),
+        ("event", "TEXT NOT NULL"),
+        ("body", "TEXT NOT NULL"),
+    ];
+
+    fn write(&self) -> Vec<Value> {
+        vec![
+            self.url.clone().into(),
+            self.event.clone().into(),
+            self.body.clone().into(),
+        ]
+    }
+
+    fn read(statement: &Statement) -> Result<Self, sqlite::Error> {
+        Ok(WebhookDelivery {
+            url: statement.read("url")?,
+            event: statement.read("event")?,
+            body: statement.read("body")?,
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("to {}", self.url)
+    }
+
+    fn summary(&self) -> String {
+        format!("{}\tto: {}", self.event, self.url)
+    }
+}
+
+/// HMAC-SHA256 of `<timestamp>.<body>`, hex encoded. Webhook receivers recompute this to check a
+/// request came from us, and reject old timestamps to prevent replays.
+pub fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Delivers events to every webhook and Matrix room configured for them. Webhook deliveries are
+/// queued in an [`Outbox`] and retried until they succeed; Matrix messages are sent once, in the
+/// background.
+#[derive(Clone)]
+pub struct Notifier {
+    webhooks: Arc<Vec<Webhook>>,
+    rooms: Arc<Vec<MatrixRoom>>,
+    outbox: Outbox<WebhookDelivery>,
+    client: Client,
+    metrics: web::Data<Metrics>,
+}
+
+impl Notifier {
+    /// Set up the configured sinks. Sinks with a bad URL or a missing secret are skipped.
+    pub fn new(
+        settings: &NotifySettings,
+        db: Db,
+        metrics: web::Data<Metrics>,
+    ) -> Result<Self, sqlite::Error> {
+        let mut webhooks = Vec::new();
+        for webhook in &settings.webhooks {
+            let (Ok(url), Ok(secret)) = (webhook.url.parse(), env::var(&webhook.secret_env)) else {
+                error!(
+                    "Webhook {} disabled: invalid URL or {} not set",
+                    webhook.url, webhook.secret_env
+                );
+                continue;
+            };
+            webhooks.push(Webhook {
+                url,
+                secret,
+                events: webhook.events.clone(),
+            });
+        }
+        let mut rooms = Vec::new();
+        for matrix in &settings.matrix {
+            let (Some(send_url), Ok(access_token)) = (
+                matrix_send_url(&matrix.homeserver, &matrix.room_id),
+                env::var(&matrix.access_token_env),
+            ) else {
+                error!(
+                    "Matrix notifications to {} disabled: invalid homeserver or {} not set",
+                    matrix.room_id, matrix.access_token_env
+                );
+                continue;
+            };
+            rooms.push(MatrixRoom {
+                send_url,
+                access_token,
+                events: matrix.events.clone(),
+            });
+        }
+        info!(
+            "Notifications configured for {} sink(s)",
+            webhooks.len() + rooms.len()
+        );
+
+        Ok(Self {
+            webhooks: Arc::new(webhooks),
+            rooms: Arc::new(rooms),
+            outbox: Outbox::open(db, settings.retry())?,
+            client: Client::new(),
+            metrics,
+        })
+    }
+
+    pub fn outbox(&self) -> &Outbox<WebhookDelivery> {
+        &self.outbox
+    }
+
+    /// Spawn the background task that makes queued webhook deliveries.
+    pub fn start_sender(&self) {
+        self.outbox.start_sender(self.clone());
+    }
+
+    /// Send an event to every sink enabled for it. Returns immediately; failures are logged.
+    pub fn publish(&self, event: Event) {
+        if let Err(e) = self.queue(None, &event) {
+            error!("Failed to queue {:?} webhooks: {e}", event.kind());
+        }
+        self.announce(&event);
+    }
+
+    /// Queue webhook deliveries of an event. With a `key`, deliveries already queued under it are
+    /// skipped, so a caller that failed part way through can safely try again.
+    pub fn queue(&self, key: Option<&str>, event: &Event) -> Result<(), sqlite::Error> {
+        let body = serde_json::to_string(event).expect("Events always serialize");
+        let webhooks = self
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.events.contains(&event.kind()));
+        for webhook in webhooks {
+            let delivery = WebhookDelivery {
+                url: webhook.url.to_string(),
+                event: event.kind().as_str().to_string(),
+                body: body.clone(),
+            };
+            match key {
+                Some(key) => self
+                    .outbox
+                    .enqueue_once(&format!("{key} {}", webhook.url), &delivery)
+                    .map(drop)?,
+                None => self.outbox.enqueue(&delivery).map(drop)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Send an event to every Matrix room enabled for it, in the background. Failures are logged.
+    pub fn announce(&self, event: &Event) {
+        for index in 0..self.rooms.len() {
+            if !self.rooms[index].events.contains(&event.kind()) {
+                continue;
+            }
+            let notifier = self.clone();
+            let event = event.clone();
+            tokio::spawn(async move {
+                let outcome = match notifier.rooms[index].send(&notifier.client, &event).await {
+                    Ok(()) => "sent",
+                    Err(e) => {
+                        warn!(
+                            "Failed to send {:?} notification to matrix: {e}",
+                            event.kind()
+                        );
+                        "failed"
+                    }
+                };
+                notifier.count("matrix", outcome);
+            });
+        }
+    }
+
+    fn count(&self, sink: &str, outcome: &str) {
+        self.metrics.increment(
+            "notifications_total",
+            &[("sink", sink), ("outcome", outcome)],
+        );
+    }
+
+    /// Make one attempt at a webhook delivery. The `id` is sent along so receivers can ignore
+    /// retries of deliveries they have already handled.
+    async fn post(&self, id: i64, delivery: &WebhookDelivery) -> Result<(), String> {
+        let webhook = self
+            .webhooks
+            .iter()
+            .find(|webhook| webhook.url.as_str() == delivery.url)
+            .ok_or("endpoint is no longer configured")?;
+        let timestamp = OffsetDateTime::now_utc().unix_timestamp().to_string();
+        let response = self
+            .client
+            .post(webhook.url.clone())
+            .timeout(DELIVERY_TIMEOUT)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Delivery", id.to_string())
+            .header("X-Webhook-Event", &delivery.event)
+            .header("X-Webhook-Timestamp", &timestamp)
+            .header(
+                "X-Webhook-Signature",
+                format!(
+                    "sha256={}",
+                    sign(&webhook.secret, &timestamp, delivery.body.as_bytes())
+                ),
+            )
+            .body(delivery.body.clone())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("HTTP {}", status.as_u16()));
+        }
+        Ok(())
+    }
+}
+
+impl Courier<WebhookDelivery> for Notifier {
+    fn deliver<'a>(
+        &'a self,
+        id: i64,
+        delivery: &'a WebhookDelivery,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let result = self.post(id, delivery).await;
+            self.count("webhook", if result.is_ok() { "sent" } else { "failed" });
+            result
+        }
+        .boxed()
+    }
+}
+
+/// URL to PUT `m.room.message` events to, minus the transaction ID.
+fn matrix_send_url(homeserver: &str, room_id: &str) -> Option<Url> {
+    let mut url: Url = homeserver.parse().ok()?;
+    url.path_segments_mut().ok()?.pop_if_empty().extend([
+        "_matrix",
+        "client",
+        "v3",
+        "rooms",
+        room_id,
+        "send",
+        "m.room.message",
+    ]);
+    Some(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::HttpResponse;
+
+    use super::*;
+    use crate::db;
+    use crate::test_server::stand_in;
+
+    fn contact_event() -> Event {
+        Event::ContactSubmitted {
+            submission_id: Some(7),
+            firstname: "Alice".to_string(),
+            lastname: "Example".to_string(),
+            email: "alice@example.com".to_string(),
+            subject: "Hello".to_string(),
+            message: "Hi!".to_string(),
+        }
+    }
+
+    /// A notifier with a single webhook at `url`.
+    fn notifier(url: &str) -> Notifier {
+        Notifier {
+            webhooks: Arc::new(vec![Webhook {
+                url: url.parse().unwrap(),
+                secret: "shh".to_string(),
+                events: vec![EventKind::ContactSubmitted],
+            }]),
+            rooms: Arc::default(),
+            outbox: Outbox::open(
+                db::open_path(":memory:").unwrap(),
+                NotifySettings::default().retry(),
+            )
+            .unwrap(),
+            client: Client::new(),
+            metrics: web::Data::new(Metrics::default()),
+        }
+    }
+
+    #[actix_web::test]
+    async fn webhook_is_queued_once_and_signed() {
+        let (base, received) = stand_in(|_| HttpResponse::Ok().finish());
+        let notifier = notifier(&format!("{base}/hook"));
+        notifier.queue(Some("contact 7"), &contact_event()).unwrap();
+        notifier.queue(Some("contact 7"), &contact_event()).unwrap();
+        let queued = notifier.outbox.list(None).unwrap();
+        assert_eq!(queued.len(), 1);
+        notifier
+            .deliver(queued[0].id, &queued[0].item)
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        let request = &received[0];
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("POST", "/hook")
+        );
+        let id = queued[0].id.to_string();
+        assert_eq!(request.header("x-webhook-delivery"), Some(id.as_str()));
+        assert_eq!(request.header("x-webhook-event"), Some("contact_submitted"));
+        let timestamp = request.header("x-webhook-timestamp").unwrap();
+        assert_eq!(
+            request.header("x-webhook-signature"),
+            Some(format!("sha256={}", sign("shh", timestamp, request.body.as_bytes())).as_str())
+        );
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["event"], "contact_submitted");
+        assert_eq!(body["submission_id"], 7);
+    }
+
+    #[actix_web::test]
+    async fn failed_webhook_reports_status() {
+        let (base, _) = stand_in(|_| HttpResponse::InternalServerError().finish());
+        let notifier = notifier(&format!("{base}/hook"));
+        notifier.queue(None, &contact_event()).unwrap();
+        let queued = notifier.outbox.list(None).unwrap().remove(0);
+        assert_eq!(
+            notifier.deliver(queued.id, &queued.item).await,
+            Err("HTTP 500".to_string())
+        );
+    }
+
+    #[actix_web::test]
+    async fn matrix_message_is_sent_to_room() {
+        let (base, received) = stand_in(|_| HttpResponse::Ok().json(json!({})));
+        let room = MatrixRoom {
+            send_url: matrix_send_url(&base, "!room:example.org").unwrap(),
+            access_token: "token".to_string(),
+            events: vec![EventKind::ContactSubmitted],
+        };
+        room.send(&Client::new(), &contact_event()).await.unwrap();
+
+        let received = received.lock().unwrap();
+        let request = &received[0];
+        assert_eq!(request.method, "PUT");
+        assert!(
+            request
+                .path
+                .starts_with("/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/"),
+            "{}",
+            request.path
+        );
+        assert_eq!(request.header("authorization"), Some("Bearer token"));
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(
+            body["body"],
+            "New contact form submission from Alice Example <alice@example.com>: Hello"
+        );
+    }
+
+    #[test]
+    fn signature_matches_known_value() {
+        // printf '1700000000.{}' | openssl dgst -sha256 -hmac key
+        assert_eq!(
+            sign("key", "1700000000", b"{}"),
+            "9d713ed406bb7076d4123f0dc2c39d2df5c654ed4b0cd56b52c8b4c940bd63ae"
+        );
+    }
+}
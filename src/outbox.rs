@@ -0,0 +1,636 @@
+use std::{convert::TryFrom, marker::PhantomData, sync::Arc, time::Duration};
+
+use clap::Subcommand;
+use futures::future::BoxFuture;
+use log::{debug, error, info, warn};
+use sqlite::{State, Statement, Value};
+use time::OffsetDateTime;
+use tokio::sync::Notify;
+
+use crate::db::{self, Db};
+
+/// Upper bound on how long a sender sleeps before checking its queue again.
+const MAX_IDLE: Duration = Duration::from_secs(60);
+/// Number of due items a sender picks up at once.
+const BATCH_SIZE: i64 = 20;
+
+/// How many times, and how far apart, deliveries are attempted.
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts after which an item is moved to the dead-letter state.
+    pub max_attempts: u32,
+    /// Delay before the first retry. Doubles with each failed attempt.
+    pub initial_backoff_secs: u64,
+    /// Upper bound on the delay between retries.
+    pub max_backoff_secs: u64,
+}
+
+impl RetryPolicy {
+    /// Delay before the next attempt, given the number of attempts made so far.
+    fn backoff(&self, attempts: u32) -> u64 {
+        let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+        self.initial_backoff_secs
+            .saturating_mul(factor)
+            .min(self.max_backoff_secs)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Pending,
+    Sent,
+    /// Gave up after `max_attempts` failures. Can be retried from the CLI.
+    Dead,
+    /// Waiting for [`Outbox::release`] before it is sent.
+    Held,
+}
+
+impl DeliveryStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Dead => "dead",
+            DeliveryStatus::Held => "held",
+        }
+    }
+
+    fn parse(status: &str) -> DeliveryStatus {
+        match status {
+            "sent" => DeliveryStatus::Sent,
+            "dead" => DeliveryStatus::Dead,
+            "held" => DeliveryStatus::Held,
+            _ => DeliveryStatus::Pending,
+        }
+    }
+}
+
+/// Something an [`Outbox`] delivers, stored in a table of its own.
+pub trait Outgoing: Sized + Send + Sync + 'static {
+    /// What the items are called in logs and CLI output, e.g. "email".
+    const NOUN: &'static str;
+    /// Table the queue is kept in.
+    const TABLE: &'static str;
+    /// Names and definitions of the item's own columns.
+    const COLUMNS: &'static [(&'static str, &'static str)];
+
+    /// Values of the item's own columns, in the order of [`Outgoing::COLUMNS`].
+    fn write(&self) -> Vec<Value>;
+    /// Read the item's own columns from a row of its table.
+    fn read(statement: &Statement) -> Result<Self, sqlite::Error>;
+    /// Where the item is going, for logs, e.g. "to alice@example.com".
+    fn describe(&self) -> String;
+    /// One line about the item for `list` commands.
+    fn summary(&self) -> String {
+        self.describe()
+    }
+}
+
+/// Makes delivery attempts for an [`Outbox`].
+pub trait Courier<T>: Send + Sync + 'static {
+    /// Make one attempt at delivering `item`, with `id` identifying it across attempts.
+    fn deliver<'a>(&'a self, id: i64, item: &'a T) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// An item in a queue, with the state of its delivery.
+pub struct Queued<T> {
+    pub id: i64,
+    pub created_at: i64,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub item: T,
+}
+
+/// Queue of outgoing items persisted in SQLite, which doubles as a log of their delivery. Callers
+/// only ever queue items; a background task started with [`Outbox::start_sender`] delivers them,
+/// retrying failures with exponential backoff.
+pub struct Outbox<T> {
+    db: Db,
+    wake: Arc<Notify>,
+    retry: RetryPolicy,
+    item: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Outbox<T> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            wake: self.wake.clone(),
+            retry: self.retry,
+            item: PhantomData,
+        }
+    }
+}
+
+impl<T: Outgoing> Outbox<T> {
+    pub fn open(db: Db, retry: RetryPolicy) -> Result<Outbox<T>, sqlite::Error> {
+        let columns: String = T::COLUMNS
+            .iter()
+            .map(|(name, definition)| format!("{name} {definition},\n"))
+            .collect();
+        db.execute(format!(
+            "CREATE TABLE IF NOT EXISTS {table} (
+                id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                created_at      INTEGER NOT NULL,
+                {columns}
+                status          TEXT NOT NULL,
+                attempts        INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                last_error      TEXT,
+                dedupe_key      TEXT
+            );
+            CREATE INDEX IF NOT EXISTS {table}_due ON {table} (status, next_attempt_at);",
+            table = T::TABLE,
+        ))?;
+        db::add_column(&db, T::TABLE, "dedupe_key", "TEXT")?;
+        db.execute(format!(
+            "CREATE UNIQUE INDEX IF NOT EXISTS {table}_dedupe ON {table} (dedupe_key);",
+            table = T::TABLE,
+        ))?;
+
+        Ok(Outbox {
+            db,
+            wake: Arc::new(Notify::new()),
+            retry,
+            item: PhantomData,
+        })
+    }
+
+    /// Persist an item for delivery by the background sender.
+    pub fn enqueue(&self, item: &T) -> Result<i64, sqlite::Error> {
+        Ok(self
+            .insert(item, DeliveryStatus::Pending, None)?
+            .expect("Items without a key are always inserted"))
+    }
+
+    /// Persist an item for delivery, unless one with the same `key` was queued before. Returns
+    /// `false` if it was, so a caller that failed part way through can safely try again.
+    pub fn enqueue_once(&self, key: &str, item: &T) -> Result<bool, sqlite::Error> {
+        let queued = self
+            .insert(item, DeliveryStatus::Pending, Some(key))?
+            .is_some();
+        if !queued {
+            debug!("{} {key} was already queued", T::NOUN);
+        }
+        Ok(queued)
+    }
+
+    /// Persist an item without delivering it until it is released.
+    pub fn hold(&self, item: &T) -> Result<i64, sqlite::Error> {
+        Ok(self
+            .insert(item, DeliveryStatus::Held, None)?
+            .expect("Items without a key are always inserted"))
+    }
+
+    /// Queue a held item for delivery. Returns `false` if no such item is being held.
+    pub fn release(&self, id: i64) -> Result<bool, sqlite::Error> {
+        let mut statement = self.db.prepare(format!(
+            "UPDATE {table}
+            SET status = :pending, next_attempt_at = :now
+            WHERE id = :id AND status = :held
+            RETURNING id",
+            table = T::TABLE,
+        ))?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":pending", DeliveryStatus::Pending.as_str().into()),
+                (":held", DeliveryStatus::Held.as_str().into()),
+                (":now", now().into()),
+                (":id", id.into()),
+            ][..],
+        )?;
+        // Read from the statement itself: the connection's change count can be moved by writes
+        // on other threads.
+        let released = statement.next()? == State::Row;
+        if released {
+            info!("Released {} {id}", T::NOUN);
+            self.wake.notify_one();
+        }
+        Ok(released)
+    }
+
+    /// Delete a held item that will never be released. Returns `false` if no such item is being
+    /// held.
+    pub fn discard(&self, id: i64) -> Result<bool, sqlite::Error> {
+        let mut statement = self.db.prepare(format!(
+            "DELETE FROM {table} WHERE id = :id AND status = :held RETURNING id",
+            table = T::TABLE,
+        ))?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":held", DeliveryStatus::Held.as_str().into()),
+                (":id", id.into()),
+            ][..],
+        )?;
+        let discarded = statement.next()? == State::Row;
+        if discarded {
+            info!("Discarded held {} {id}", T::NOUN);
+        }
+        Ok(discarded)
+    }
+
+    /// Move a dead-lettered (or pending) item back to the front of the queue. Returns `false` if
+    /// no such item is waiting to be delivered. Held items are left alone.
+    pub fn retry(&self, id: i64) -> Result<bool, sqlite::Error> {
+        let mut statement = self.db.prepare(format!(
+            "UPDATE {table}
+            SET status = :pending, attempts = 0, next_attempt_at = :now
+            WHERE id = :id AND status IN (:pending, :dead)
+            RETURNING id",
+            table = T::TABLE,
+        ))?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":pending", DeliveryStatus::Pending.as_str().into()),
+                (":dead", DeliveryStatus::Dead.as_str().into()),
+                (":now", now().into()),
+                (":id", id.into()),
+            ][..],
+        )?;
+        let retried = statement.next()? == State::Row;
+        if retried {
+            self.wake.notify_one();
+        }
+        Ok(retried)
+    }
+
+    /// Insert an item, returning its ID, or `None` if one with the same `key` already exists.
+    fn insert(
+        &self,
+        item: &T,
+        status: DeliveryStatus,
+        key: Option<&str>,
+    ) -> Result<Option<i64>, sqlite::Error> {
+        let names: Vec<&str> = T::COLUMNS.iter().map(|(name, _)| *name).collect();
+        let mut statement = self.db.prepare(format!(
+            "INSERT INTO {table}
+                (created_at, status, next_attempt_at, dedupe_key, {columns})
+            VALUES (:created_at, :status, :created_at, :dedupe_key, {values})
+            ON CONFLICT (dedupe_key) DO NOTHING
+            RETURNING id",
+            table = T::TABLE,
+            columns = names.join(", "),
+            values = names
+                .iter()
+                .map(|name| format!(":{name}"))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ))?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":created_at", now().into()),
+                (":status", status.as_str().into()),
+                (":dedupe_key", key.map_or(Value::Null, Into::into)),
+            ][..],
+        )?;
+        for (name, value) in names.iter().zip(item.write()) {
+            statement.bind((format!(":{name}").as_str(), value))?;
+        }
+        if statement.next()? != State::Row {
+            return Ok(None);
+        }
+        let id = statement.read::<i64, _>("id")?;
+
+        if status == DeliveryStatus::Held {
+            info!("Holding {} {id} {}", T::NOUN, item.describe());
+        } else {
+            info!("Queued {} {id} {}", T::NOUN, item.describe());
+            self.wake.notify_one();
+        }
+        Ok(Some(id))
+    }
+
+    /// List queued items, most recent first. Lists every item if `status` is `None`.
+    pub fn list(&self, status: Option<DeliveryStatus>) -> Result<Vec<Queued<T>>, sqlite::Error> {
+        let mut statement = self.db.prepare(format!(
+            "SELECT * FROM {table}
+            WHERE :status IS NULL OR status = :status
+            ORDER BY id DESC",
+            table = T::TABLE,
+        ))?;
+        statement.bind((":status", status.map(DeliveryStatus::as_str)))?;
+        read_queued(&mut statement)
+    }
+
+    /// Spawn the background task that delivers queued items with `courier`.
+    pub fn start_sender(&self, courier: impl Courier<T>) {
+        let outbox = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let due = match outbox.due() {
+                    Ok(due) => due,
+                    Err(e) => {
+                        error!("Failed to read {} queue: {e}", T::NOUN);
+                        Vec::new()
+                    }
+                };
+                for queued in &due {
+                    outbox.deliver(&courier, queued).await;
+                }
+                if due.len() < BATCH_SIZE as usize {
+                    let idle = outbox.idle_time();
+                    let _ = tokio::time::timeout(idle, outbox.wake.notified()).await;
+                }
+            }
+        });
+    }
+
+    async fn deliver(&self, courier: &impl Courier<T>, queued: &Queued<T>) {
+        let result = courier.deliver(queued.id, &queued.item).await;
+
+        let noun = T::NOUN;
+        let id = queued.id;
+        let to = queued.item.describe();
+        let attempts = queued.attempts + 1;
+        let update = match result {
+            Ok(()) => {
+                info!("Sent {noun} {id} {to}");
+                self.set_status(id, DeliveryStatus::Sent, attempts, now(), None)
+            }
+            Err(e) if attempts >= self.retry.max_attempts => {
+                error!("Giving up on {noun} {id} {to} after {attempts} attempts: {e}");
+                self.set_status(id, DeliveryStatus::Dead, attempts, now(), Some(&e))
+            }
+            Err(e) => {
+                let backoff = self.retry.backoff(attempts);
+                warn!(
+                    "Could not send {noun} {id} {to} (attempt {attempts}), retrying in {backoff}s: {e}"
+                );
+                let next_attempt_at = now().saturating_add(backoff as i64);
+                self.set_status(
+                    id,
+                    DeliveryStatus::Pending,
+                    attempts,
+                    next_attempt_at,
+                    Some(&e),
+                )
+            }
+        };
+        if let Err(e) = update {
+            error!("Failed to record delivery status of {noun} {id}: {e}");
+        }
+    }
+
+    fn due(&self) -> Result<Vec<Queued<T>>, sqlite::Error> {
+        let mut statement = self.db.prepare(format!(
+            "SELECT * FROM {table}
+            WHERE status = :pending AND next_attempt_at <= :now
+            ORDER BY next_attempt_at, id
+            LIMIT :limit",
+            table = T::TABLE,
+        ))?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":pending", DeliveryStatus::Pending.as_str().into()),
+                (":now", now().into()),
+                (":limit", BATCH_SIZE.into()),
+            ][..],
+        )?;
+        read_queued(&mut statement)
+    }
+
+    /// How long to sleep before the next pending item becomes due.
+    fn idle_time(&self) -> Duration {
+        let next = self
+            .db
+            .prepare(format!(
+                "SELECT MIN(next_attempt_at) AS next FROM {} WHERE status = :pending",
+                T::TABLE
+            ))
+            .and_then(|mut statement| {
+                statement.bind((":pending", DeliveryStatus::Pending.as_str()))?;
+                statement.next()?;
+                statement.read::<Option<i64>, _>("next")
+            });
+        match next {
+            Ok(Some(next)) => {
+                let secs = u64::try_from(next - now()).unwrap_or_default();
+                Duration::from_secs(secs).min(MAX_IDLE)
+            }
+            Ok(None) => MAX_IDLE,
+            Err(e) => {
+                debug!("Failed to find next due {}: {e}", T::NOUN);
+                MAX_IDLE
+            }
+        }
+    }
+
+    fn set_status(
+        &self,
+        id: i64,
+        status: DeliveryStatus,
+        attempts: u32,
+        next_attempt_at: i64,
+        error: Option<&str>,
+    ) -> Result<(), sqlite::Error> {
+        let mut statement = self.db.prepare(format!(
+            "UPDATE {table}
+            SET status = :status, attempts = :attempts, next_attempt_at = :next_attempt_at,
+                last_error = :last_error
+            WHERE id = :id",
+            table = T::TABLE,
+        ))?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":status", status.as_str().into()),
+                (":attempts", i64::from(attempts).into()),
+                (":next_attempt_at", next_attempt_at.into()),
+                (":last_error", error.map_or(Value::Null, Into::into)),
+                (":id", id.into()),
+            ][..],
+        )?;
+        while statement.next()? == State::Row {}
+        Ok(())
+    }
+}
+
+fn read_queued<T: Outgoing>(statement: &mut Statement) -> Result<Vec<Queued<T>>, sqlite::Error> {
+    let mut queued = Vec::new();
+    while statement.next()? == State::Row {
+        queued.push(Queued {
+            id: statement.read("id")?,
+            created_at: statement.read("created_at")?,
+            status: DeliveryStatus::parse(&statement.read::<String, _>("status")?),
+            attempts: u32::try_from(statement.read::<i64, _>("attempts")?).unwrap_or_default(),
+            next_attempt_at: statement.read("next_attempt_at")?,
+            last_error: statement.read("last_error")?,
+            item: T::read(statement)?,
+        });
+    }
+    Ok(queued)
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .map_or_else(|_| timestamp.to_string(), |t| t.to_string())
+}
+
+#[derive(Subcommand, Debug)]
+pub enum OutboxCommand {
+    /// List the queue. Only failed (dead-lettered) items are shown unless `--all` is set.
+    List {
+        #[arg(long)]
+        all: bool,
+    },
+    /// Put failed items back in the queue for the running server to send.
+    Retry {
+        /// IDs of the items to retry.
+        ids: Vec<i64>,
+        /// Retry every failed item.
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+}
+
+pub fn run_command<T: Outgoing>(
+    command: OutboxCommand,
+    outbox: &Outbox<T>,
+) -> Result<(), sqlite::Error> {
+    match command {
+        OutboxCommand::List { all } => {
+            let status = if all {
+                None
+            } else {
+                Some(DeliveryStatus::Dead)
+            };
+            for queued in outbox.list(status)? {
+                println!(
+                    "{}\t{:?}\t{} attempt(s)\t{}\t{}",
+                    queued.id,
+                    queued.status,
+                    queued.attempts,
+                    format_timestamp(queued.created_at),
+                    queued.item.summary()
+                );
+                if queued.status == DeliveryStatus::Pending {
+                    println!(
+                        "\tnext attempt at: {}",
+                        format_timestamp(queued.next_attempt_at)
+                    );
+                }
+                if let Some(error) = queued.last_error {
+                    println!("\tlast error: {error}");
+                }
+            }
+        }
+        OutboxCommand::Retry { ids, all } => {
+            let ids = if all {
+                outbox
+                    .list(Some(DeliveryStatus::Dead))?
+                    .into_iter()
+                    .map(|queued| queued.id)
+                    .collect()
+            } else {
+                ids
+            };
+            for id in ids {
+                if outbox.retry(id)? {
+                    println!("Queued {} {id} for retry", T::NOUN);
+                } else {
+                    println!("No {} {id} waiting to be sent", T::NOUN);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    struct Note(String);
+
+    impl Outgoing for Note {
+        const NOUN: &'static str = "note";
+        const TABLE: &'static str = "notes";
+        const COLUMNS: &'static [(&'static str, &'static str)] = &[("text", "TEXT NOT NULL")];
+
+        fn write(&self) -> Vec<Value> {
+            vec![self.0.clone().into()]
+        }
+
+        fn read(statement: &Statement) -> Result<Self, sqlite::Error> {
+            Ok(Note(statement.read("text")?))
+        }
+
+        fn describe(&self) -> String {
+            format!("saying {}", self.0)
+        }
+    }
+
+    /// Never gets a note through.
+    struct Down;
+
+    impl Courier<Note> for Down {
+        fn deliver<'a>(&'a self, _id: i64, _note: &'a Note) -> BoxFuture<'a, Result<(), String>> {
+            async { Err("down".to_string()) }.boxed()
+        }
+    }
+
+    fn outbox(max_attempts: u32) -> Outbox<Note> {
+        let retry = RetryPolicy {
+            max_attempts,
+            initial_backoff_secs: 10,
+            max_backoff_secs: 60,
+        };
+        Outbox::open(db::open_path(":memory:").unwrap(), retry).unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_limit() {
+        let retry = outbox(1).retry;
+        let backoffs: Vec<_> = (1..=5).map(|attempts| retry.backoff(attempts)).collect();
+        assert_eq!(backoffs, [10, 20, 40, 60, 60]);
+    }
+
+    #[actix_web::test]
+    async fn failures_back_off_then_die() {
+        let outbox = outbox(2);
+        let id = outbox.enqueue(&Note("hi".to_string())).unwrap();
+
+        let due = outbox.due().unwrap();
+        assert_eq!(due.len(), 1);
+        outbox.deliver(&Down, &due[0]).await;
+        let queued = outbox.list(None).unwrap().remove(0);
+        assert_eq!(queued.status, DeliveryStatus::Pending);
+        assert_eq!(queued.attempts, 1);
+        assert_eq!(queued.last_error.as_deref(), Some("down"));
+        assert!(queued.next_attempt_at > now());
+        assert!(outbox.due().unwrap().is_empty());
+
+        outbox.deliver(&Down, &queued).await;
+        let queued = outbox.list(None).unwrap().remove(0);
+        assert_eq!(queued.status, DeliveryStatus::Dead);
+
+        assert!(outbox.retry(id).unwrap());
+        let due = outbox.due().unwrap();
+        assert_eq!((due.len(), due[0].attempts), (1, 0));
+    }
+
+    #[test]
+    fn held_items_wait_for_release() {
+        let outbox = outbox(2);
+        let id = outbox.hold(&Note("later".to_string())).unwrap();
+        assert!(outbox.due().unwrap().is_empty());
+        assert!(!outbox.retry(id).unwrap());
+
+        assert!(outbox.release(id).unwrap());
+        assert!(!outbox.release(id).unwrap());
+        assert_eq!(outbox.due().unwrap()[0].item.0, "later");
+
+        // Only held items can be discarded.
+        assert!(!outbox.discard(id).unwrap());
+        let id = outbox.hold(&Note("never".to_string())).unwrap();
+        assert!(outbox.discard(id).unwrap());
+        assert!(!outbox.release(id).unwrap());
+    }
+}
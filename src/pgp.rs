@@ -0,0 +1,242 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+};
+
+use actix_web::web;
+use lettre::{
+    message::{
+        header::{ContentDisposition, ContentType},
+        MessageBuilder, MultiPart, SinglePart,
+    },
+    Message,
+};
+use log::info;
+use serde::Deserialize;
+
+use crate::email_templates::RenderedEmail;
+
+/// Subject of encrypted messages. The real subject is only in the encrypted body, since headers
+/// are sent in the clear.
+const ENCRYPTED_SUBJECT: &str = "Encrypted notification";
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PgpSettings {
+    /// ASCII armored public key that notifications to the site owner are encrypted to. They are
+    /// sent unencrypted if this is not set.
+    pub public_key_path: Option<String>,
+    /// The GnuPG binary used for encryption and for checking keys sent through the contact form.
+    pub gpg: String,
+}
+
+impl Default for PgpSettings {
+    fn default() -> Self {
+        Self {
+            public_key_path: None,
+            gpg: "gpg".to_string(),
+        }
+    }
+}
+
+/// OpenPGP operations, done by GnuPG in a home directory of its own. Keys are never imported:
+/// they are passed to each command as files or on stdin.
+#[derive(Clone)]
+pub struct Pgp {
+    gpg: String,
+    homedir: PathBuf,
+    owner_key: Option<PathBuf>,
+}
+
+impl Pgp {
+    pub fn new(settings: &PgpSettings, data_dir: &str) -> io::Result<Self> {
+        let homedir = PathBuf::from(data_dir).join("gnupg");
+        fs::create_dir_all(&homedir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&homedir, fs::Permissions::from_mode(0o700))?;
+        }
+
+        let pgp = Self {
+            gpg: settings.gpg.clone(),
+            homedir,
+            owner_key: settings.public_key_path.as_ref().map(PathBuf::from),
+        };
+        if let Some(path) = &pgp.owner_key {
+            let fingerprint = pgp.inspect_blocking(&fs::read_to_string(path)?)?;
+            info!("Encrypting notifications to PGP key {fingerprint}");
+        }
+        Ok(pgp)
+    }
+
+    /// Check that `armored` is a single public key that can encrypt, returning its fingerprint.
+    /// GnuPG runs on the blocking thread pool, so this can be awaited from a request handler.
+    pub async fn inspect(&self, armored: String) -> io::Result<String> {
+        let pgp = self.clone();
+        blocking(move || pgp.inspect_blocking(&armored)).await
+    }
+
+    /// Build a message to the site owner, encrypted as PGP/MIME if they have a key configured.
+    /// GnuPG runs on the blocking thread pool.
+    pub async fn build_for_owner(
+        &self,
+        builder: MessageBuilder,
+        email: RenderedEmail,
+        attachments: Vec<SinglePart>,
+    ) -> io::Result<Message> {
+        let pgp = self.clone();
+        blocking(move || pgp.build_for_owner_blocking(builder, email, attachments)).await
+    }
+
+    fn inspect_blocking(&self, armored: &str) -> io::Result<String> {
+        let listing = self.run(&["--with-colons", "--show-keys"], armored.as_bytes())?;
+        parse_listing(&String::from_utf8_lossy(&listing)).map_err(io::Error::other)
+    }
+
+    fn build_for_owner_blocking(
+        &self,
+        builder: MessageBuilder,
+        email: RenderedEmail,
+        attachments: Vec<SinglePart>,
+    ) -> io::Result<Message> {
+        let Some(owner_key) = &self.owner_key else {
+            return email
+                .build_with_attachments(builder, attachments)
+                .map_err(io::Error::other);
+        };
+        let body = email.body(attachments).formatted();
+        let recipient_file = owner_key.to_string_lossy();
+        let encrypted = self.run(
+            &[
+                "--trust-model",
+                "always",
+                "--armor",
+                "--encrypt",
+                "--recipient-file",
+                &recipient_file,
+            ],
+            &body,
+        )?;
+
+        builder
+            .subject(ENCRYPTED_SUBJECT)
+            .multipart(
+                MultiPart::encrypted("application/pgp-encrypted".to_string())
+                    .singlepart(
+                        SinglePart::builder()
+                            .header(ContentType::parse("application/pgp-encrypted").unwrap())
+                            .body("Version: 1\r\n".to_string()),
+                    )
+                    .singlepart(
+                        SinglePart::builder()
+                            .header(
+                                ContentType::parse(
+                                    "application/octet-stream; name=\"encrypted.asc\"",
+                                )
+                                .unwrap(),
+                            )
+                            .header(ContentDisposition::inline_with_name("encrypted.asc"))
+                            .body(String::from_utf8_lossy(&encrypted).into_owned()),
+                    ),
+            )
+            .map_err(io::Error::other)
+    }
+
+    /// Run GnuPG with `input` on stdin, returning what it writes to stdout.
+    fn run(&self, args: &[&str], input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut child = Command::new(&self.gpg)
+            .arg("--homedir")
+            .arg(&self.homedir)
+            .args(["--batch", "--no-tty", "--quiet"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Write from another thread, so a full stdout pipe can't deadlock us.
+        let mut stdin = child.stdin.take().expect("gpg stdin is piped");
+        let input = input.to_vec();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output()?;
+        let written = writer.join().expect("gpg stdin writer panicked");
+
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "gpg failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        written?;
+        Ok(output.stdout)
+    }
+}
+
+/// Run `f` on the blocking thread pool, since GnuPG is a separate process we wait on.
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(io::Error::other)?
+}
+
+/// Fingerprint of the only key in a `gpg --with-colons --show-keys` listing, if it is a public
+/// key that can encrypt.
+fn parse_listing(listing: &str) -> Result<String, &'static str> {
+    let records: Vec<Vec<&str>> = listing
+        .lines()
+        .map(|line| line.split(':').collect())
+        .collect();
+    if records.iter().any(|record| record[0] == "sec") {
+        return Err("that is a secret key, not a public key");
+    }
+    let mut keys = records
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| record[0] == "pub");
+    let (Some((index, key)), None) = (keys.next(), keys.next()) else {
+        return Err("expected exactly one public key");
+    };
+    // Field 12 holds the capabilities of the key as a whole, upper case.
+    if !key
+        .get(11)
+        .is_some_and(|capabilities| capabilities.contains('E'))
+    {
+        return Err("the key cannot be used for encryption");
+    }
+    records[index + 1..]
+        .iter()
+        .find(|record| record[0] == "fpr")
+        .and_then(|record| record.get(9))
+        .map(|fingerprint| fingerprint.to_string())
+        .ok_or("the key has no fingerprint")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LISTING: &str = "\
+pub:-:255:22:5918E653121B78D7:1792383624:::-:::cEC:::::ed25519:::0:
+fpr:::::::::84C514B2830F7297590996145918E653121B78D7:
+uid:-::::1792383624::FDDEFB96E735A80D3C34A9A2D924249A19DDE05E::Owner <owner@example.com>::::::::::0:
+sub:-:255:18:2346FACC214BECC6:1792383624::::::e:::::cv25519::
+fpr:::::::::D069DDEFEF6D627C9A6D55792346FACC214BECC6:
+";
+
+    #[test]
+    fn accepts_one_encryption_key() {
+        assert_eq!(
+            parse_listing(LISTING),
+            Ok("84C514B2830F7297590996145918E653121B78D7".to_string())
+        );
+        assert!(parse_listing(&LISTING.repeat(2)).is_err());
+        assert!(parse_listing(&LISTING.replace("cEC", "cSC")).is_err());
+        assert!(parse_listing(&LISTING.replace("pub:", "sec:")).is_err());
+        assert!(parse_listing("").is_err());
+    }
+}
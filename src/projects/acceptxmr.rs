@@ -1,5 +1,6 @@
 use std::{
     future::Future,
+    io,
     pin::Pin,
     task::Poll,
     time::{Duration, Instant},
@@ -17,27 +18,86 @@ use actix_web::{
 };
 use actix_web_actors::ws;
 use bytestring::ByteString;
-use lettre::{message::Mailbox, Message, SmtpTransport, Transport};
+use lettre::{message::Mailbox, Message};
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::Arc;
+use shared::{format_xmr, InvoiceStatus, InvoiceUpdate, LockedRate, INVOICE_UPDATE_VERSION};
+use time::OffsetDateTime;
 
-use crate::{Secrets, Settings};
+use crate::{
+    email::{header_value, MAX_ADDRESS_LEN},
+    email_templates::{
+        EmailTemplates, ACCEPTXMR_ADMIN, ACCEPTXMR_SHORTFALL_ADMIN, ACCEPTXMR_SHORTFALL_USER,
+        ACCEPTXMR_USER,
+    },
+    mail::MailQueue,
+    notify::{Event, InvoiceDetails, Notifier},
+    pgp::Pgp,
+    Secrets, Settings,
+};
+
+use archive::InvoiceArchive;
+use catalog::{CatalogSettings, LineItem};
+use exchange_rate::{ExchangeRateProvider, ExchangeRateSettings};
+use extensions::InvoiceExtensions;
+use ledger::{NotificationLedger, Transition};
+
+pub mod admin;
+pub mod archive;
+pub mod catalog;
+pub mod events;
+pub mod exchange_rate;
+pub mod extensions;
+pub mod ledger;
 
 /// Time before lack of client response causes a timeout.
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 /// Time between sending heartbeat pings.
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(4);
 
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct AcceptXmrSettings {
+    /// Blocks after expiry during which an unpaid invoice is still watched for late payments.
+    pub late_payment_grace_blocks: u64,
+    pub catalog: CatalogSettings,
+    pub exchange_rate: ExchangeRateSettings,
+}
+
+impl Default for AcceptXmrSettings {
+    fn default() -> Self {
+        Self {
+            late_payment_grace_blocks: 30,
+            catalog: CatalogSettings::default(),
+            exchange_rate: ExchangeRateSettings::default(),
+        }
+    }
+}
+
+/// Everything needed to tell me and the buyer about an invoice.
+#[derive(Clone)]
+pub(crate) struct Notices {
+    pub mail_queue: MailQueue,
+    pub templates: EmailTemplates,
+    pub notifier: Notifier,
+    pub pgp: Pgp,
+    /// Mentioned in shortfall emails, since late payments still count.
+    pub late_payment_grace_blocks: u64,
+}
+
 pub(crate) async fn setup(
-    mailer: Arc<SmtpTransport>,
+    notices: Notices,
+    ledger: NotificationLedger,
+    extensions: InvoiceExtensions,
+    archive: InvoiceArchive,
     secrets: Secrets,
     settings: Settings,
 ) -> PaymentGateway<Sqlite> {
     // Read view key from file.
     let private_view_key = secrets.xmr_private_viewkey;
     let daemon_password = secrets.daemon_password;
+    let grace_blocks = settings.acceptxmr.late_payment_grace_blocks;
 
     // No need to keep the public spend key secret.
     let primary_address = "49KLp1DYdn8H344GXKDtKs9Aq8GGQBWnACxut4eHtMeYG1GNRhEmbzFCySA8WicJdQ6jVEqCKeSo4hpV6vFd9iXyH9hm4qq";
@@ -79,25 +139,37 @@ pub(crate) async fn setup(
         // Watch all invoice updates.
         let mut subscriber = gateway_copy.subscribe_all();
         loop {
-            let invoice = match subscriber.blocking_recv() {
+            let invoice = match subscriber.recv().await {
                 Some(p) => p,
                 // Global subscriber should never close.
                 None => panic!("Blockchain scanner crashed!"),
             };
 
-            // If it's confirmed, send the confirmation email.
-            if invoice.is_confirmed() {
-                send_email(&mailer, &invoice);
+            // Send the emails, notifications and webhooks for every transition the invoice has
+            // gone through, unless an earlier update already did.
+            let expiry = Expiry::of(&invoice, &extensions);
+            for transition in transitions(&invoice, expiry) {
+                notify(&ledger, &notices, &invoice, expiry, transition).await;
             }
 
-            // If it's confirmed or expired, we probably shouldn't bother tracking it anymore.
+            // If it's confirmed, or expired and still unpaid after the grace window for late
+            // payments, we probably shouldn't bother tracking it anymore. It's archived first, so
+            // there's a record of it.
+            let grace_over =
+                invoice.current_height() >= expiry.height().saturating_add(grace_blocks);
             if (invoice.is_confirmed() && invoice.creation_height() < invoice.current_height())
-                || invoice.is_expired()
+                || (grace_over && !invoice.is_paid())
             {
                 debug!(
                     "Invoice to index {} is either confirmed or expired. Removing invoice now",
                     invoice.index()
                 );
+                let outcome = invoice_update(&invoice, expiry).status;
+                if let Err(e) = archive.archive(&invoice, expiry, outcome.as_str()) {
+                    // Keep tracking it, so the next update tries again.
+                    error!("Failed to archive invoice {}: {e}", invoice.id());
+                    continue;
+                }
                 if let Err(e) = gateway_copy.remove_invoice(invoice.id()).await {
                     error!("Failed to remove fully confirmed invoice: {e}");
                 };
@@ -107,86 +179,395 @@ pub(crate) async fn setup(
     payment_gateway.clone()
 }
 
-fn send_email(mailer: &SmtpTransport, invoice: &Invoice) {
-    let description_json: CheckoutInfo = serde_json::from_str(invoice.description())
-        .expect("failed to parse description as Checkout Info");
-
-    let admin_email = Message::builder()
-        .from(
-            "AcceptXMR Demo <donotreply@busyboredom.com>"
-                .parse()
-                .unwrap(),
-        )
-        .to("Charlie Wilkin <charlie@busyboredom.com>".parse().unwrap())
-        .subject("AcceptXMR Demo: ".to_owned() + &description_json.message)
-        .body(format!(
-            "Email: {}\nMessage: {}",
-            &description_json.email, &description_json.message
-        ))
-        .expect("failed to build email");
-
-    // Send the email to me.
-    match mailer.send(&admin_email) {
-        Ok(_) => info!("AcceptXMR Demo admin email sent successfully!"),
+/// Queue the emails and webhooks for `transition` of the invoice, then record it in the ledger,
+/// unless it already was. Everything queued is keyed by the transition, so if any step fails the
+/// next update tries again without queueing anything twice. Chat messages, which aren't queued,
+/// are only sent once the transition is recorded.
+async fn notify(
+    ledger: &NotificationLedger,
+    notices: &Notices,
+    invoice: &Invoice,
+    expiry: Expiry,
+    transition: Transition,
+) {
+    let invoice_id = invoice.id().to_string();
+    match ledger.is_recorded(&invoice_id, transition) {
+        Ok(false) => {}
+        Ok(true) => return,
         Err(e) => {
-            error!("Could not send AcceptXMR Demo admin email: {e:?}");
+            error!("Could not read AcceptXMR Demo notifications of invoice {invoice_id}: {e}");
+            return;
         }
     }
 
-    if description_json.email.parse::<Mailbox>().is_err() {
-        error!(
-            "Failed to parse email address of AcceptXMR demo user: {}",
-            description_json.email
-        );
+    let key = format!("invoice {invoice_id} {}", transition.as_str());
+    if let Err(e) = notices.queue(invoice, expiry, transition, Some(&key)).await {
+        error!("Could not queue AcceptXMR Demo notifications for invoice {invoice_id}: {e}");
         return;
     }
-    let user_email = Message::builder()
-        .from("AcceptXMR Demo <donotreply@busyboredom.com>".parse().unwrap())
-        .to(description_json.email.parse().unwrap())
-        .subject("AcceptXMR Demo: ".to_owned() + &description_json.message)
-        .body(
-            format!(
-                "Thank you for trying the AcceptXMR demo! This is the message you sent:\n\"{}\"", 
-                description_json.message
-            ) + "\n\nIf your message was a question, you can expect to hear back from me within\na week or so."
-        )
-        .expect("failed to build email");
+    match ledger.record(&invoice_id, transition) {
+        Ok(true) => notices.announce(invoice, expiry, transition),
+        Ok(false) => debug!("Invoice {invoice_id} was already notified: {transition:?}"),
+        Err(e) => error!("Could not record AcceptXMR Demo notification: {e}"),
+    }
+}
 
-    // Send the email to user.
-    match mailer.send(&user_email) {
-        Ok(_) => info!("AcceptXMR Demo user email sent successfully!"),
-        Err(e) => {
-            error!("Could not send AcceptXMR Demo user email: {e:?}");
+/// The transitions the invoice has gone through, given its current state, in the order they
+/// happened.
+fn transitions(invoice: &Invoice, expiry: Expiry) -> Vec<Transition> {
+    let mut transitions = vec![Transition::Created];
+    if invoice.amount_paid() > 0 {
+        transitions.push(Transition::PaymentSeen);
+    }
+    if invoice.is_confirmed() {
+        transitions.push(Transition::Confirmed);
+    } else if expiry.is_expired(invoice) && !invoice.is_paid() {
+        transitions.push(Transition::Expired);
+        if invoice.amount_paid() > 0 {
+            transitions.push(Transition::ExpiredUnderpaid);
+        }
+    }
+    transitions
+}
+
+impl Notices {
+    /// Queue emails to me and the buyer, and webhooks, about `transition` of the invoice. Only
+    /// confirmed and partly paid expired invoices are emailed about. With a `key`, anything
+    /// already queued under it is skipped.
+    async fn queue(
+        &self,
+        invoice: &Invoice,
+        expiry: Expiry,
+        transition: Transition,
+        key: Option<&str>,
+    ) -> io::Result<()> {
+        match transition {
+            Transition::Created | Transition::PaymentSeen | Transition::Expired => {}
+            Transition::Confirmed => {
+                self.queue_emails(invoice, expiry, ACCEPTXMR_ADMIN, ACCEPTXMR_USER, key)
+                    .await?
+            }
+            Transition::ExpiredUnderpaid => {
+                info!(
+                    "Invoice {} expired {} XMR short",
+                    invoice.id(),
+                    format_xmr(invoice.amount_requested() - invoice.amount_paid())
+                );
+                self.queue_emails(
+                    invoice,
+                    expiry,
+                    ACCEPTXMR_SHORTFALL_ADMIN,
+                    ACCEPTXMR_SHORTFALL_USER,
+                    key,
+                )
+                .await?
+            }
+        }
+        match invoice_event(invoice, expiry, transition) {
+            Some(event) => self.notifier.queue(key, &event).map_err(io::Error::other),
+            None => Ok(()),
+        }
+    }
+
+    /// Tell the notifier's chat rooms about `transition` of the invoice.
+    fn announce(&self, invoice: &Invoice, expiry: Expiry, transition: Transition) {
+        if let Some(event) = invoice_event(invoice, expiry, transition) {
+            self.notifier.announce(&event);
+        }
+    }
+
+    /// Queue the `admin_template` email to me and the `user_template` email to the buyer.
+    async fn queue_emails(
+        &self,
+        invoice: &Invoice,
+        expiry: Expiry,
+        admin_template: &str,
+        user_template: &str,
+        key: Option<&str>,
+    ) -> io::Result<()> {
+        let description_json: CheckoutInfo =
+            serde_json::from_str(invoice.description()).map_err(|e| {
+                io::Error::other(format!("failed to parse description as Checkout Info: {e}"))
+            })?;
+        let amount = format_xmr(invoice.amount_paid());
+        let amount_requested = format_xmr(invoice.amount_requested());
+        let shortfall = format_xmr(
+            invoice
+                .amount_requested()
+                .saturating_sub(invoice.amount_paid()),
+        );
+        let payment_note = payment_note(invoice, expiry);
+        let grace_blocks = self.late_payment_grace_blocks.to_string();
+        let confirmations = invoice.confirmations().unwrap_or_default().to_string();
+        let confirmations_required = invoice.confirmations_required().to_string();
+        let items = description_json.items();
+        let vars = [
+            ("email", description_json.email.as_str()),
+            ("message", &description_json.message),
+            ("items", &items),
+            ("amount", &amount),
+            ("amount_requested", &amount_requested),
+            ("shortfall", &shortfall),
+            ("payment_note", &payment_note),
+            ("late_payment_grace_blocks", &grace_blocks),
+            ("confirmations", &confirmations),
+            ("confirmations_required", &confirmations_required),
+        ];
+
+        let admin_email = self
+            .pgp
+            .build_for_owner(
+                Message::builder()
+                    .from(
+                        "AcceptXMR Demo <donotreply@busyboredom.com>"
+                            .parse()
+                            .unwrap(),
+                    )
+                    .to("Charlie Wilkin <charlie@busyboredom.com>".parse().unwrap()),
+                self.templates.render(admin_template, &vars),
+                Vec::new(),
+            )
+            .await?;
+
+        // Queue the email to me.
+        self.enqueue(key.map(|key| format!("{key} admin")), &admin_email)?;
+
+        if header_value(&description_json.email, MAX_ADDRESS_LEN).is_err()
+            || description_json.email.parse::<Mailbox>().is_err()
+        {
+            error!(
+                "Failed to parse email address of AcceptXMR demo user: {}",
+                description_json.email
+            );
+            return Ok(());
+        }
+        let user_email = self
+            .templates
+            .render(user_template, &vars)
+            .build(
+                Message::builder()
+                    .from(
+                        "AcceptXMR Demo <donotreply@busyboredom.com>"
+                            .parse()
+                            .unwrap(),
+                    )
+                    .to(description_json.email.parse().unwrap()),
+            )
+            .map_err(|e| io::Error::other(format!("failed to build email: {e}")))?;
+
+        // Queue the email to user.
+        self.enqueue(key.map(|key| format!("{key} user")), &user_email)
+    }
+
+    fn enqueue(&self, key: Option<String>, message: &Message) -> io::Result<()> {
+        match key {
+            Some(key) => self.mail_queue.enqueue_once(&key, message).map(drop),
+            None => self.mail_queue.enqueue(message).map(drop),
+        }
+        .map_err(io::Error::other)
+    }
+}
+
+/// Anything unusual about how an invoice was paid, for receipts.
+fn payment_note(invoice: &Invoice, expiry: Expiry) -> String {
+    let mut notes = Vec::new();
+    if expiry.paid_late(invoice) {
+        notes.push("Paid after the invoice expired.".to_string());
+    }
+    let overpaid = invoice
+        .amount_paid()
+        .saturating_sub(invoice.amount_requested());
+    if overpaid > 0 {
+        notes.push(format!("Overpaid by {} XMR.", format_xmr(overpaid)));
+    }
+    notes.join("\n")
+}
+
+/// When an invoice expires, counting any extension from the admin dashboard.
+#[derive(Clone, Copy)]
+struct Expiry {
+    height: u64,
+}
+
+impl Expiry {
+    fn of(invoice: &Invoice, extensions: &InvoiceExtensions) -> Self {
+        let blocks = extensions
+            .blocks(&invoice.id().to_string())
+            .unwrap_or_else(|e| {
+                error!("Could not read extension of invoice {}: {e}", invoice.id());
+                0
+            });
+        Self {
+            height: invoice.expiration_height().saturating_add(blocks),
         }
     }
+
+    fn height(self) -> u64 {
+        self.height
+    }
+
+    fn is_expired(self, invoice: &Invoice) -> bool {
+        invoice.current_height() >= self.height
+    }
+
+    /// Blocks until the invoice expires.
+    fn blocks_left(self, invoice: &Invoice) -> u64 {
+        let height = invoice.creation_height().max(invoice.current_height());
+        self.height.saturating_sub(height)
+    }
+
+    /// Whether the invoice was only paid in full after it expired.
+    fn paid_late(self, invoice: &Invoice) -> bool {
+        // Confirmations count from the block the invoice was paid in full at.
+        invoice.confirmations().is_some_and(|confirmations| {
+            invoice.current_height().saturating_sub(confirmations) >= self.height
+        })
+    }
 }
 
+/// Why the buyer's page can stop watching an invoice with this status, if it can. Expired
+/// invoices are watched until the server stops tracking them, so late payments still show up.
+fn close_reason(status: InvoiceStatus) -> Option<&'static str> {
+    status.is_final().then_some("Invoice Complete")
+}
+
+/// Why the buyer's page can stop watching an invoice the server no longer tracks, e.g. one that
+/// expired and wasn't paid within the grace window.
+const NOT_TRACKED_REASON: &str = "Invoice No Longer Watched";
+
+/// The notification for `transition` of the invoice, if there is one. Partly paid invoices are
+/// only announced once, as expired.
+fn invoice_event(invoice: &Invoice, expiry: Expiry, transition: Transition) -> Option<Event> {
+    let event = match transition {
+        Transition::Created => Event::InvoiceCreated,
+        Transition::PaymentSeen => Event::InvoicePaymentSeen,
+        Transition::Confirmed => Event::InvoicePaid,
+        Transition::Expired => Event::InvoiceExpired,
+        Transition::ExpiredUnderpaid => return None,
+    };
+    let info: CheckoutInfo = serde_json::from_str(invoice.description()).unwrap_or_default();
+    let items = info.items();
+    Some(event(InvoiceDetails {
+        invoice_id: invoice.id().to_string(),
+        status: invoice_update(invoice, expiry).status,
+        address: invoice.address().to_string(),
+        amount_requested: invoice.amount_requested(),
+        amount_piconeros: invoice.amount_paid(),
+        amount_xmr: format_xmr(invoice.amount_paid()),
+        confirmations: invoice.confirmations(),
+        confirmations_required: invoice.confirmations_required(),
+        creation_height: invoice.creation_height(),
+        expiration_height: expiry.height(),
+        current_height: invoice.current_height(),
+        email: info.email,
+        message: info.message,
+        items,
+        exchange_rate: info.exchange_rate,
+    }))
+}
+
+/// Stored as the description of each invoice.
 #[derive(Deserialize, Serialize, Default)]
 struct CheckoutInfo {
     email: String,
     message: String,
+    /// Missing from invoices created before the catalog existed.
+    #[serde(default)]
+    line_items: Vec<LineItem>,
+    /// Rate the invoice was priced at.
+    #[serde(default)]
+    exchange_rate: Option<LockedRate>,
+    /// Unix time of checkout. Missing from invoices created before the admin dashboard.
+    #[serde(default)]
+    created_at: Option<i64>,
+}
+
+impl CheckoutInfo {
+    /// What was bought, one line per item.
+    fn items(&self) -> String {
+        let currency = self
+            .exchange_rate
+            .as_ref()
+            .map(|rate| rate.currency.as_str())
+            .unwrap_or_default();
+        catalog::summary(&self.line_items, currency)
+    }
+}
+
+/// What the buyer asks for at checkout.
+#[derive(Deserialize, Serialize)]
+struct CheckoutRequest {
+    #[serde(default)]
+    email: String,
+    #[serde(default)]
+    message: String,
+    item_id: String,
+    quantity: u32,
+}
+
+/// The items that can be checked out.
+#[get("/projects/acceptxmr/catalog")]
+async fn catalog_items(settings: web::Data<Settings>) -> HttpResponse {
+    HttpResponse::Ok().json(&settings.acceptxmr.catalog)
 }
 
 /// Create new invoice and place cookie.
 #[post("/projects/acceptxmr/checkout")]
 async fn checkout(
     session: Session,
-    checkout_info: Option<web::Json<CheckoutInfo>>,
+    checkout_request: Option<web::Json<CheckoutRequest>>,
     payment_gateway: web::Data<PaymentGateway<Sqlite>>,
+    exchange_rates: web::Data<dyn ExchangeRateProvider>,
+    settings: web::Data<Settings>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let checkout_info = match checkout_info {
-        Some(json_info) => {
-            let info = json_info.into_inner();
-            session.insert("checkout_info", &info)?;
-            info
-        }
-        None => {
-            // If not provided, see if there's one in the session cookie.
-            session.get("checkout_info")?.unwrap_or_default()
+    let request = match checkout_request {
+        Some(json_request) => json_request.into_inner(),
+        // If not provided, see if there's one in the session cookie.
+        None => match session.get::<CheckoutRequest>("checkout_request")? {
+            Some(request) => request,
+            None => return Ok(bad_request("Choose an item to check out.".to_string())),
+        },
+    };
+    if let Err(e) = header_value(&request.email, MAX_ADDRESS_LEN) {
+        return Ok(bad_request(format!("Email {e}.")));
+    }
+    let order = match settings
+        .acceptxmr
+        .catalog
+        .order(&request.item_id, request.quantity)
+    {
+        Ok(order) => order,
+        Err(e) => return Ok(bad_request(e)),
+    };
+    let rate = match exchange_rates.rate().await {
+        Ok(rate) => rate,
+        Err(e) => {
+            error!("Could not get exchange rate: {e}");
+            return Ok(HttpResponse::ServiceUnavailable()
+                .append_header(CacheControl(vec![CacheDirective::NoStore]))
+                .body("Prices are unavailable right now. Please try again later."));
         }
     };
+    let (locked_rate, amount) = match order.at_rate(&settings.acceptxmr.catalog.currency, rate) {
+        Ok(priced) => priced,
+        Err(e) => return Ok(bad_request(e)),
+    };
+    session.insert("checkout_request", &request)?;
+
+    let checkout_info = CheckoutInfo {
+        email: request.email,
+        message: request.message,
+        line_items: order.line_items,
+        exchange_rate: Some(locked_rate),
+        created_at: Some(OffsetDateTime::now_utc().unix_timestamp()),
+    };
     let invoice_id = payment_gateway
-        .new_invoice(1_000_000_000, 2, 5, json!(checkout_info).to_string())
+        .new_invoice(
+            amount,
+            order.confirmations,
+            order.expiration_blocks,
+            json!(checkout_info).to_string(),
+        )
         .await
         .unwrap();
     session.insert("id", invoice_id)?;
@@ -195,27 +576,25 @@ async fn checkout(
         .finish())
 }
 
+fn bad_request(message: String) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .append_header(CacheControl(vec![CacheDirective::NoStore]))
+        .body(message)
+}
+
 // Get invoice update without waiting for websocket.
 #[get("/update")]
 async fn update(
     session: Session,
     payment_gateway: web::Data<PaymentGateway<Sqlite>>,
+    extensions: web::Data<InvoiceExtensions>,
 ) -> Result<HttpResponse, actix_web::Error> {
     if let Ok(Some(invoice_id)) = session.get::<InvoiceId>("id") {
         if let Ok(Some(invoice)) = payment_gateway.get_invoice(invoice_id).await {
+            let expiry = Expiry::of(&invoice, &extensions);
             return Ok(HttpResponse::Ok()
                 .append_header(CacheControl(vec![CacheDirective::NoStore]))
-                .json(json!(
-                    {
-                        "address": invoice.address(),
-                        "amount_paid": invoice.amount_paid(),
-                        "amount_requested": invoice.amount_requested(),
-                        "uri": invoice.uri(),
-                        "confirmations": invoice.confirmations(),
-                        "confirmations_required": invoice.confirmations_required(),
-                        "expiration_in": invoice.expiration_in(),
-                    }
-                )));
+                .json(invoice_update(&invoice, expiry)));
         };
     }
     Ok(HttpResponse::Gone()
@@ -223,6 +602,32 @@ async fn update(
         .finish())
 }
 
+/// The state of an invoice, as sent to the buyer's browser.
+fn invoice_update(invoice: &Invoice, expiry: Expiry) -> InvoiceUpdate {
+    let exchange_rate = serde_json::from_str::<CheckoutInfo>(invoice.description())
+        .ok()
+        .and_then(|info| info.exchange_rate);
+    InvoiceUpdate {
+        version: INVOICE_UPDATE_VERSION,
+        status: InvoiceStatus::new(
+            invoice.amount_paid(),
+            invoice.amount_requested(),
+            invoice.confirmations(),
+            invoice.confirmations_required(),
+            expiry.is_expired(invoice),
+            expiry.paid_late(invoice),
+        ),
+        address: invoice.address().to_string(),
+        amount_paid: invoice.amount_paid(),
+        amount_requested: invoice.amount_requested(),
+        uri: invoice.uri(),
+        confirmations: invoice.confirmations(),
+        confirmations_required: invoice.confirmations_required(),
+        expiration_in: expiry.blocks_left(invoice),
+        exchange_rate,
+    }
+}
+
 /// WebSocket rout.
 #[get("/projects/acceptxmr/ws/")]
 async fn websocket(
@@ -230,6 +635,7 @@ async fn websocket(
     req: HttpRequest,
     stream: web::Payload,
     payment_gateway: web::Data<PaymentGateway<Sqlite>>,
+    extensions: web::Data<InvoiceExtensions>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let invoice_id = match session.get::<InvoiceId>("id") {
         Ok(Some(i)) => i,
@@ -247,20 +653,26 @@ async fn websocket(
                 .finish())
         }
     };
-    ws::start(WebSocket::new(subscriber), &req, stream)
+    ws::start(
+        WebSocket::new(subscriber, extensions.get_ref().clone()),
+        &req,
+        stream,
+    )
 }
 
 /// Define websocket HTTP actor
 struct WebSocket {
     last_heartbeat: Instant,
     invoice_subscriber: Option<Subscriber>,
+    extensions: InvoiceExtensions,
 }
 
 impl WebSocket {
-    fn new(invoice_subscriber: Subscriber) -> Self {
+    fn new(invoice_subscriber: Subscriber, extensions: InvoiceExtensions) -> Self {
         Self {
             last_heartbeat: Instant::now(),
             invoice_subscriber: Some(invoice_subscriber),
+            extensions,
         }
     }
 
@@ -320,34 +732,25 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
 impl StreamHandler<Invoice> for WebSocket {
     fn handle(&mut self, msg: Invoice, ctx: &mut Self::Context) {
         // Send the update to the user.
+        let state = invoice_update(&msg, Expiry::of(&msg, &self.extensions));
         ctx.text(ByteString::from(
-            json!(
-                {
-                    "address": msg.address(),
-                    "amount_paid": msg.amount_paid(),
-                    "amount_requested": msg.amount_requested(),
-                    "uri": msg.uri(),
-                    "confirmations": msg.confirmations(),
-                    "confirmations_required": msg.confirmations_required(),
-                    "expiration_in": msg.expiration_in(),
-                }
-            )
-            .to_string(),
+            serde_json::to_string(&state).expect("Invoice updates always serialize"),
         ));
         // If the invoice is confirmed or expired, stop checking for updates.
-        if msg.is_confirmed() {
-            ctx.close(Some(ws::CloseReason::from((
-                ws::CloseCode::Normal,
-                "Invoice Complete",
-            ))));
-            ctx.stop();
-        } else if msg.is_expired() {
-            ctx.close(Some(ws::CloseReason::from((
-                ws::CloseCode::Normal,
-                "Invoice Expired",
-            ))));
-            ctx.stop();
-        }
+        let Some(reason) = close_reason(state.status) else {
+            return;
+        };
+        ctx.close(Some(ws::CloseReason::from((ws::CloseCode::Normal, reason))));
+        ctx.stop();
+    }
+
+    /// The invoice is no longer tracked, so there will be no more updates.
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason::from((
+            ws::CloseCode::Normal,
+            NOT_TRACKED_REASON,
+        ))));
+        ctx.stop();
     }
 }
 
@@ -0,0 +1,402 @@
+use std::convert::TryFrom;
+
+use acceptxmr::{storage::stores::Sqlite, AcceptXmrError, Invoice, InvoiceId, PaymentGateway};
+use actix_web::{
+    error::{ErrorInternalServerError, ErrorNotFound},
+    get,
+    http::header::{self, CacheControl, CacheDirective, ContentDisposition, DispositionParam},
+    post, web, HttpResponse, Result,
+};
+use log::{error, info};
+use serde::Deserialize;
+use shared::{format_cents, format_xmr, InvoiceStatus};
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+
+use super::{
+    archive::{self, ExportFormat, InvoiceArchive},
+    extensions::InvoiceExtensions,
+    invoice_update,
+    ledger::Transition,
+    transitions, CheckoutInfo, Expiry, Notices,
+};
+use crate::{
+    admin::{self, Admin},
+    email::escape_html,
+};
+
+/// Average time between Monero blocks, for estimating when older invoices were created.
+const BLOCK_TIME_SECS: i64 = 120;
+/// Most blocks an invoice can be extended by at once, about a day.
+const MAX_EXTENSION_BLOCKS: u64 = 720;
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct InvoiceQuery {
+    /// Status as it appears in invoice updates. Empty for all.
+    status: String,
+    /// Earliest creation date to show, as `YYYY-MM-DD`.
+    from: String,
+    /// Latest creation date to show, as `YYYY-MM-DD`.
+    to: String,
+}
+
+impl InvoiceQuery {
+    /// Whether to show an invoice with `status`, created at `created_at` in RFC 3339 format.
+    fn matches(&self, status: InvoiceStatus, created_at: &str) -> bool {
+        let date = created_at.get(..10).unwrap_or_default();
+        (self.status.is_empty() || self.status == status.as_str())
+            && (self.from.is_empty() || date >= self.from.as_str())
+            && (self.to.is_empty() || date <= self.to.as_str())
+    }
+}
+
+/// An invoice as shown on the dashboard.
+struct InvoiceRow {
+    invoice: Invoice,
+    info: CheckoutInfo,
+    expiry: Expiry,
+    status: InvoiceStatus,
+    /// When the invoice was created, in RFC 3339 format.
+    created_at: String,
+    /// Whether `created_at` was estimated from block heights, for invoices created before
+    /// checkout times were recorded.
+    estimated: bool,
+}
+
+impl InvoiceRow {
+    fn new(invoice: Invoice, extensions: &InvoiceExtensions) -> Self {
+        let info: CheckoutInfo = serde_json::from_str(invoice.description()).unwrap_or_default();
+        let expiry = Expiry::of(&invoice, extensions);
+        let status = invoice_update(&invoice, expiry).status;
+        let recorded = info
+            .created_at
+            .and_then(|t| OffsetDateTime::from_unix_timestamp(t).ok());
+        let estimated = recorded.is_none();
+        let created_at = recorded.unwrap_or_else(|| {
+            let blocks = invoice
+                .current_height()
+                .saturating_sub(invoice.creation_height());
+            let age = i64::from(u32::try_from(blocks).unwrap_or(u32::MAX)) * BLOCK_TIME_SECS;
+            OffsetDateTime::now_utc()
+                .checked_sub(Duration::seconds(age))
+                .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+        });
+        Self {
+            invoice,
+            info,
+            expiry,
+            status,
+            created_at: created_at.format(&Rfc3339).unwrap_or_default(),
+            estimated,
+        }
+    }
+}
+
+/// Every invoice the payment gateway is tracking, newest first.
+async fn invoices(
+    payment_gateway: &PaymentGateway<Sqlite>,
+    extensions: &InvoiceExtensions,
+) -> Result<Vec<InvoiceRow>, AcceptXmrError> {
+    let mut rows = Vec::new();
+    for id in payment_gateway.get_invoice_ids().await? {
+        if let Some(invoice) = payment_gateway.get_invoice(id).await? {
+            rows.push(InvoiceRow::new(invoice, extensions));
+        }
+    }
+    rows.sort_by_key(|row| std::cmp::Reverse(row.invoice.id()));
+    Ok(rows)
+}
+
+/// Admin page listing AcceptXMR demo invoices.
+#[get("/admin/invoices")]
+async fn invoices_page(
+    _admin: Admin,
+    payment_gateway: web::Data<PaymentGateway<Sqlite>>,
+    extensions: web::Data<InvoiceExtensions>,
+    web::Query(query): web::Query<InvoiceQuery>,
+) -> Result<HttpResponse> {
+    let rows = invoices(&payment_gateway, &extensions).await.map_err(|e| {
+        error!("Failed to list invoices: {e}");
+        ErrorInternalServerError("Failed to list invoices")
+    })?;
+
+    let mut options = format!(
+        "<option value=\"\"{}>All</option>",
+        if query.status.is_empty() {
+            " selected"
+        } else {
+            ""
+        }
+    );
+    for status in InvoiceStatus::ALL {
+        options += &format!(
+            "<option value=\"{status}\"{}>{status}</option>",
+            if query.status == status.as_str() {
+                " selected"
+            } else {
+                ""
+            },
+            status = status.as_str(),
+        );
+    }
+    let mut body = format!(
+        "<form method=\"get\">
+<select name=\"status\">{options}</select>
+<label>From <input type=\"date\" name=\"from\" value=\"{}\"></label>
+<label>To <input type=\"date\" name=\"to\" value=\"{}\"></label>
+<button>Filter</button>
+</form>
+<table>
+<tr><th>Created</th><th>Invoice</th><th>Status</th><th>Requested</th><th>Paid</th>\
+<th>Confirmations</th><th>Expiry</th><th>Checkout</th><th></th></tr>\n",
+        escape_html(&query.from),
+        escape_html(&query.to),
+    );
+    let mut shown = 0;
+    for row in rows
+        .iter()
+        .filter(|row| query.matches(row.status, &row.created_at))
+    {
+        shown += 1;
+        let invoice = &row.invoice;
+        let id = u128::from(invoice.id());
+        let extended = row.expiry.height() - invoice.expiration_height();
+        let mut expiry = if row.expiry.is_expired(invoice) {
+            format!("expired at block {}", row.expiry.height())
+        } else {
+            format!("{} blocks left", row.expiry.blocks_left(invoice))
+        };
+        if extended > 0 {
+            expiry += &format!("<br><small>extended by {extended}</small>");
+        }
+        let rate = match &row.info.exchange_rate {
+            Some(rate) => format!(
+                "<br><small>{} {currency} at {} {currency}/XMR</small>",
+                format_cents(rate.total_cents),
+                rate.rate,
+                currency = escape_html(&rate.currency),
+            ),
+            None => String::new(),
+        };
+        body += &format!(
+            "<tr><td>{}{}</td><td>{}<br><small>{}</small></td><td>{}</td><td>{} XMR{rate}</td>\
+            <td>{} XMR</td><td>{}/{}</td><td>{expiry}</td>\
+            <td><a href=\"mailto:{}\">{}</a><br>{}<div class=\"message\">{}</div></td>\
+            <td><form class=\"inline\" method=\"post\" action=\"/admin/invoices/{id}/resend\">\
+            <button>Resend notifications</button></form>\
+            <form method=\"post\" action=\"/admin/invoices/{id}/extend\">\
+            <input type=\"number\" name=\"blocks\" min=\"1\" max=\"{MAX_EXTENSION_BLOCKS}\" value=\"10\">\
+            <button>Extend</button></form>\
+            <form class=\"inline\" method=\"post\" action=\"/admin/invoices/{id}/cancel\">\
+            <button>Cancel</button></form></td></tr>\n",
+            escape_html(&row.created_at),
+            if row.estimated { " (estimated)" } else { "" },
+            escape_html(&invoice.id().to_string()),
+            escape_html(invoice.address()),
+            row.status.as_str(),
+            format_xmr(invoice.amount_requested()),
+            format_xmr(invoice.amount_paid()),
+            invoice.confirmations().unwrap_or_default(),
+            invoice.confirmations_required(),
+            escape_html(&row.info.email),
+            escape_html(&row.info.email),
+            escape_html(&row.info.items()).replace('\n', "<br>"),
+            escape_html(&row.info.message),
+        );
+    }
+    body += "</table>";
+    if shown == 0 {
+        body += "<p>No invoices.</p>";
+    }
+    body += "<h2>Archive</h2>
+<form method=\"get\" action=\"/admin/invoices/export\">
+<label>From <input type=\"date\" name=\"from\"></label>
+<label>To <input type=\"date\" name=\"to\"></label>
+<select name=\"format\">
+<option value=\"csv\">CSV</option>
+<option value=\"ledger\">Ledger</option>
+</select>
+<button>Export</button>
+</form>";
+
+    Ok(admin::page("Invoices", &body))
+}
+
+/// Look up an invoice the payment gateway is tracking.
+async fn tracked_invoice(
+    payment_gateway: &PaymentGateway<Sqlite>,
+    id: InvoiceId,
+) -> Result<Invoice> {
+    match payment_gateway.get_invoice(id).await {
+        Ok(Some(invoice)) => Ok(invoice),
+        Ok(None) => Err(ErrorNotFound("No such invoice")),
+        Err(e) => {
+            error!("Failed to get invoice {id}: {e}");
+            Err(ErrorInternalServerError("Failed to get invoice"))
+        }
+    }
+}
+
+fn back_to_invoices() -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "/admin/invoices"))
+        .finish()
+}
+
+/// Archive an invoice and stop tracking it. The buyer's page will show it as gone.
+#[post("/admin/invoices/{id}/cancel")]
+async fn cancel(
+    _admin: Admin,
+    payment_gateway: web::Data<PaymentGateway<Sqlite>>,
+    extensions: web::Data<InvoiceExtensions>,
+    archive: web::Data<InvoiceArchive>,
+    id: web::Path<u128>,
+) -> Result<HttpResponse> {
+    let id = InvoiceId::from(id.into_inner());
+    let invoice = tracked_invoice(&payment_gateway, id).await?;
+    let expiry = Expiry::of(&invoice, &extensions);
+    if let Err(e) = archive.archive(&invoice, expiry, "cancelled") {
+        error!("Failed to archive invoice {id}: {e}");
+        return Err(ErrorInternalServerError("Failed to archive invoice"));
+    }
+    match payment_gateway.remove_invoice(id).await {
+        Ok(Some(_)) => {
+            info!("Cancelled invoice {id}");
+            Ok(back_to_invoices())
+        }
+        Ok(None) => Err(ErrorNotFound("No such invoice")),
+        Err(e) => {
+            error!("Failed to cancel invoice {id}: {e}");
+            Err(ErrorInternalServerError("Failed to cancel invoice"))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExtendForm {
+    blocks: u64,
+}
+
+/// Give the buyer more blocks to pay an invoice in.
+#[post("/admin/invoices/{id}/extend")]
+async fn extend(
+    _admin: Admin,
+    payment_gateway: web::Data<PaymentGateway<Sqlite>>,
+    extensions: web::Data<InvoiceExtensions>,
+    id: web::Path<u128>,
+    form: web::Form<ExtendForm>,
+) -> Result<HttpResponse> {
+    let id = InvoiceId::from(id.into_inner());
+    if form.blocks == 0 || form.blocks > MAX_EXTENSION_BLOCKS {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "Invoices can be extended by 1 to {MAX_EXTENSION_BLOCKS} blocks at a time"
+        )));
+    }
+    tracked_invoice(&payment_gateway, id).await?;
+    match extensions.extend(&id.to_string(), form.blocks) {
+        Ok(total) => {
+            info!(
+                "Extended invoice {id} by {} blocks, {total} in total",
+                form.blocks
+            );
+            Ok(back_to_invoices())
+        }
+        Err(e) => {
+            error!("Failed to extend invoice {id}: {e}");
+            Err(ErrorInternalServerError("Failed to extend invoice"))
+        }
+    }
+}
+
+/// Send the emails and notifications for an invoice's current state again.
+#[post("/admin/invoices/{id}/resend")]
+async fn resend(
+    _admin: Admin,
+    payment_gateway: web::Data<PaymentGateway<Sqlite>>,
+    extensions: web::Data<InvoiceExtensions>,
+    notices: web::Data<Notices>,
+    id: web::Path<u128>,
+) -> Result<HttpResponse> {
+    let id = InvoiceId::from(id.into_inner());
+    let invoice = tracked_invoice(&payment_gateway, id).await?;
+    let expiry = Expiry::of(&invoice, &extensions);
+    let emailed = transitions(&invoice, expiry)
+        .into_iter()
+        .rev()
+        .find(|transition| {
+            matches!(
+                transition,
+                Transition::Confirmed | Transition::ExpiredUnderpaid
+            )
+        });
+    match emailed {
+        Some(transition) => {
+            info!("Resending notifications for invoice {id}: {transition:?}");
+            if let Err(e) = notices.queue(&invoice, expiry, transition, None).await {
+                error!("Failed to resend notifications for invoice {id}: {e}");
+                return Err(ErrorInternalServerError("Failed to queue notifications"));
+            }
+            notices.announce(&invoice, expiry, transition);
+            Ok(back_to_invoices())
+        }
+        None => Ok(HttpResponse::Conflict().body("Nothing to notify for this invoice yet")),
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: ExportFormat,
+    /// Earliest archive date to include, as `YYYY-MM-DD`. Empty for no limit.
+    #[serde(default)]
+    from: String,
+    /// Latest archive date to include, as `YYYY-MM-DD`. Empty for no limit.
+    #[serde(default)]
+    to: String,
+}
+
+/// Download archived invoices as CSV or ledger entries.
+#[get("/admin/invoices/export")]
+async fn export(
+    _admin: Admin,
+    archive: web::Data<InvoiceArchive>,
+    web::Query(query): web::Query<ExportQuery>,
+) -> Result<HttpResponse> {
+    let from = Some(query.from.as_str()).filter(|date| !date.is_empty());
+    let to = Some(query.to.as_str()).filter(|date| !date.is_empty());
+    let invoices = archive.between(from, to).map_err(|e| {
+        error!("Failed to read invoice archive: {e}");
+        ErrorInternalServerError("Failed to read invoice archive")
+    })?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .insert_header(ContentDisposition {
+            disposition: header::DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(
+                query.format.filename().to_string(),
+            )],
+        })
+        .content_type(query.format.content_type())
+        .body(archive::export(&invoices, query.format)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_by_status_and_date() {
+        let query = |status: &str, from: &str, to: &str| InvoiceQuery {
+            status: status.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        let created_at = "2026-10-19T12:00:00Z";
+        assert!(query("", "", "").matches(InvoiceStatus::Pending, created_at));
+        assert!(query("pending", "2026-10-19", "2026-10-19")
+            .matches(InvoiceStatus::Pending, created_at));
+        assert!(!query("confirmed", "", "").matches(InvoiceStatus::Pending, created_at));
+        assert!(!query("", "2026-10-20", "").matches(InvoiceStatus::Pending, created_at));
+        assert!(!query("", "", "2026-10-18").matches(InvoiceStatus::Pending, created_at));
+    }
+}
@@ -0,0 +1,401 @@
+use std::convert::{TryFrom, TryInto};
+
+use acceptxmr::Invoice;
+use clap::{Subcommand, ValueEnum};
+use serde::Deserialize;
+use shared::format_xmr;
+use sqlite::{State, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use super::{CheckoutInfo, Expiry};
+use crate::{admin::csv_row, db::Db};
+
+/// Account payments are booked to in ledger exports.
+const ASSET_ACCOUNT: &str = "Assets:Monero:AcceptXMR";
+/// Account payments are booked against in ledger exports.
+const INCOME_ACCOUNT: &str = "Income:AcceptXMR";
+
+/// Invoices the payment gateway has stopped tracking, kept as a record of past payments.
+#[derive(Clone)]
+pub struct InvoiceArchive {
+    db: Db,
+}
+
+/// An invoice as it was when it was archived.
+pub struct ArchivedInvoice {
+    pub id: String,
+    /// Subaddress index payments went to, as `major/minor`.
+    pub index: String,
+    /// Status as it appears in invoice updates, or "cancelled".
+    pub outcome: String,
+    pub address: String,
+    pub amount_requested: u64,
+    pub amount_paid: u64,
+    pub confirmations_required: u64,
+    pub creation_height: u64,
+    /// Height the invoice expired at, including any extension.
+    pub expiration_height: u64,
+    /// Height when the invoice was archived.
+    pub final_height: u64,
+    /// Checkout time, for invoices created after checkout times were recorded.
+    pub created_at: Option<i64>,
+    pub archived_at: i64,
+    /// The checkout's JSON description.
+    pub description: String,
+}
+
+impl InvoiceArchive {
+    pub fn open(db: Db) -> Result<InvoiceArchive, sqlite::Error> {
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS invoice_archive (
+                invoice_id             TEXT PRIMARY KEY,
+                invoice_index          TEXT NOT NULL,
+                outcome                TEXT NOT NULL,
+                address                TEXT NOT NULL,
+                amount_requested       INTEGER NOT NULL,
+                amount_paid            INTEGER NOT NULL,
+                confirmations_required INTEGER NOT NULL,
+                creation_height        INTEGER NOT NULL,
+                expiration_height      INTEGER NOT NULL,
+                final_height           INTEGER NOT NULL,
+                created_at             INTEGER,
+                archived_at            INTEGER NOT NULL,
+                description            TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS invoice_archive_archived_at
+                ON invoice_archive (archived_at);",
+        )?;
+        Ok(InvoiceArchive { db })
+    }
+
+    /// Record the invoice before it is removed from the payment gateway. Archiving it again, if
+    /// removing it failed, updates the record but keeps the original archive time.
+    pub(super) fn archive(
+        &self,
+        invoice: &Invoice,
+        expiry: Expiry,
+        outcome: &str,
+    ) -> Result<(), sqlite::Error> {
+        let info: CheckoutInfo = serde_json::from_str(invoice.description()).unwrap_or_default();
+        self.insert(&ArchivedInvoice {
+            id: invoice.id().to_string(),
+            index: invoice.index().to_string(),
+            outcome: outcome.to_string(),
+            address: invoice.address().to_string(),
+            amount_requested: invoice.amount_requested(),
+            amount_paid: invoice.amount_paid(),
+            confirmations_required: invoice.confirmations_required(),
+            creation_height: invoice.creation_height(),
+            expiration_height: expiry.height(),
+            final_height: invoice.current_height(),
+            created_at: info.created_at,
+            archived_at: OffsetDateTime::now_utc().unix_timestamp(),
+            description: invoice.description().to_string(),
+        })
+    }
+
+    fn insert(&self, invoice: &ArchivedInvoice) -> Result<(), sqlite::Error> {
+        let mut statement = self.db.prepare(
+            "INSERT INTO invoice_archive (
+                invoice_id, invoice_index, outcome, address, amount_requested, amount_paid,
+                confirmations_required, creation_height, expiration_height, final_height,
+                created_at, archived_at, description
+            ) VALUES (
+                :invoice_id, :invoice_index, :outcome, :address, :amount_requested, :amount_paid,
+                :confirmations_required, :creation_height, :expiration_height, :final_height,
+                :created_at, :archived_at, :description
+            )
+            ON CONFLICT (invoice_id) DO UPDATE
+            SET outcome = excluded.outcome, amount_paid = excluded.amount_paid,
+                expiration_height = excluded.expiration_height,
+                final_height = excluded.final_height",
+        )?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":invoice_id", invoice.id.as_str().into()),
+                (":invoice_index", invoice.index.as_str().into()),
+                (":outcome", invoice.outcome.as_str().into()),
+                (":address", invoice.address.as_str().into()),
+                (":amount_requested", to_sql(invoice.amount_requested).into()),
+                (":amount_paid", to_sql(invoice.amount_paid).into()),
+                (
+                    ":confirmations_required",
+                    to_sql(invoice.confirmations_required).into(),
+                ),
+                (":creation_height", to_sql(invoice.creation_height).into()),
+                (
+                    ":expiration_height",
+                    to_sql(invoice.expiration_height).into(),
+                ),
+                (":final_height", to_sql(invoice.final_height).into()),
+                (
+                    ":created_at",
+                    invoice.created_at.map_or(Value::Null, Into::into),
+                ),
+                (":archived_at", invoice.archived_at.into()),
+                (":description", invoice.description.as_str().into()),
+            ][..],
+        )?;
+        while statement.next()? == State::Row {}
+        Ok(())
+    }
+
+    /// Invoices archived between `from` and `to` inclusive, as `YYYY-MM-DD` in UTC, oldest first.
+    /// Either end may be left open.
+    pub fn between(
+        &self,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> Result<Vec<ArchivedInvoice>, sqlite::Error> {
+        let mut statement = self.db.prepare(
+            "SELECT * FROM invoice_archive
+            WHERE (:from IS NULL OR date(archived_at, 'unixepoch') >= :from)
+                AND (:to IS NULL OR date(archived_at, 'unixepoch') <= :to)
+            ORDER BY archived_at, rowid",
+        )?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":from", from.map_or(Value::Null, Into::into)),
+                (":to", to.map_or(Value::Null, Into::into)),
+            ][..],
+        )?;
+        let mut invoices = Vec::new();
+        while statement.next()? == State::Row {
+            let read = |column| -> Result<u64, sqlite::Error> {
+                Ok(statement
+                    .read::<i64, _>(column)?
+                    .try_into()
+                    .unwrap_or_default())
+            };
+            invoices.push(ArchivedInvoice {
+                id: statement.read("invoice_id")?,
+                index: statement.read("invoice_index")?,
+                outcome: statement.read("outcome")?,
+                address: statement.read("address")?,
+                amount_requested: read("amount_requested")?,
+                amount_paid: read("amount_paid")?,
+                confirmations_required: read("confirmations_required")?,
+                creation_height: read("creation_height")?,
+                expiration_height: read("expiration_height")?,
+                final_height: read("final_height")?,
+                created_at: statement.read("created_at")?,
+                archived_at: statement.read("archived_at")?,
+                description: statement.read("description")?,
+            });
+        }
+        Ok(invoices)
+    }
+}
+
+fn to_sql(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .ok()
+        .and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One row per archived invoice.
+    Csv,
+    /// Plain text accounting entries for every invoice that was paid anything.
+    Ledger,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ledger => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn filename(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "invoices.csv",
+            ExportFormat::Ledger => "invoices.ledger",
+        }
+    }
+}
+
+/// Write archived invoices out in `format`.
+pub fn export(invoices: &[ArchivedInvoice], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Csv => csv(invoices),
+        ExportFormat::Ledger => ledger(invoices),
+    }
+}
+
+fn csv(invoices: &[ArchivedInvoice]) -> String {
+    let mut csv = csv_row(&[
+        "id",
+        "index",
+        "outcome",
+        "address",
+        "amount_requested_xmr",
+        "amount_paid_xmr",
+        "confirmations_required",
+        "creation_height",
+        "expiration_height",
+        "final_height",
+        "created_at",
+        "archived_at",
+        "description",
+    ]);
+    for invoice in invoices {
+        csv += &csv_row(&[
+            &invoice.id,
+            &invoice.index,
+            &invoice.outcome,
+            &invoice.address,
+            &format_xmr(invoice.amount_requested),
+            &format_xmr(invoice.amount_paid),
+            &invoice.confirmations_required.to_string(),
+            &invoice.creation_height.to_string(),
+            &invoice.expiration_height.to_string(),
+            &invoice.final_height.to_string(),
+            &invoice.created_at.map(format_timestamp).unwrap_or_default(),
+            &format_timestamp(invoice.archived_at),
+            &invoice.description,
+        ]);
+    }
+    csv
+}
+
+fn ledger(invoices: &[ArchivedInvoice]) -> String {
+    let mut ledger = String::new();
+    for invoice in invoices.iter().filter(|invoice| invoice.amount_paid > 0) {
+        let date = OffsetDateTime::from_unix_timestamp(invoice.archived_at)
+            .map(|t| t.date().to_string())
+            .unwrap_or_default();
+        let info: CheckoutInfo = serde_json::from_str(&invoice.description).unwrap_or_default();
+        ledger += &format!(
+            "{date} * AcceptXMR invoice {}\n    ; outcome: {}\n    ; address: {}\n",
+            invoice.id, invoice.outcome, invoice.address
+        );
+        if !info.email.is_empty() {
+            // Keep the comment on one line, whatever the buyer typed.
+            ledger += &format!("    ; email: {}\n", info.email.replace(['\r', '\n'], " "));
+        }
+        ledger += &format!(
+            "    {ASSET_ACCOUNT}  {} XMR\n    {INCOME_ACCOUNT}\n\n",
+            format_xmr(invoice.amount_paid)
+        );
+    }
+    ledger
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ArchiveCommand {
+    /// Print invoices archived between two dates to stdout.
+    Export {
+        /// Earliest archive date to include, as `YYYY-MM-DD` in UTC.
+        #[arg(long)]
+        from: Option<String>,
+        /// Latest archive date to include, as `YYYY-MM-DD` in UTC.
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ExportFormat,
+    },
+}
+
+pub fn run_command(command: ArchiveCommand, archive: &InvoiceArchive) -> Result<(), sqlite::Error> {
+    match command {
+        ArchiveCommand::Export { from, to, format } => {
+            let invoices = archive.between(from.as_deref(), to.as_deref())?;
+            print!("{}", export(&invoices, format));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn archived(id: &str, amount_paid: u64, archived_at: i64) -> ArchivedInvoice {
+        ArchivedInvoice {
+            id: id.to_string(),
+            index: "0/1".to_string(),
+            outcome: "confirmed".to_string(),
+            address: "84Ph3RzZ".to_string(),
+            amount_requested: 250_000_000_000,
+            amount_paid,
+            confirmations_required: 2,
+            creation_height: 100,
+            expiration_height: 105,
+            final_height: 103,
+            created_at: None,
+            archived_at,
+            description: r#"{"email":"alice@example.com","message":"Hi, there!"}"#.to_string(),
+        }
+    }
+
+    #[test]
+    fn filters_by_archive_date() {
+        let archive = InvoiceArchive::open(db::open_path(":memory:").unwrap()).unwrap();
+        // 2026-10-18 and 2026-10-19, 12:00 UTC.
+        archive
+            .insert(&archived("(0/1,100)", 0, 1_792_324_800))
+            .unwrap();
+        archive
+            .insert(&archived("(0/2,100)", 0, 1_792_411_200))
+            .unwrap();
+        let ids = |from, to| -> Vec<String> {
+            archive
+                .between(from, to)
+                .unwrap()
+                .into_iter()
+                .map(|invoice| invoice.id)
+                .collect()
+        };
+        assert_eq!(ids(None, None), ["(0/1,100)", "(0/2,100)"]);
+        assert_eq!(ids(Some("2026-10-19"), None), ["(0/2,100)"]);
+        assert_eq!(ids(None, Some("2026-10-18")), ["(0/1,100)"]);
+        assert!(ids(Some("2026-10-20"), None).is_empty());
+
+        // Archiving again keeps the original archive time.
+        archive
+            .insert(&archived("(0/1,100)", 5, 1_792_411_200))
+            .unwrap();
+        let invoices = archive.between(None, Some("2026-10-18")).unwrap();
+        assert_eq!(invoices[0].amount_paid, 5);
+    }
+
+    #[test]
+    fn exports_csv_and_ledger() {
+        let invoices = [
+            archived("(0/1,100)", 250_000_000_000, 1_792_411_200),
+            archived("(0/2,100)", 0, 1_792_411_200),
+        ];
+
+        let csv = export(&invoices, ExportFormat::Csv);
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("\"(0/1,100)\",0/1,confirmed,84Ph3RzZ,0.250000000000,"));
+        assert!(lines[1].contains(",2026-10-19T12:00:00Z,"));
+        assert!(
+            lines[1].ends_with(r#""{""email"":""alice@example.com"",""message"":""Hi, there!""}""#)
+        );
+
+        // Unpaid invoices have nothing to book.
+        assert_eq!(
+            export(&invoices, ExportFormat::Ledger),
+            "2026-10-19 * AcceptXMR invoice (0/1,100)
+    ; outcome: confirmed
+    ; address: 84Ph3RzZ
+    ; email: alice@example.com
+    Assets:Monero:AcceptXMR  0.250000000000 XMR
+    Income:AcceptXMR
+
+"
+        );
+    }
+}
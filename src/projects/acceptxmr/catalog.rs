@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+use shared::{format_cents, LockedRate};
+
+use super::exchange_rate::cents_to_piconeros;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CatalogSettings {
+    /// Currency prices are in, converted to XMR at checkout.
+    pub currency: String,
+    /// Items that can be checked out. Each invoice is for a quantity of one of them.
+    pub items: Vec<CatalogItem>,
+    /// Largest quantity of an item allowed in one invoice.
+    pub max_quantity: u32,
+}
+
+impl Default for CatalogSettings {
+    fn default() -> Self {
+        Self {
+            currency: "USD".to_string(),
+            items: vec![CatalogItem {
+                id: "demo".to_string(),
+                name: "AcceptXMR Demo".to_string(),
+                price_cents: 25,
+                confirmations: 2,
+                expiration_blocks: 5,
+            }],
+            max_quantity: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CatalogItem {
+    pub id: String,
+    pub name: String,
+    /// Price of one item, in hundredths of the catalog's currency.
+    pub price_cents: u64,
+    /// Confirmations required before an invoice for the item counts as paid.
+    pub confirmations: u64,
+    /// Blocks until an unpaid invoice for the item expires.
+    pub expiration_blocks: u64,
+}
+
+/// An item on an invoice, as stored in the invoice's description. The name and price are copied
+/// from the catalog at checkout, so later catalog changes don't affect existing invoices.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LineItem {
+    pub id: String,
+    pub name: String,
+    pub quantity: u32,
+    /// Price of one item, in hundredths of the currency.
+    pub unit_price_cents: u64,
+}
+
+/// What an invoice is created for.
+#[derive(Debug, PartialEq)]
+pub struct Order {
+    pub line_items: Vec<LineItem>,
+    /// Total, in hundredths of the currency.
+    pub total_cents: u64,
+    pub confirmations: u64,
+    pub expiration_blocks: u64,
+}
+
+impl CatalogSettings {
+    /// Price an order of `quantity` of the item with ID `item_id`. The error is meant for the
+    /// buyer.
+    pub fn order(&self, item_id: &str, quantity: u32) -> Result<Order, String> {
+        let item = self
+            .items
+            .iter()
+            .find(|item| item.id == item_id)
+            .ok_or("Unknown item.")?;
+        if quantity == 0 || quantity > self.max_quantity {
+            return Err(format!(
+                "Quantity must be between 1 and {}.",
+                self.max_quantity
+            ));
+        }
+        let total_cents = item
+            .price_cents
+            .checked_mul(quantity.into())
+            .ok_or("Order total is too large.")?;
+        Ok(Order {
+            line_items: vec![LineItem {
+                id: item.id.clone(),
+                name: item.name.clone(),
+                quantity,
+                unit_price_cents: item.price_cents,
+            }],
+            total_cents,
+            confirmations: item.confirmations,
+            expiration_blocks: item.expiration_blocks,
+        })
+    }
+}
+
+impl Order {
+    /// Lock in `rate`, the price of one XMR, returning it and the total in piconeros.
+    pub fn at_rate(&self, currency: &str, rate: f64) -> Result<(LockedRate, u64), String> {
+        let amount =
+            cents_to_piconeros(self.total_cents, rate).ok_or("Could not price this order.")?;
+        let locked = LockedRate {
+            currency: currency.to_string(),
+            rate,
+            total_cents: self.total_cents,
+        };
+        Ok((locked, amount))
+    }
+}
+
+/// One line per item, e.g. "2 x Sticker at 5.00 USD", for emails and notifications.
+pub fn summary(line_items: &[LineItem], currency: &str) -> String {
+    line_items
+        .iter()
+        .map(|line| {
+            format!(
+                "{} x {} at {} {currency}",
+                line.quantity,
+                line.name,
+                format_cents(line.unit_price_cents)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> CatalogSettings {
+        CatalogSettings {
+            currency: "USD".to_string(),
+            items: vec![CatalogItem {
+                id: "sticker".to_string(),
+                name: "Sticker".to_string(),
+                price_cents: 500,
+                confirmations: 1,
+                expiration_blocks: 10,
+            }],
+            max_quantity: 5,
+        }
+    }
+
+    #[test]
+    fn prices_orders_from_catalog() {
+        let order = catalog().order("sticker", 3).unwrap();
+        assert_eq!(order.total_cents, 1500);
+        assert_eq!((order.confirmations, order.expiration_blocks), (1, 10));
+        assert_eq!(summary(&order.line_items, "USD"), "3 x Sticker at 5.00 USD");
+
+        let (locked, amount) = order.at_rate("USD", 150.0).unwrap();
+        assert_eq!(amount, 100_000_000_000);
+        assert_eq!((locked.rate, locked.total_cents), (150.0, 1500));
+    }
+
+    #[test]
+    fn rejects_invalid_orders() {
+        let mut catalog = catalog();
+        assert_eq!(catalog.order("mug", 1), Err("Unknown item.".to_string()));
+        assert_eq!(
+            catalog.order("sticker", 0),
+            Err("Quantity must be between 1 and 5.".to_string())
+        );
+        assert!(catalog.order("sticker", 6).is_err());
+
+        catalog.items[0].price_cents = u64::MAX / 2;
+        assert_eq!(
+            catalog.order("sticker", 3),
+            Err("Order total is too large.".to_string())
+        );
+    }
+}
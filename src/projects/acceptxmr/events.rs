@@ -0,0 +1,294 @@
+use std::{collections::VecDeque, convert::Infallible, time::Duration};
+
+use acceptxmr::{storage::stores::Sqlite, Invoice, InvoiceId, PaymentGateway, Subscriber};
+use actix_session::Session;
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective},
+    rt::time::timeout,
+    web::{self, Bytes},
+    HttpRequest, HttpResponse,
+};
+use futures::stream;
+use shared::InvoiceUpdate;
+
+use super::{
+    close_reason, extensions::InvoiceExtensions, invoice_update, Expiry, NOT_TRACKED_REASON,
+};
+
+/// Time between keepalive comments, so proxies don't close an idle stream.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// How long browsers should wait before reconnecting a dropped stream, in milliseconds.
+const RETRY_MS: u32 = 5000;
+
+/// Invoice updates as server-sent events, for clients that can't use the websocket. Each update
+/// is an `update` event whose ID identifies the invoice's state, so a reconnecting client sending
+/// `Last-Event-ID` only gets the current state if it has changed. The stream ends with a `close`
+/// event once the invoice is complete, or once the server stops tracking it.
+#[get("/projects/acceptxmr/events")]
+async fn events(
+    session: Session,
+    req: HttpRequest,
+    payment_gateway: web::Data<PaymentGateway<Sqlite>>,
+    extensions: web::Data<InvoiceExtensions>,
+) -> HttpResponse {
+    let not_found = || {
+        HttpResponse::NotFound()
+            .append_header(CacheControl(vec![CacheDirective::NoStore]))
+            .finish()
+    };
+    let Ok(Some(invoice_id)) = session.get::<InvoiceId>("id") else {
+        return not_found();
+    };
+    // Subscribe before reading the invoice, so no update falls between the two.
+    let Some(subscriber) = payment_gateway.subscribe(invoice_id) else {
+        return not_found();
+    };
+    let Ok(Some(invoice)) = payment_gateway.get_invoice(invoice_id).await else {
+        return not_found();
+    };
+
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string);
+    let mut events = EventStream::new(subscriber, extensions.get_ref().clone(), last_event_id);
+    events.push_update(&invoice);
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        // Ask nginx not to buffer the stream.
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(stream::unfold(events, |mut events| async move {
+            let chunk = events.next().await?;
+            Some((Ok::<_, Infallible>(chunk), events))
+        }))
+}
+
+struct EventStream {
+    subscriber: Subscriber,
+    extensions: InvoiceExtensions,
+    queue: EventQueue,
+}
+
+impl EventStream {
+    fn new(subscriber: Subscriber, extensions: InvoiceExtensions, last_id: Option<String>) -> Self {
+        Self {
+            subscriber,
+            extensions,
+            queue: EventQueue::new(last_id),
+        }
+    }
+
+    /// Queue an update, unless the client has already seen this state.
+    fn push_update(&mut self, invoice: &Invoice) {
+        let update = invoice_update(invoice, Expiry::of(invoice, &self.extensions));
+        self.queue.push(&invoice.id(), &update);
+    }
+
+    /// The next chunk to send, waiting for an update or keepalive if none is queued.
+    async fn next(&mut self) -> Option<Bytes> {
+        loop {
+            match self.queue.pop() {
+                Next::Send(chunk) => return Some(chunk),
+                Next::End => return None,
+                Next::Wait => {}
+            }
+            match timeout(KEEPALIVE_INTERVAL, self.subscriber.recv()).await {
+                Ok(Some(invoice)) => self.push_update(&invoice),
+                Ok(None) => self.queue.close(NOT_TRACKED_REASON),
+                Err(_) => return Some(Bytes::from_static(b": keepalive\n\n")),
+            }
+        }
+    }
+}
+
+/// The events waiting to be sent on a stream.
+struct EventQueue {
+    /// Chunks ready to be sent.
+    queued: VecDeque<Bytes>,
+    /// ID of the last update the client has seen.
+    last_id: Option<String>,
+    /// Whether the stream ends once the queue is empty.
+    closing: bool,
+}
+
+/// What a stream does next, without waiting for the invoice to change.
+#[derive(Debug, PartialEq)]
+enum Next {
+    Send(Bytes),
+    End,
+    Wait,
+}
+
+impl EventQueue {
+    fn new(last_id: Option<String>) -> Self {
+        Self {
+            queued: VecDeque::from([Bytes::from(format!("retry: {RETRY_MS}\n\n"))]),
+            last_id,
+            closing: false,
+        }
+    }
+
+    /// Queue an update, unless the client has already seen this state.
+    fn push(&mut self, invoice_id: &InvoiceId, update: &InvoiceUpdate) {
+        let id = event_id(invoice_id, update);
+        if self.last_id.as_deref() != Some(id.as_str()) {
+            let data = serde_json::to_string(update).expect("Invoice updates always serialize");
+            self.queued.push_back(event(Some(&id), "update", &data));
+            self.last_id = Some(id);
+        }
+        // Close the same way the websocket does.
+        if let Some(reason) = close_reason(update.status) {
+            self.close(reason);
+        }
+    }
+
+    /// Queue the `close` event, ending the stream once it is sent.
+    fn close(&mut self, reason: &str) {
+        if !self.closing {
+            self.queued.push_back(event(None, "close", reason));
+            self.closing = true;
+        }
+    }
+
+    fn pop(&mut self) -> Next {
+        match self.queued.pop_front() {
+            Some(chunk) => Next::Send(chunk),
+            None if self.closing => Next::End,
+            None => Next::Wait,
+        }
+    }
+}
+
+/// Identifies the state of an invoice: everything an update shows changes with one of these.
+fn event_id(invoice_id: &InvoiceId, update: &InvoiceUpdate) -> String {
+    format!(
+        "{invoice_id}/{}/{}/{}",
+        update.expiration_in,
+        update.amount_paid,
+        update.confirmations.unwrap_or_default()
+    )
+}
+
+/// Format one server-sent event. `data` must not contain line breaks.
+fn event(id: Option<&str>, name: &str, data: &str) -> Bytes {
+    let id = id.map(|id| format!("id: {id}\n")).unwrap_or_default();
+    Bytes::from(format!("{id}event: {name}\ndata: {data}\n\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{InvoiceStatus, INVOICE_UPDATE_VERSION};
+
+    use super::*;
+
+    fn update(status: InvoiceStatus, amount_paid: u64) -> InvoiceUpdate {
+        InvoiceUpdate {
+            version: INVOICE_UPDATE_VERSION,
+            status,
+            address: "address".to_string(),
+            amount_paid,
+            amount_requested: 100,
+            uri: "monero:address".to_string(),
+            confirmations: None,
+            confirmations_required: 2,
+            expiration_in: 5,
+            exchange_rate: None,
+        }
+    }
+
+    /// Everything queued, up to the point the stream would wait or end.
+    fn drain(queue: &mut EventQueue) -> (Vec<Bytes>, Next) {
+        let mut chunks = Vec::new();
+        loop {
+            match queue.pop() {
+                Next::Send(chunk) => chunks.push(chunk),
+                next => return (chunks, next),
+            }
+        }
+    }
+
+    #[test]
+    fn skips_state_client_has_seen() {
+        let invoice_id = InvoiceId::from(1);
+        let pending = update(InvoiceStatus::Pending, 0);
+        let seen = event_id(&invoice_id, &pending);
+
+        let mut queue = EventQueue::new(Some(seen.clone()));
+        queue.push(&invoice_id, &pending);
+        let (chunks, next) = drain(&mut queue);
+        assert_eq!(chunks, [Bytes::from(format!("retry: {RETRY_MS}\n\n"))]);
+        assert_eq!(next, Next::Wait);
+
+        // A changed state is sent, and becomes the one the client has seen.
+        let underpaid = update(InvoiceStatus::Underpaid, 50);
+        queue.push(&invoice_id, &underpaid);
+        queue.push(&invoice_id, &underpaid);
+        let (chunks, _) = drain(&mut queue);
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0]
+            .starts_with(format!("id: {}\n", event_id(&invoice_id, &underpaid)).as_bytes()));
+    }
+
+    #[test]
+    fn ends_with_close_event() {
+        let invoice_id = InvoiceId::from(1);
+        let mut queue = EventQueue::new(None);
+        queue.push(&invoice_id, &update(InvoiceStatus::Pending, 0));
+        let (chunks, next) = drain(&mut queue);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(next, Next::Wait);
+
+        queue.push(&invoice_id, &update(InvoiceStatus::Confirmed, 100));
+        let (chunks, next) = drain(&mut queue);
+        assert!(chunks[0].starts_with(b"id: "));
+        assert_eq!(chunks[1], event(None, "close", "Invoice Complete"));
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(next, Next::End);
+        // Once closing, the stream stays ended.
+        assert_eq!(queue.pop(), Next::End);
+    }
+
+    #[test]
+    fn closes_when_seen_state_is_final() {
+        let invoice_id = InvoiceId::from(1);
+        let confirmed = update(InvoiceStatus::Confirmed, 100);
+        let mut queue = EventQueue::new(Some(event_id(&invoice_id, &confirmed)));
+        queue.push(&invoice_id, &confirmed);
+        let (chunks, next) = drain(&mut queue);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1], event(None, "close", "Invoice Complete"));
+        assert_eq!(next, Next::End);
+    }
+
+    #[test]
+    fn stays_open_for_late_payments() {
+        let invoice_id = InvoiceId::from(1);
+        let mut queue = EventQueue::new(None);
+        queue.push(&invoice_id, &update(InvoiceStatus::ExpiredUnderpaid, 50));
+        let (chunks, next) = drain(&mut queue);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(next, Next::Wait);
+
+        // Once the invoice is no longer tracked, the stream says so and ends.
+        queue.close(NOT_TRACKED_REASON);
+        let (chunks, next) = drain(&mut queue);
+        assert_eq!(chunks, [event(None, "close", NOT_TRACKED_REASON)]);
+        assert_eq!(next, Next::End);
+    }
+
+    #[test]
+    fn formats_events() {
+        assert_eq!(
+            event(Some("1/5/0/0"), "update", r#"{"version":1}"#),
+            Bytes::from_static(b"id: 1/5/0/0\nevent: update\ndata: {\"version\":1}\n\n")
+        );
+        assert_eq!(
+            event(None, "close", "Invoice Expired"),
+            Bytes::from_static(b"event: close\ndata: Invoice Expired\n\n")
+        );
+    }
+}
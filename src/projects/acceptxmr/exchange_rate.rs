@@ -0,0 +1,362 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{BoxFuture, FutureExt},
+    lock::Mutex as AsyncMutex,
+};
+use log::warn;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Time allowed for the price feed to answer.
+const FEED_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Replaced with the catalog's currency in a feed's `url` and `pointer`.
+const CURRENCY_PLACEHOLDER: &str = "{currency}";
+
+/// Where the price of XMR in the catalog's currency comes from.
+#[derive(Deserialize, Clone)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum ExchangeRateSettings {
+    /// The same rate every time.
+    Fixed { rate: f64 },
+    /// A JSON price feed, with its last answer reused for `refresh_secs` and used as a fallback
+    /// for up to `max_age_secs` while the feed is down. `{currency}` in `url` and `pointer` is
+    /// replaced with the catalog's currency in lower case, so the two can't disagree.
+    Http {
+        url: String,
+        /// JSON pointer to the price in the feed's response, e.g. `/monero/{currency}`.
+        pointer: String,
+        #[serde(default = "default_refresh_secs")]
+        refresh_secs: u64,
+        #[serde(default = "default_max_age_secs")]
+        max_age_secs: u64,
+    },
+}
+
+impl Default for ExchangeRateSettings {
+    fn default() -> Self {
+        Self::Http {
+            url:
+                "https://api.coingecko.com/api/v3/simple/price?ids=monero&vs_currencies={currency}"
+                    .to_string(),
+            pointer: "/monero/{currency}".to_string(),
+            refresh_secs: default_refresh_secs(),
+            max_age_secs: default_max_age_secs(),
+        }
+    }
+}
+
+impl ExchangeRateSettings {
+    /// Check that a feed is told which currency to quote, rather than quietly pricing the catalog
+    /// in whatever currency its URL happens to ask for.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::Fixed { .. } => Ok(()),
+            Self::Http { url, pointer, .. } => {
+                if url.contains(CURRENCY_PLACEHOLDER) || pointer.contains(CURRENCY_PLACEHOLDER) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "exchange rate feed must use {CURRENCY_PLACEHOLDER} in its url or pointer"
+                    ))
+                }
+            }
+        }
+    }
+}
+
+fn default_refresh_secs() -> u64 {
+    60
+}
+
+fn default_max_age_secs() -> u64 {
+    15 * 60
+}
+
+/// A source of the price of one XMR, in the catalog's currency.
+pub trait ExchangeRateProvider: Send + Sync {
+    fn rate(&self) -> BoxFuture<'_, io::Result<f64>>;
+}
+
+/// Build the provider described by `settings`, quoting prices in `currency`.
+pub fn provider(settings: &ExchangeRateSettings, currency: &str) -> Arc<dyn ExchangeRateProvider> {
+    match settings {
+        ExchangeRateSettings::Fixed { rate } => Arc::new(FixedRate(*rate)),
+        ExchangeRateSettings::Http {
+            url,
+            pointer,
+            refresh_secs,
+            max_age_secs,
+        } => Arc::new(CachedRate::new(
+            PriceFeed::new(
+                with_currency(url, currency),
+                with_currency(pointer, currency),
+            ),
+            Duration::from_secs(*refresh_secs),
+            Duration::from_secs(*max_age_secs),
+        )),
+    }
+}
+
+fn with_currency(template: &str, currency: &str) -> String {
+    template.replace(CURRENCY_PLACEHOLDER, &currency.to_lowercase())
+}
+
+pub struct FixedRate(pub f64);
+
+impl ExchangeRateProvider for FixedRate {
+    fn rate(&self) -> BoxFuture<'_, io::Result<f64>> {
+        let rate = checked(self.0);
+        async move { rate }.boxed()
+    }
+}
+
+/// Fetches the rate from a JSON HTTP API on every call.
+pub struct PriceFeed {
+    client: Client,
+    url: String,
+    pointer: String,
+}
+
+impl PriceFeed {
+    pub fn new(url: String, pointer: String) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            pointer,
+        }
+    }
+
+    async fn fetch(&self) -> io::Result<f64> {
+        let response: Value = self
+            .client
+            .get(&self.url)
+            .timeout(FEED_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io::Error::other)?
+            .json()
+            .await
+            .map_err(io::Error::other)?;
+        // Some feeds send prices as strings, to avoid rounding them.
+        let rate = match response.pointer(&self.pointer) {
+            Some(Value::Number(rate)) => rate.as_f64(),
+            Some(Value::String(rate)) => rate.parse().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| io::Error::other(format!("no price at {} in feed", self.pointer)))?;
+        checked(rate)
+    }
+}
+
+impl ExchangeRateProvider for PriceFeed {
+    fn rate(&self) -> BoxFuture<'_, io::Result<f64>> {
+        self.fetch().boxed()
+    }
+}
+
+/// Reuses another provider's last rate for `refresh`, and falls back to it for up to `max_age`
+/// when the provider fails. Only one caller at a time asks the provider, so a stale rate is
+/// fetched once however many checkouts are waiting on it.
+pub struct CachedRate<P> {
+    inner: P,
+    refresh: Duration,
+    max_age: Duration,
+    last: Mutex<Option<(f64, Instant)>>,
+    /// Held while asking the provider.
+    asking: AsyncMutex<()>,
+    /// Times the provider has answered, so callers that waited can tell they were answered for.
+    answers: AtomicU64,
+}
+
+impl<P: ExchangeRateProvider> CachedRate<P> {
+    pub fn new(inner: P, refresh: Duration, max_age: Duration) -> Self {
+        Self {
+            inner,
+            refresh,
+            max_age,
+            last: Mutex::new(None),
+            asking: AsyncMutex::new(()),
+            answers: AtomicU64::new(0),
+        }
+    }
+
+    /// The last rate, if it was fetched less than `age` ago.
+    fn last_within(&self, age: Duration) -> Option<f64> {
+        let last = *self.last.lock().unwrap();
+        last.filter(|(_, fetched)| fetched.elapsed() < age)
+            .map(|(rate, _)| rate)
+    }
+
+    async fn cached_rate(&self) -> io::Result<f64> {
+        if let Some(rate) = self.last_within(self.refresh) {
+            return Ok(rate);
+        }
+        let answers = self.answers.load(Ordering::SeqCst);
+        let _asking = self.asking.lock().await;
+        // Whoever held the lock just asked, so use their answer rather than asking again.
+        if self.answers.load(Ordering::SeqCst) != answers {
+            return self
+                .last_within(self.max_age)
+                .ok_or_else(|| io::Error::other("exchange rate unavailable"));
+        }
+        let rate = self.inner.rate().await;
+        self.answers.fetch_add(1, Ordering::SeqCst);
+        match rate {
+            Ok(rate) => {
+                *self.last.lock().unwrap() = Some((rate, Instant::now()));
+                Ok(rate)
+            }
+            Err(e) => match self.last_within(self.max_age) {
+                Some(rate) => {
+                    warn!("Exchange rate unavailable, using the last one: {e}");
+                    Ok(rate)
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+impl<P: ExchangeRateProvider> ExchangeRateProvider for CachedRate<P> {
+    fn rate(&self) -> BoxFuture<'_, io::Result<f64>> {
+        self.cached_rate().boxed()
+    }
+}
+
+fn checked(rate: f64) -> io::Result<f64> {
+    if rate.is_finite() && rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err(io::Error::other(format!("invalid exchange rate {rate}")))
+    }
+}
+
+/// Convert an amount in cents to piconeros at `rate`, rounding to the nearest piconero.
+pub fn cents_to_piconeros(cents: u64, rate: f64) -> Option<u64> {
+    let piconeros = (cents as f64 / 100.0 / rate * 1e12).round();
+    // Casts saturate, so check the range first.
+    (piconeros >= 1.0 && piconeros < u64::MAX as f64).then_some(piconeros as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use actix_web::HttpResponse;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_server::stand_in;
+
+    /// Answers with a rate on its first call only.
+    struct Flaky(AtomicUsize);
+
+    impl ExchangeRateProvider for Flaky {
+        fn rate(&self) -> BoxFuture<'_, io::Result<f64>> {
+            let calls = self.0.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match calls {
+                    0 => Ok(150.0),
+                    _ => Err(io::Error::other("feed down")),
+                }
+            }
+            .boxed()
+        }
+    }
+
+    /// Answers slowly, counting how often it is asked.
+    struct Slow(AtomicUsize);
+
+    impl ExchangeRateProvider for Slow {
+        fn rate(&self) -> BoxFuture<'_, io::Result<f64>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            async move {
+                actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+                Ok(150.0)
+            }
+            .boxed()
+        }
+    }
+
+    #[actix_web::test]
+    async fn reads_rate_from_feed() {
+        let (base, _) = stand_in(|req| match req.path() {
+            "/number" => HttpResponse::Ok().json(json!({"xmr": {"usd": 151.5}})),
+            "/string" => HttpResponse::Ok().json(json!({"price": "149.25"})),
+            _ => HttpResponse::NotFound().finish(),
+        });
+
+        let feed = |path: &str, pointer: &str| {
+            PriceFeed::new(format!("{base}{path}"), pointer.to_string())
+        };
+        assert_eq!(feed("/number", "/xmr/usd").rate().await.unwrap(), 151.5);
+        assert_eq!(feed("/string", "/price").rate().await.unwrap(), 149.25);
+        assert!(feed("/number", "/xmr/eur").rate().await.is_err());
+        assert!(feed("/missing", "/price").rate().await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn falls_back_to_recent_rate() {
+        let cached = CachedRate::new(
+            Flaky(AtomicUsize::new(0)),
+            Duration::ZERO,
+            Duration::from_secs(60),
+        );
+        assert_eq!(cached.rate().await.unwrap(), 150.0);
+        assert_eq!(cached.rate().await.unwrap(), 150.0);
+
+        let stale = CachedRate::new(Flaky(AtomicUsize::new(0)), Duration::ZERO, Duration::ZERO);
+        assert_eq!(stale.rate().await.unwrap(), 150.0);
+        assert!(stale.rate().await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn fetches_once_for_concurrent_callers() {
+        let cached = CachedRate::new(
+            Slow(AtomicUsize::new(0)),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        );
+        let rates = futures::future::join_all((0..5).map(|_| cached.rate())).await;
+        assert!(rates.iter().all(|rate| rate.as_ref().unwrap() == &150.0));
+        assert_eq!(cached.inner.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn feed_quotes_catalog_currency() {
+        let settings = ExchangeRateSettings::default();
+        assert!(settings.validate().is_ok());
+        let ExchangeRateSettings::Http { url, pointer, .. } = &settings else {
+            panic!("default provider is a feed");
+        };
+        assert!(with_currency(url, "EUR").ends_with("vs_currencies=eur"));
+        assert_eq!(with_currency(pointer, "EUR"), "/monero/eur");
+
+        let fixed_currency = ExchangeRateSettings::Http {
+            url: "https://example.com/price?vs=usd".to_string(),
+            pointer: "/monero/usd".to_string(),
+            refresh_secs: 60,
+            max_age_secs: 900,
+        };
+        assert!(fixed_currency.validate().is_err());
+    }
+
+    #[test]
+    fn converts_cents_to_piconeros() {
+        assert_eq!(cents_to_piconeros(15_000, 150.0), Some(1_000_000_000_000));
+        assert_eq!(cents_to_piconeros(1, 300.0), Some(33_333_333));
+        assert_eq!(cents_to_piconeros(0, 150.0), None);
+        assert_eq!(cents_to_piconeros(u64::MAX, 1e-12), None);
+    }
+}
@@ -0,0 +1,82 @@
+use std::convert::{TryFrom, TryInto};
+
+use sqlite::{State, Value};
+use time::OffsetDateTime;
+
+use crate::db::Db;
+
+/// Extra blocks granted to invoices from the admin dashboard. The payment gateway keeps watching
+/// an invoice until it is removed, so expiry is up to us and extending an invoice only needs to be
+/// recorded here.
+#[derive(Clone)]
+pub struct InvoiceExtensions {
+    db: Db,
+}
+
+impl InvoiceExtensions {
+    pub fn open(db: Db) -> Result<InvoiceExtensions, sqlite::Error> {
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS invoice_extensions (
+                invoice_id TEXT PRIMARY KEY,
+                blocks     INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            );",
+        )?;
+        Ok(InvoiceExtensions { db })
+    }
+
+    /// Blocks the invoice has been extended by in total.
+    pub fn blocks(&self, invoice_id: &str) -> Result<u64, sqlite::Error> {
+        let mut statement = self
+            .db
+            .prepare("SELECT blocks FROM invoice_extensions WHERE invoice_id = :invoice_id")?;
+        statement.bind((":invoice_id", invoice_id))?;
+        if statement.next()? == State::Row {
+            Ok(statement
+                .read::<i64, _>("blocks")?
+                .try_into()
+                .unwrap_or_default())
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Extend the invoice by `blocks` more, returning its total extension.
+    pub fn extend(&self, invoice_id: &str, blocks: u64) -> Result<u64, sqlite::Error> {
+        let mut statement = self.db.prepare(
+            "INSERT INTO invoice_extensions (invoice_id, blocks, updated_at)
+            VALUES (:invoice_id, :blocks, :now)
+            ON CONFLICT (invoice_id) DO UPDATE
+            SET blocks = blocks + excluded.blocks, updated_at = excluded.updated_at
+            RETURNING blocks",
+        )?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":invoice_id", invoice_id.into()),
+                (":blocks", i64::try_from(blocks).unwrap_or(i64::MAX).into()),
+                (":now", OffsetDateTime::now_utc().unix_timestamp().into()),
+            ][..],
+        )?;
+        statement.next()?;
+        Ok(statement
+            .read::<i64, _>("blocks")?
+            .try_into()
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn extensions_add_up() {
+        let extensions = InvoiceExtensions::open(db::open_path(":memory:").unwrap()).unwrap();
+        assert_eq!(extensions.blocks("(0/1,100)").unwrap(), 0);
+        assert_eq!(extensions.extend("(0/1,100)", 5).unwrap(), 5);
+        assert_eq!(extensions.extend("(0/1,100)", 10).unwrap(), 15);
+        assert_eq!(extensions.blocks("(0/1,100)").unwrap(), 15);
+        assert_eq!(extensions.blocks("(0/2,100)").unwrap(), 0);
+    }
+}
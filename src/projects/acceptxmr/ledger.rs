@@ -0,0 +1,119 @@
+use sqlite::{State, Value};
+use time::OffsetDateTime;
+
+use crate::db::Db;
+
+/// A state change of an invoice that is announced by email or webhook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transition {
+    Created,
+    /// The first payment towards the invoice was seen.
+    PaymentSeen,
+    Confirmed,
+    /// Expired before it was paid in full.
+    Expired,
+    /// Expired with part of the amount paid.
+    ExpiredUnderpaid,
+}
+
+impl Transition {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::PaymentSeen => "payment_seen",
+            Self::Confirmed => "confirmed",
+            Self::Expired => "expired",
+            Self::ExpiredUnderpaid => "expired_underpaid",
+        }
+    }
+}
+
+/// Records which invoice transitions have been notified, so that repeated updates for the same
+/// invoice, or updates replayed after a restart, don't send the same emails or webhooks twice.
+/// A transition is only recorded once everything about it is queued, and what is queued is keyed
+/// by the transition, so a failure part way through is retried without queueing anything twice.
+#[derive(Clone)]
+pub struct NotificationLedger {
+    db: Db,
+}
+
+impl NotificationLedger {
+    pub fn open(db: Db) -> Result<NotificationLedger, sqlite::Error> {
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS invoice_notifications (
+                invoice_id  TEXT NOT NULL,
+                transition  TEXT NOT NULL,
+                notified_at INTEGER NOT NULL,
+                PRIMARY KEY (invoice_id, transition)
+            );",
+        )?;
+        Ok(NotificationLedger { db })
+    }
+
+    /// Whether `transition` of the invoice has already been notified.
+    pub fn is_recorded(
+        &self,
+        invoice_id: &str,
+        transition: Transition,
+    ) -> Result<bool, sqlite::Error> {
+        let mut statement = self.db.prepare(
+            "SELECT 1 FROM invoice_notifications
+            WHERE invoice_id = :invoice_id AND transition = :transition",
+        )?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":invoice_id", invoice_id.into()),
+                (":transition", transition.as_str().into()),
+            ][..],
+        )?;
+        Ok(statement.next()? == State::Row)
+    }
+
+    /// Record that `transition` of the invoice has been notified. Returns `false` if it already
+    /// was.
+    pub fn record(&self, invoice_id: &str, transition: Transition) -> Result<bool, sqlite::Error> {
+        let mut statement = self.db.prepare(
+            "INSERT INTO invoice_notifications (invoice_id, transition, notified_at)
+            VALUES (:invoice_id, :transition, :now)
+            ON CONFLICT (invoice_id, transition) DO NOTHING
+            RETURNING invoice_id",
+        )?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                (":invoice_id", invoice_id.into()),
+                (":transition", transition.as_str().into()),
+                (":now", OffsetDateTime::now_utc().unix_timestamp().into()),
+            ][..],
+        )?;
+        Ok(statement.next()? == State::Row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn records_each_transition_once() {
+        let db = db::open_path(":memory:").unwrap();
+        let ledger = NotificationLedger::open(db.clone()).unwrap();
+        assert!(!ledger.is_recorded("1-100", Transition::Confirmed).unwrap());
+        assert!(ledger.record("1-100", Transition::Confirmed).unwrap());
+        assert!(ledger.is_recorded("1-100", Transition::Confirmed).unwrap());
+        assert!(!ledger.record("1-100", Transition::Confirmed).unwrap());
+        assert!(ledger.record("2-100", Transition::Confirmed).unwrap());
+        assert!(!ledger
+            .is_recorded("1-100", Transition::ExpiredUnderpaid)
+            .unwrap());
+        assert!(ledger
+            .record("1-100", Transition::ExpiredUnderpaid)
+            .unwrap());
+
+        // Records outlive the ledger, as they would a restart.
+        let reopened = NotificationLedger::open(db).unwrap();
+        assert!(reopened
+            .is_recorded("1-100", Transition::Confirmed)
+            .unwrap());
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderMap},
+    middleware::Next,
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use log::warn;
+use lru::LruCache;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::metrics::Metrics;
+
+/// Most buckets kept at once. Past this, the least recently used bucket is dropped, so a flood
+/// from many addresses can't grow the limiter without bound.
+const MAX_TRACKED_CLIENTS: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Peers allowed to report the client's address in `Forwarded` or `X-Forwarded-For`.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Limits by request path. Paths not listed here are not limited.
+    pub routes: BTreeMap<String, BucketSettings>,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            trusted_proxies: vec![
+                IpAddr::from([127, 0, 0, 1]),
+                IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]),
+            ],
+            routes: BTreeMap::from([
+                (
+                    "/api/generate_captcha".to_string(),
+                    BucketSettings {
+                        burst: 20,
+                        per_minute: 10.0,
+                        group: None,
+                    },
+                ),
+                ("/api/contact".to_string(), BucketSettings::contact()),
+                ("/contact-submitted".to_string(), BucketSettings::contact()),
+                (
+                    "/projects/acceptxmr/checkout".to_string(),
+                    BucketSettings {
+                        burst: 5,
+                        per_minute: 2.0,
+                        group: None,
+                    },
+                ),
+            ]),
+        }
+    }
+}
+
+/// A token bucket: a client may make `burst` requests at once, then `per_minute` on average.
+#[derive(Deserialize, Clone)]
+pub struct BucketSettings {
+    pub burst: u32,
+    pub per_minute: f64,
+    /// Routes with the same group share one bucket per client, so clients can't get around a
+    /// limit by switching between routes that do the same work. Each route has its own bucket if
+    /// unset.
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl BucketSettings {
+    /// Shared by the contact form and the contact API, which both submit the form.
+    fn contact() -> Self {
+        Self {
+            burst: 5,
+            per_minute: 1.0,
+            group: Some("contact".to_string()),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Take a token if there is one, otherwise return how long until there will be.
+    fn take(&mut self, settings: &BucketSettings, now: Instant) -> Result<(), Duration> {
+        let per_sec = settings.per_minute / 60.0;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(f64::from(settings.burst));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if per_sec > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_sec))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+}
+
+/// Per-client token buckets for each route or group of routes.
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    buckets: Mutex<LruCache<(String, IpAddr), Bucket>>,
+}
+
+/// The client's address, taken from proxy headers only when the peer is a trusted proxy. Set on
+/// every request by [`rate_limit`].
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    pub fn of(req: &HttpRequest) -> Option<IpAddr> {
+        req.extensions().get::<ClientIp>().map(|ip| ip.0)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        Self {
+            settings,
+            buckets: Mutex::new(LruCache::new(MAX_TRACKED_CLIENTS)),
+        }
+    }
+
+    /// Take a token for a request to `path` from `ip`. Returns how long the client should wait if
+    /// it is over the limit.
+    fn check(&self, path: &str, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let Some(settings) = self.settings.routes.get(path) else {
+            return Ok(());
+        };
+        let bucket = settings.group.as_deref().unwrap_or(path);
+        let mut buckets = self
+            .buckets
+            .lock()
+            .expect("Unable to get lock on rate limiter");
+        buckets
+            .get_or_insert_mut((bucket.to_string(), client_key(ip)), || Bucket {
+                tokens: f64::from(settings.burst),
+                updated: now,
+            })
+            .take(settings, now)
+    }
+
+    /// Work out the client's address. Proxy headers are followed from the nearest hop outward,
+    /// for as long as each hop is a trusted proxy.
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let trusted = |ip: &IpAddr| self.settings.trusted_proxies.contains(ip);
+        if !trusted(&peer) {
+            return peer;
+        }
+        let mut client = peer;
+        for hop in forwarded_for(headers).into_iter().rev() {
+            // Anything we can't parse (e.g. "unknown" or an obfuscated identifier) ends the chain.
+            let Some(ip) = parse_node(&hop) else {
+                break;
+            };
+            client = ip;
+            if !trusted(&ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// The address a client is limited by. IPv6 clients are usually handed a whole /64, so they are
+/// limited by that rather than by an address they can rotate freely.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let [a, b, c, d, ..] = v6.segments();
+                IpAddr::V6(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0))
+            }
+        },
+    }
+}
+
+/// Addresses listed in the `Forwarded` header, or `X-Forwarded-For` if there is none, nearest
+/// client first.
+fn forwarded_for(headers: &HeaderMap) -> Vec<String> {
+    let forwarded: Vec<String> = headers
+        .get_all(header::FORWARDED)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| value.trim().trim_matches('"').to_string())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().to_string())
+        .collect()
+}
+
+/// Parse an address as found in proxy headers: `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` or
+/// `[2001:db8::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| {
+            node.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+        })
+        .ok()
+}
+
+/// Middleware recording each client's address and limiting how often it may call the routes
+/// configured in [`RateLimitSettings`].
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let limiter = req
+        .app_data::<web::Data<RateLimiter>>()
+        .expect("Rate limiter not registered")
+        .clone();
+    let Some(peer) = req.peer_addr().map(|addr| addr.ip()) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let ip = limiter.client_ip(peer, req.headers());
+    req.extensions_mut().insert(ClientIp(ip));
+
+    let path = req.path().to_string();
+    if let Err(wait) = limiter.check(&path, ip, Instant::now()) {
+        warn!("Rate limited {ip} on {path}");
+        if let Some(metrics) = req.app_data::<web::Data<Metrics>>() {
+            metrics.increment("rate_limited_total", &[("route", &path)]);
+        }
+        // Round up, so the client doesn't come back a moment too early.
+        let retry_after = wait.as_secs_f64().ceil().min(f64::from(u32::MAX)) as u64;
+        let response = HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+            .json(json!({
+                "error": "rate_limited",
+                "message": format!("Too many requests. Please try again in {retry_after} seconds."),
+            }));
+        return Ok(req.into_response(response));
+    }
+
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{
+            header::{HeaderName, HeaderValue},
+            StatusCode,
+        },
+        middleware::from_fn,
+        test::{call_service, init_service, TestRequest},
+        App,
+    };
+
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitSettings {
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            routes: BTreeMap::from([
+                (
+                    "/limited".to_string(),
+                    BucketSettings {
+                        burst: 2,
+                        per_minute: 6.0,
+                        group: None,
+                    },
+                ),
+                ("/form".to_string(), BucketSettings::contact()),
+                ("/api/form".to_string(), BucketSettings::contact()),
+            ]),
+        })
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static(name),
+            HeaderValue::from_static(value),
+        );
+        headers
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limiter = limiter();
+        let ip = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        assert!(limiter.check("/limited", ip, start).is_ok());
+        assert!(limiter.check("/limited", ip, start).is_ok());
+        assert_eq!(
+            limiter.check("/limited", ip, start),
+            Err(Duration::from_secs(10))
+        );
+        // Other clients and other routes have their own buckets.
+        assert!(limiter
+            .check("/limited", "192.0.2.2".parse().unwrap(), start)
+            .is_ok());
+        assert!(limiter.check("/unlimited", ip, start).is_ok());
+
+        assert!(limiter
+            .check("/limited", ip, start + Duration::from_secs(10))
+            .is_ok());
+    }
+
+    #[test]
+    fn grouped_routes_share_a_bucket() {
+        let limiter = limiter();
+        let ip = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        for path in ["/form", "/api/form"].iter().cycle().take(5) {
+            assert!(limiter.check(path, ip, start).is_ok());
+        }
+        assert!(limiter.check("/form", ip, start).is_err());
+        assert!(limiter.check("/api/form", ip, start).is_err());
+        // Ungrouped routes keep their own buckets.
+        assert!(limiter.check("/limited", ip, start).is_ok());
+    }
+
+    #[test]
+    fn ipv6_clients_share_a_bucket_per_64() {
+        let limiter = limiter();
+        let start = Instant::now();
+        assert!(limiter
+            .check("/limited", "2001:db8:0:1::1".parse().unwrap(), start)
+            .is_ok());
+        assert!(limiter
+            .check("/limited", "2001:db8:0:1::2".parse().unwrap(), start)
+            .is_ok());
+        assert!(limiter
+            .check("/limited", "2001:db8:0:1:ffff::3".parse().unwrap(), start)
+            .is_err());
+        assert!(limiter
+            .check("/limited", "2001:db8:0:2::1".parse().unwrap(), start)
+            .is_ok());
+        assert_eq!(
+            client_key("::ffff:192.0.2.1".parse().unwrap()),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn tracks_a_bounded_number_of_clients() {
+        let limiter = limiter();
+        let start = Instant::now();
+        for i in 0..MAX_TRACKED_CLIENTS.get() as u32 + 10 {
+            let ip = IpAddr::from((0x0a00_0000 + i).to_be_bytes());
+            assert!(limiter.check("/limited", ip, start).is_ok());
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_TRACKED_CLIENTS.get());
+        // The oldest clients were dropped to make room.
+        assert!(!buckets.contains(&("/limited".to_string(), IpAddr::from([10, 0, 0, 0]))));
+    }
+
+    #[test]
+    fn proxy_headers_are_only_trusted_from_proxies() {
+        let limiter = limiter();
+        let spoofed = headers("x-forwarded-for", "198.51.100.7");
+        let stranger = "203.0.113.9".parse().unwrap();
+        assert_eq!(limiter.client_ip(stranger, &spoofed), stranger);
+
+        let proxy = "10.0.0.1".parse().unwrap();
+        assert_eq!(
+            limiter.client_ip(proxy, &spoofed),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+
+        // A client can prepend whatever it likes, so only hops added by trusted proxies count.
+        let chained = headers("x-forwarded-for", "1.1.1.1, 198.51.100.7, 10.0.0.2");
+        assert_eq!(
+            limiter.client_ip(proxy, &chained),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+
+        let forwarded = headers(
+            "forwarded",
+            "for=1.1.1.1, for=\"[2001:db8::7]:4711\";proto=https",
+        );
+        assert_eq!(
+            limiter.client_ip(proxy, &forwarded),
+            "2001:db8::7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[actix_web::test]
+    async fn responds_with_retry_after() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(limiter()))
+                .wrap(from_fn(rate_limit))
+                .route("/limited", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = || {
+            TestRequest::get()
+                .uri("/limited")
+                .peer_addr("192.0.2.1:1234".parse().unwrap())
+                .to_request()
+        };
+        for _ in 0..2 {
+            let response = call_service(&app, request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = call_service(&app, request()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "10");
+    }
+}
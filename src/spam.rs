@@ -0,0 +1,370 @@
+use std::fmt;
+
+use actix_session::Session;
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective},
+    web, HttpResponse,
+};
+use log::error;
+use rand::random;
+use serde::Deserialize;
+use sqlite::{State, Value};
+use time::OffsetDateTime;
+
+use crate::db::{self, Db};
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SpamSettings {
+    /// Submissions sent sooner than this after the form was loaded are rejected.
+    pub min_fill_secs: i64,
+    /// Submissions sent later than this after the form was loaded are rejected.
+    pub max_form_age_secs: i64,
+    /// Submissions scoring at least this much are quarantined instead of emailed.
+    pub quarantine_score: u32,
+    /// Links allowed before they start counting towards the score.
+    pub free_links: usize,
+    /// Score added for each link past `free_links`.
+    pub link_score: u32,
+    /// Words or phrases that count towards the score, matched case-insensitively.
+    pub blocked_keywords: Vec<String>,
+    /// Score added for each blocked keyword found.
+    pub keyword_score: u32,
+    /// Fraction of letters outside the Latin script above which a submission looks like it was
+    /// written in a language I don't read.
+    pub foreign_script_ratio: f64,
+    /// Score added when `foreign_script_ratio` is exceeded.
+    pub foreign_script_score: u32,
+}
+
+impl Default for SpamSettings {
+    fn default() -> Self {
+        Self {
+            min_fill_secs: 5,
+            max_form_age_secs: 24 * 60 * 60,
+            quarantine_score: 5,
+            free_links: 1,
+            link_score: 2,
+            blocked_keywords: [
+                "backlinks",
+                "casino",
+                "crypto investment",
+                "seo services",
+                "viagra",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            keyword_score: 3,
+            foreign_script_ratio: 0.5,
+            foreign_script_score: 3,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenError {
+    /// The token is missing, malformed or wasn't signed by us.
+    Invalid,
+    /// The form was submitted faster than a human could fill it in.
+    TooFast,
+    Expired,
+    /// The token was already used for an accepted submission.
+    Reused,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Invalid => write!(f, "invalid form token"),
+            TokenError::TooFast => write!(f, "form submitted too quickly"),
+            TokenError::Expired => write!(f, "form token expired"),
+            TokenError::Reused => write!(f, "form token already used"),
+        }
+    }
+}
+
+/// Result of scoring a submission.
+#[derive(Debug, Default)]
+pub struct SpamCheck {
+    pub score: u32,
+    /// Why points were added, for the logs and the inbox.
+    pub reasons: Vec<String>,
+    pub quarantine: bool,
+}
+
+/// Session key holding a random value that form tokens issued to the session are bound to.
+const FORM_SESSION_KEY: &str = "form_session";
+
+/// Checks contact form submissions for signs of spam beyond the captcha.
+pub struct SpamFilter {
+    db: Db,
+    settings: SpamSettings,
+    key: [u8; 32],
+}
+
+impl SpamFilter {
+    pub fn new(db: &Db, settings: SpamSettings) -> Result<Self, sqlite::Error> {
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS used_form_tokens (
+                nonce   TEXT PRIMARY KEY,
+                used_at INTEGER NOT NULL
+            );",
+        )?;
+        Ok(Self {
+            db: db.clone(),
+            settings,
+            key: db::key(db, "form_token")?,
+        })
+    }
+
+    /// A signed token recording when the form was rendered, only valid within `session`.
+    pub fn issue_token(&self, session: &Session) -> String {
+        let binding = match session.get::<String>(FORM_SESSION_KEY).unwrap_or_default() {
+            Some(binding) => binding,
+            None => {
+                let binding = format!("{:032x}", random::<u128>());
+                if let Err(e) = session.insert(FORM_SESSION_KEY, &binding) {
+                    error!("Could not bind form token to session: {e}");
+                }
+                binding
+            }
+        };
+        let nonce = format!("{:032x}", random::<u128>());
+        self.token_at(now(), &nonce, &binding)
+    }
+
+    fn token_at(&self, rendered_at: i64, nonce: &str, binding: &str) -> String {
+        format!(
+            "{rendered_at}.{nonce}.{}",
+            self.mac(rendered_at, nonce, binding).to_hex()
+        )
+    }
+
+    fn mac(&self, rendered_at: i64, nonce: &str, binding: &str) -> blake3::Hash {
+        blake3::keyed_hash(
+            &self.key,
+            format!("{rendered_at}.{nonce}.{binding}").as_bytes(),
+        )
+    }
+
+    /// Check that a token is ours and was issued to this session, and that the form was filled in
+    /// at a human pace. Doesn't use the token up; see [`SpamFilter::consume_token`].
+    pub fn check_token(&self, token: &str, session: &Session) -> Result<(), TokenError> {
+        let binding = session
+            .get::<String>(FORM_SESSION_KEY)
+            .unwrap_or_default()
+            .ok_or(TokenError::Invalid)?;
+        self.check_token_at(token, &binding, now())
+    }
+
+    fn check_token_at(&self, token: &str, binding: &str, now: i64) -> Result<(), TokenError> {
+        let mut parts = token.splitn(3, '.');
+        let (Some(rendered_at), Some(nonce), Some(mac)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TokenError::Invalid);
+        };
+        let rendered_at: i64 = rendered_at.parse().map_err(|_| TokenError::Invalid)?;
+        let mac = blake3::Hash::from_hex(mac).map_err(|_| TokenError::Invalid)?;
+        // `blake3::Hash` comparisons are constant time.
+        if mac != self.mac(rendered_at, nonce, binding) {
+            return Err(TokenError::Invalid);
+        }
+        let age = now - rendered_at;
+        if age < self.settings.min_fill_secs {
+            Err(TokenError::TooFast)
+        } else if age > self.settings.max_form_age_secs {
+            Err(TokenError::Expired)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Use up a token that passed [`SpamFilter::check_token`], so it can't be replayed. Tokens
+    /// are remembered until they would have expired anyway.
+    pub fn consume_token(&self, token: &str) -> Result<(), TokenError> {
+        let nonce = token.split('.').nth(1).ok_or(TokenError::Invalid)?;
+        let now = now();
+        let consumed = self
+            .db
+            .prepare("DELETE FROM used_form_tokens WHERE used_at < :expired")
+            .and_then(|mut statement| {
+                statement.bind((
+                    ":expired",
+                    now.saturating_sub(self.settings.max_form_age_secs),
+                ))?;
+                while statement.next()? == State::Row {}
+
+                let mut statement = self.db.prepare(
+                    "INSERT INTO used_form_tokens (nonce, used_at) VALUES (:nonce, :now)
+                    ON CONFLICT (nonce) DO NOTHING
+                    RETURNING nonce",
+                )?;
+                statement
+                    .bind::<&[(_, Value)]>(&[(":nonce", nonce.into()), (":now", now.into())][..])?;
+                Ok(statement.next()? == State::Row)
+            });
+        match consumed {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(TokenError::Reused),
+            // Not being able to record the token shouldn't lose the sender's message.
+            Err(e) => {
+                error!("Could not record used form token: {e}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Score a submission. `honeypot` is the value of the form field hidden from humans, and
+    /// `fields` is everything the sender wrote.
+    pub fn check(&self, honeypot: &str, fields: &[&str]) -> SpamCheck {
+        let settings = &self.settings;
+        let mut check = SpamCheck::default();
+        if !honeypot.is_empty() {
+            check.score += settings.quarantine_score;
+            check.reasons.push("honeypot filled in".to_string());
+        }
+
+        let text = fields.join("\n").to_lowercase();
+        let links = text
+            .split_whitespace()
+            .filter(|word| {
+                word.contains("://") || word.starts_with("www.") || word.contains("[url")
+            })
+            .count();
+        if links > settings.free_links {
+            check.score += settings.link_score * (links - settings.free_links) as u32;
+            check.reasons.push(format!("{links} links"));
+        }
+
+        for keyword in &settings.blocked_keywords {
+            if text.contains(&keyword.to_lowercase()) {
+                check.score += settings.keyword_score;
+                check.reasons.push(format!("keyword {keyword:?}"));
+            }
+        }
+
+        let (letters, foreign) = text
+            .chars()
+            .filter(|c| c.is_alphabetic())
+            .fold((0, 0), |(letters, foreign), c| {
+                (letters + 1, foreign + usize::from(!is_latin(c)))
+            });
+        if letters > 0 && foreign as f64 / letters as f64 > settings.foreign_script_ratio {
+            check.score += settings.foreign_script_score;
+            check
+                .reasons
+                .push(format!("{foreign} of {letters} letters not Latin"));
+        }
+
+        check.quarantine = check.score >= settings.quarantine_score;
+        check
+    }
+}
+
+/// Whether a letter belongs to the Latin script, including accented letters.
+fn is_latin(c: char) -> bool {
+    c.is_ascii_alphabetic() || ('\u{00C0}'..='\u{024F}').contains(&c)
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// Token to include with a contact form submission, proving when the form was loaded.
+#[get("/api/contact_token")]
+async fn contact_token(spam_filter: web::Data<SpamFilter>, session: Session) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .content_type("text/plain; charset=utf-8")
+        .body(spam_filter.issue_token(&session))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> SpamFilter {
+        SpamFilter::new(&db::open_path(":memory:").unwrap(), SpamSettings::default()).unwrap()
+    }
+
+    #[test]
+    fn token_enforces_fill_time() {
+        let filter = filter();
+        let token = filter.token_at(1000, "n1", "session");
+        assert_eq!(
+            filter.check_token_at(&token, "session", 1002),
+            Err(TokenError::TooFast)
+        );
+        assert_eq!(filter.check_token_at(&token, "session", 1030), Ok(()));
+        assert_eq!(
+            filter.check_token_at(&token, "session", 1000 + 24 * 60 * 60 + 1),
+            Err(TokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn token_must_be_signed() {
+        let filter = filter();
+        let token = filter.token_at(1000, "n1", "session");
+        let forged = token.replacen("1000", "900", 1);
+        assert_eq!(
+            filter.check_token_at(&forged, "session", 1030),
+            Err(TokenError::Invalid)
+        );
+        assert_eq!(
+            filter.check_token_at("", "session", 1030),
+            Err(TokenError::Invalid)
+        );
+        assert_eq!(
+            filter.check_token_at("1000.n1.zz", "session", 1030),
+            Err(TokenError::Invalid)
+        );
+    }
+
+    #[test]
+    fn token_is_bound_to_session() {
+        let filter = filter();
+        let token = filter.token_at(1000, "n1", "session");
+        assert_eq!(
+            filter.check_token_at(&token, "other session", 1030),
+            Err(TokenError::Invalid)
+        );
+    }
+
+    #[test]
+    fn token_is_used_once() {
+        let filter = filter();
+        let token = filter.token_at(1000, "n1", "session");
+        assert_eq!(filter.consume_token(&token), Ok(()));
+        assert_eq!(filter.consume_token(&token), Err(TokenError::Reused));
+        let other = filter.token_at(1000, "n2", "session");
+        assert_eq!(filter.consume_token(&other), Ok(()));
+    }
+
+    #[test]
+    fn scores_spammy_content() {
+        let filter = filter();
+        let ham = filter.check("", &["Alice", "Hi", "See https://example.com, thanks!"]);
+        assert_eq!(ham.score, 0);
+        assert!(!ham.quarantine);
+
+        let spam = filter.check(
+            "",
+            &[
+                "Best CASINO",
+                "http://a.example http://b.example www.c.example",
+            ],
+        );
+        assert_eq!(spam.score, 4 + 3);
+        assert!(spam.quarantine);
+
+        let foreign = filter.check("", &["Привет", "Как дела?"]);
+        assert_eq!(foreign.reasons, ["13 of 13 letters not Latin"]);
+
+        let bot = filter.check("https://spam.example", &["Hi", "Hello"]);
+        assert!(bot.quarantine);
+        assert_eq!(bot.reasons, ["honeypot filled in"]);
+    }
+}
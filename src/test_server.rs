@@ -0,0 +1,48 @@
+use std::{net::TcpListener, sync::Mutex};
+
+use actix_web::{http::header::HeaderMap, web, App, HttpRequest, HttpResponse, HttpServer};
+
+/// A request received by a [`stand_in`] server.
+pub struct Received {
+    pub method: String,
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl Received {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+pub type Log = web::Data<Mutex<Vec<Received>>>;
+
+/// Start a local HTTP server that records every request and answers it with `respond`. Returns
+/// the server's base URL, e.g. `http://127.0.0.1:1234`, and the requests it has received.
+pub fn stand_in(respond: fn(&HttpRequest) -> HttpResponse) -> (String, Log) {
+    let log = Log::new(Mutex::default());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let data = log.clone();
+    let server = HttpServer::new(move || {
+        App::new().app_data(data.clone()).default_service(web::to(
+            move |req: HttpRequest, body: String, log: Log| async move {
+                let response = respond(&req);
+                log.lock().unwrap().push(Received {
+                    method: req.method().to_string(),
+                    path: req.path().to_string(),
+                    headers: req.headers().clone(),
+                    body,
+                });
+                response
+            },
+        ))
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    actix_web::rt::spawn(server);
+    (base, log)
+}
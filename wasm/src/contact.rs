@@ -4,11 +4,15 @@
 #![allow(clippy::module_name_repetitions)]
 #![allow(clippy::missing_panics_doc)]
 
-use js_sys::Date;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use js_sys::{Array, Date, Object, Reflect, Uint8Array, JSON};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{HtmlInputElement, Request, RequestInit, Response};
+use web_sys::{
+    Document, File, FormData, HtmlFormElement, HtmlInputElement, Request, RequestInit, Response,
+    UrlSearchParams, Window,
+};
 
 use crate::{active_tab, goto_page};
 
@@ -18,7 +22,81 @@ pub async fn contact() {
     active_tab("contact");
 
     // Go to the page.
-    goto_page("/contact", "/api/contact.html?ver=dIqPU546Qj4", "Contact").await;
+    goto_page("/contact", "/api/contact.html?ver=KFJ9HUDpIw0", "Contact").await;
+
+    let window = web_sys::window().expect("No global `window` exists");
+    let document = window.document().expect("Should have a document on window");
+    form_token_refresh(&window, &document).await;
+
+    // Fill the contact info selector with the methods the server knows about.
+    let Some(methods) = fetch_json(&window, "/api/contact_methods").await else {
+        return;
+    };
+    let selector = document
+        .get_element_by_id("info-selector")
+        .expect("Could not find element 'info-selector'");
+    for method in Array::from(&methods).iter() {
+        let Some(label) = Reflect::get(&method, &JsValue::from_str("label"))
+            .ok()
+            .and_then(|label| label.as_string())
+        else {
+            continue;
+        };
+        let option = document
+            .create_element("option")
+            .expect("Could not create option element");
+        option
+            .set_attribute("value", &label)
+            .expect("Could not set option value");
+        option.set_text_content(Some(&label));
+        selector
+            .append_child(&option)
+            .expect("Could not add contact method to selector");
+    }
+}
+
+/// Get a token from the server recording when the form was loaded, and put it in the form.
+async fn form_token_refresh(window: &Window, document: &Document) {
+    let Ok(response) = JsFuture::from(window.fetch_with_str("/api/contact_token")).await else {
+        return;
+    };
+    let resp: Response = response.dyn_into().unwrap();
+    if !resp.ok() {
+        return;
+    }
+    let Some(token) = JsFuture::from(resp.text().unwrap())
+        .await
+        .ok()
+        .and_then(|token| token.as_string())
+    else {
+        return;
+    };
+    let input: HtmlInputElement = document
+        .get_element_by_id("form-token")
+        .expect("Could not find element 'form-token'")
+        .dyn_into()
+        .expect("'form-token' is not an input");
+    input.set_value(&token);
+}
+
+/// GET a JSON resource, returning `None` if the request fails or the response isn't a success.
+async fn fetch_json(window: &Window, url: &str) -> Option<JsValue> {
+    let req = RequestInit::new();
+    req.set_method("GET");
+    let request = Request::new_with_str_and_init(url, &req).expect("Request could not be created");
+    request
+        .headers()
+        .set("Accept", "application/json")
+        .expect("Headers could not be set");
+
+    let response = JsFuture::from(window.fetch_with_request(&request))
+        .await
+        .ok()?;
+    let resp: Response = response.dyn_into().ok()?;
+    if !resp.ok() {
+        return None;
+    }
+    JsFuture::from(resp.json().ok()?).await.ok()
 }
 
 #[wasm_bindgen]
@@ -34,44 +112,68 @@ pub async fn contact_info() {
     let info_selector: HtmlInputElement = input_js.into();
     let selected = info_selector.value();
 
-    let contact_info;
+    let text = document
+        .get_element_by_id("info-text")
+        .expect("Could not get element with id 'info-text'");
+    text.set_text_content(None);
+    if selected == "Select" {
+        return;
+    }
 
-    if selected == "Phone" {
-        contact_info = "Dude, it's ".to_owned() + &Date::new_0().get_full_year().to_string() + ".";
-    } else if selected == "Select" {
-        contact_info = String::new();
-    } else {
-        let req = RequestInit::new();
-        req.set_method("GET");
-        let request_string = format!("/api/contact_info?method={selected}");
-        let request = Request::new_with_str_and_init(&request_string, &req)
-            .expect("Request could not be created");
-        request
-            .headers()
-            .set("Accept", "text/plain")
-            .expect("Headers could not be set");
-
-        let response = JsFuture::from(window.fetch_with_request(&request))
-            .await
-            .expect("Could not cast response as JsFuture");
+    let req = RequestInit::new();
+    req.set_method("GET");
+    let request_string = format!(
+        "/api/contact_info?method={}",
+        js_sys::encode_uri_component(&selected)
+    );
+    let request = Request::new_with_str_and_init(&request_string, &req)
+        .expect("Request could not be created");
+    request
+        .headers()
+        .set("Accept", "application/json")
+        .expect("Headers could not be set");
 
-        // `response` is a `Response` object.
-        assert!(response.is_instance_of::<Response>());
-        let resp: Response = response.dyn_into().unwrap();
+    let response = JsFuture::from(window.fetch_with_request(&request))
+        .await
+        .expect("Could not cast response as JsFuture");
 
-        // Convert this other `Promise` into a rust `Future`.
-        contact_info = JsFuture::from(resp.text().unwrap())
+    // `response` is a `Response` object.
+    assert!(response.is_instance_of::<Response>());
+    let resp: Response = response.dyn_into().unwrap();
+
+    // Methods that are hidden until a captcha is solved come back with an explanation instead.
+    if !resp.ok() {
+        let explanation = JsFuture::from(resp.text().unwrap())
             .await
             .unwrap()
             .as_string()
-            .unwrap();
+            .unwrap_or_default();
+        text.set_text_content(Some(&explanation));
+        return;
     }
 
+    let info = JsFuture::from(resp.json().unwrap()).await.unwrap();
+    let get = |key: &str| {
+        Reflect::get(&info, &JsValue::from_str(key))
+            .ok()
+            .and_then(|value| value.as_string())
+    };
+    let value = get("value").unwrap_or_default();
+
     // Show contact info.
-    let text = document
-        .get_element_by_id("info-text")
-        .expect("Could not get element with id 'info-text'");
-    text.set_inner_html(&contact_info);
+    if let Some(link) = get("link") {
+        let anchor = document
+            .create_element("a")
+            .expect("Could not create link element");
+        anchor
+            .set_attribute("href", &link)
+            .expect("Could not set link target");
+        anchor.set_text_content(Some(&value));
+        text.append_child(&anchor)
+            .expect("Could not show contact info link");
+    } else {
+        text.set_text_content(Some(&value));
+    }
 }
 
 #[wasm_bindgen]
@@ -83,8 +185,9 @@ pub async fn contact_copy() {
     // Get the text.
     let text = document
         .get_element_by_id("info-text")
-        .expect("Could not get element with id 'submit'")
-        .inner_html();
+        .expect("Could not get element with id 'info-text'")
+        .text_content()
+        .unwrap_or_default();
 
     if text.is_empty() {
         return;
@@ -123,21 +226,216 @@ pub fn contact_copy_reset() {
 }
 
 #[wasm_bindgen]
-pub fn contact_submit() {
+pub async fn contact_submit() {
     let window = web_sys::window().expect("No global `window` exists");
     let document = window.document().expect("Should have a document on window");
 
-    // Remove submit button.
-    document
+    // Hide submit button.
+    let submit = document
         .get_element_by_id("submit")
-        .expect("Could not get element with id 'submit'")
-        .remove();
+        .expect("Could not get element with id 'submit'");
+    submit
+        .set_attribute("hidden", "true")
+        .expect("Hidden attribute could not be set");
 
     // Show loading text.
     let loading = document
         .get_element_by_id("contact-loading")
         .expect("Could not get element with id 'contact-loading'");
     loading.set_class_name("contact-loading show");
+
+    clear_contact_errors(&document);
+
+    // Submit the form in the background so that nothing the user typed is lost on error.
+    let form: HtmlFormElement = document
+        .get_element_by_id("contact-form")
+        .expect("Could not get element with id 'contact-form'")
+        .dyn_into()
+        .expect("'contact-form' is not a form");
+    let error = match contact_json(&form).await {
+        Ok(body) => match post_contact(&window, &body).await {
+            Ok(()) => {
+                contact_submitted().await;
+                return;
+            }
+            Err(error) => error,
+        },
+        Err(error) => error,
+    };
+
+    show_contact_errors(&document, &error);
+    // An expired form needs a new token before it can be submitted again.
+    if Reflect::get(&error, &JsValue::from_str("error"))
+        .ok()
+        .and_then(|error| error.as_string())
+        .as_deref()
+        == Some("form_expired")
+    {
+        form_token_refresh(&window, &document).await;
+    }
+    submit
+        .remove_attribute("hidden")
+        .expect("Hidden attribute not present");
+    loading.set_class_name("contact-loading");
+}
+
+/// Send the contact form, returning the server's error body if it was refused.
+async fn post_contact(window: &Window, body: &JsValue) -> Result<(), JsValue> {
+    let req = RequestInit::new();
+    req.set_method("POST");
+    req.set_body(body);
+    let request =
+        Request::new_with_str_and_init("/api/contact", &req).expect("Request could not be created");
+    let headers = request.headers();
+    headers
+        .set("Accept", "application/json")
+        .expect("Headers could not be set");
+    headers
+        .set("Content-Type", "application/json")
+        .expect("Headers could not be set");
+
+    let Ok(response) = JsFuture::from(window.fetch_with_request(&request)).await else {
+        return Err(JsValue::NULL);
+    };
+    let resp: Response = response.dyn_into().unwrap();
+    if resp.ok() {
+        return Ok(());
+    }
+    Err(match resp.json() {
+        Ok(json) => JsFuture::from(json).await.unwrap_or(JsValue::NULL),
+        Err(_) => JsValue::NULL,
+    })
+}
+
+/// The contact form as JSON, with attached files base64 encoded.
+///
+/// If a file can't be read, the error is returned in the same shape the server uses, so it
+/// can be shown next to the attachments field.
+async fn contact_json(form: &HtmlFormElement) -> Result<JsValue, JsValue> {
+    let form_data = FormData::new_with_form(form).expect("Could not read contact form");
+    let body = Object::new();
+    let attachments = Array::new();
+    let entries = js_sys::try_iter(&form_data)
+        .expect("Could not iterate contact form")
+        .expect("Contact form is not iterable");
+    for entry in entries {
+        let entry: Array = entry.expect("Could not read contact form entry").into();
+        let name = entry.get(0);
+        let value = entry.get(1);
+        let Some(file) = value.dyn_ref::<File>() else {
+            Reflect::set(&body, &name, &value).expect("Could not set field");
+            continue;
+        };
+        // Browsers send an empty, unnamed file when nothing was chosen.
+        if file.name().is_empty() && file.size() == 0.0 {
+            continue;
+        }
+        let Ok(buffer) = JsFuture::from(file.array_buffer()).await else {
+            return Err(attachment_error(&file.name()));
+        };
+        let attachment = Object::new();
+        Reflect::set(
+            &attachment,
+            &JsValue::from_str("filename"),
+            &JsValue::from_str(&file.name()),
+        )
+        .expect("Could not set filename");
+        Reflect::set(
+            &attachment,
+            &JsValue::from_str("data"),
+            &JsValue::from_str(&STANDARD.encode(Uint8Array::new(&buffer).to_vec())),
+        )
+        .expect("Could not set file data");
+        attachments.push(&attachment);
+    }
+    Reflect::set(&body, &JsValue::from_str("attachments"), &attachments)
+        .expect("Could not set attachments");
+
+    Ok(JSON::stringify(&body)
+        .expect("Contact form could not be serialized")
+        .into())
+}
+
+/// An error for a file that couldn't be read, as `show_contact_errors` expects it.
+fn attachment_error(filename: &str) -> JsValue {
+    let message = format!("'{filename}' could not be read. Please choose it again.");
+    let fields = Object::new();
+    Reflect::set(
+        &fields,
+        &JsValue::from_str("attachments"),
+        &JsValue::from_str(&message),
+    )
+    .expect("Could not set attachment error");
+    let error = Object::new();
+    Reflect::set(
+        &error,
+        &JsValue::from_str("message"),
+        &JsValue::from_str("An attachment could not be read."),
+    )
+    .expect("Could not set error message");
+    Reflect::set(&error, &JsValue::from_str("fields"), &fields).expect("Could not set fields");
+    error.into()
+}
+
+/// Remove error messages and highlighting left over from a previous submission.
+fn clear_contact_errors(document: &Document) {
+    let errors = document.get_elements_by_class_name("field-error");
+    for index in 0..errors.length() {
+        if let Some(error) = errors.item(index) {
+            error.set_text_content(None);
+        }
+    }
+
+    let invalid = document.get_elements_by_class_name("invalid");
+    // Removing the class removes the element from the live collection, so go backwards.
+    for index in (0..invalid.length()).rev() {
+        if let Some(input) = invalid.item(index) {
+            input
+                .class_list()
+                .remove_1("invalid")
+                .expect("Could not remove 'invalid' class");
+        }
+    }
+}
+
+/// Show the errors returned by the server next to the fields they refer to.
+fn show_contact_errors(document: &Document, error: &JsValue) {
+    let get = |key: &str| Reflect::get(error, &JsValue::from_str(key)).ok();
+
+    let message = get("message")
+        .and_then(|message| message.as_string())
+        .unwrap_or_else(|| "Something went wrong. Please try again later.".to_string());
+    document
+        .get_element_by_id("contact-error")
+        .expect("Could not get element with id 'contact-error'")
+        .set_text_content(Some(&message));
+
+    if let Some(fields) = get("fields").and_then(|fields| fields.dyn_into::<Object>().ok()) {
+        for entry in Object::entries(&fields).iter() {
+            let entry: Array = entry.into();
+            let (Some(field), Some(message)) = (entry.get(0).as_string(), entry.get(1).as_string())
+            else {
+                continue;
+            };
+            if let Some(field_error) = document.get_element_by_id(&format!("{field}-error")) {
+                field_error.set_text_content(Some(&message));
+            }
+            if let Ok(Some(input)) = document.query_selector(&format!("[name='{field}']")) {
+                input
+                    .class_list()
+                    .add_1("invalid")
+                    .expect("Could not add 'invalid' class");
+            }
+        }
+    }
+
+    // The server discards a captcha once it has been checked, so a new one is needed.
+    if matches!(
+        get("error").and_then(|error| error.as_string()).as_deref(),
+        Some("captcha_failed" | "pgp_key_rejected")
+    ) {
+        captcha_reset();
+    }
 }
 
 #[wasm_bindgen]
@@ -148,7 +446,7 @@ pub async fn contact_submitted() {
     // Go to the page.
     goto_page(
         "/contact-submitted",
-        "/api/contact_submitted.html?ver=ypBIrFi5QPY",
+        "/api/contact_submitted.html?ver=21A9-bds6QE",
         "Submitted",
     )
     .await;
@@ -195,8 +493,9 @@ pub async fn captcha_submit() {
         // Hide captcha control stuff.
         document
             .get_element_by_id("captcha-buttons")
-            .expect("Could not find element 'captcha-pass'")
-            .remove();
+            .expect("Could not find element 'captcha-buttons'")
+            .set_attribute("style", "display: none")
+            .expect("Style attribute could not be set");
         document
             .get_element_by_id("captcha-chars")
             .expect("Could not find element 'captcha-pass'")
@@ -214,6 +513,8 @@ pub async fn captcha_submit() {
             .expect("Could not find element 'captcha-pass'")
             .remove_attribute("hidden")
             .expect("Hidden attribute not present");
+        // Reveal contact info that was waiting on the captcha.
+        contact_info().await;
     } else {
         // Show try again.
         document
@@ -236,3 +537,126 @@ pub fn captcha_refresh() {
         .set_attribute("src", &url)
         .expect("Could not set hidden attribute");
 }
+
+/// Show a fresh captcha and hide the submit button until it is solved.
+#[wasm_bindgen]
+pub fn captcha_reset() {
+    let window = web_sys::window().expect("No global `window` exists");
+    let document = window.document().expect("Should have a document on window");
+
+    // Show captcha control stuff.
+    document
+        .get_element_by_id("captcha-buttons")
+        .expect("Could not find element 'captcha-buttons'")
+        .remove_attribute("style")
+        .expect("Could not remove style attribute");
+    let input_js: JsValue = document
+        .get_element_by_id("captcha-chars")
+        .expect("Could not find element 'captcha-chars'")
+        .into();
+    let captcha_input: HtmlInputElement = input_js.into();
+    captcha_input.set_value("");
+    captcha_input
+        .remove_attribute("hidden")
+        .expect("Could not remove hidden attribute");
+
+    // Hide pass checkmark, try again text and submit button.
+    for id in ["captcha-pass", "try-again", "submit"] {
+        document
+            .get_element_by_id(id)
+            .expect("Could not find captcha element")
+            .set_attribute("hidden", "true")
+            .expect("Hidden attribute could not be set");
+    }
+
+    captcha_refresh();
+}
+
+#[wasm_bindgen]
+pub async fn contact_verify() {
+    // Set active tab.
+    active_tab("contact");
+
+    // The token is only in the URL of the link that was emailed, so read it before navigating.
+    let window = web_sys::window().expect("No global `window` exists");
+    let search = window.location().search().unwrap_or_default();
+    let token = UrlSearchParams::new_with_str(&search)
+        .ok()
+        .and_then(|params| params.get("token"))
+        .unwrap_or_default();
+
+    // Go to the page.
+    goto_page(
+        "/contact-verify",
+        "/api/contact_verify.html?ver=j7qnZKp2cEM",
+        "Confirm Message",
+    )
+    .await;
+
+    let document = window.document().expect("Should have a document on window");
+    let input: HtmlInputElement = document
+        .get_element_by_id("verify-token")
+        .expect("Could not get element with id 'verify-token'")
+        .dyn_into()
+        .expect("'verify-token' is not an input");
+    input.set_value(&token);
+}
+
+#[wasm_bindgen]
+pub async fn contact_verify_submit() {
+    let window = web_sys::window().expect("No global `window` exists");
+    let document = window.document().expect("Should have a document on window");
+
+    let submit = document
+        .get_element_by_id("verify-submit")
+        .expect("Could not get element with id 'verify-submit'");
+    submit
+        .set_attribute("hidden", "true")
+        .expect("Hidden attribute could not be set");
+
+    let token: HtmlInputElement = document
+        .get_element_by_id("verify-token")
+        .expect("Could not get element with id 'verify-token'")
+        .dyn_into()
+        .expect("'verify-token' is not an input");
+    let body = UrlSearchParams::new().expect("Could not create form body");
+    body.append("token", &token.value());
+
+    let req = RequestInit::new();
+    req.set_method("POST");
+    req.set_body(&body);
+    let request = Request::new_with_str_and_init("/api/contact_verify", &req)
+        .expect("Request could not be created");
+    request
+        .headers()
+        .set("Accept", "application/json")
+        .expect("Headers could not be set");
+
+    // Both success and error responses carry a message to show.
+    let (ok, reply) = match JsFuture::from(window.fetch_with_request(&request)).await {
+        Ok(response) => {
+            let resp: Response = response.dyn_into().unwrap();
+            let reply = match resp.json() {
+                Ok(json) => JsFuture::from(json).await.unwrap_or(JsValue::NULL),
+                Err(_) => JsValue::NULL,
+            };
+            (resp.ok(), reply)
+        }
+        Err(_) => (false, JsValue::NULL),
+    };
+    let message = Reflect::get(&reply, &JsValue::from_str("message"))
+        .ok()
+        .and_then(|message| message.as_string())
+        .unwrap_or_else(|| "Something went wrong. Please try again later.".to_string());
+    document
+        .get_element_by_id("verify-text")
+        .expect("Could not get element with id 'verify-text'")
+        .set_text_content(Some(&message));
+
+    // A failed request can be retried, but a rejected link won't get any better.
+    if !ok && reply.is_null() {
+        submit
+            .remove_attribute("hidden")
+            .expect("Hidden attribute not present");
+    }
+}
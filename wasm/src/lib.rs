@@ -266,6 +266,7 @@ pub fn route(rt: &str) {
         "/contact" => spawn_local(contact()),
         "/coming-soon" => spawn_local(coming_soon()),
         "/contact-submitted" => spawn_local(contact_submitted()),
+        "/contact-verify" => spawn_local(contact_verify()),
         "/projects/acceptxmr" => spawn_local(acceptxmr()),
         "/projects/amplifier-optimizer" => spawn_local(amplifier_optimizer()),
         "/projects/mnist-tutorial" => spawn_local(mnist_tutorial()),
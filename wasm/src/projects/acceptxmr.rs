@@ -1,16 +1,76 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use gloo_timers::future::TimeoutFuture;
+use js_sys::{Array, Function, Math, Object, Promise, Reflect, JSON};
+use shared::{format_cents, format_xmr_short, InvoiceStatus, InvoiceUpdate, UpdateError};
 use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{
+    CloseEvent, Document, EventSource, HtmlInputElement, HtmlSelectElement, HtmlTextAreaElement,
+    MessageEvent, Request, RequestInit, Response, WebSocket, Window,
+};
 
 use crate::{active_tab, goto_page};
 
+/// First delay before reconnecting, in milliseconds. Doubles with every failure.
+const BACKOFF_BASE_MS: u32 = 500;
+/// Longest delay before reconnecting, in milliseconds.
+const BACKOFF_MAX_MS: u32 = 30_000;
+/// Websocket connections that never open before giving up on them and polling instead.
+const MAX_UNOPENED_SOCKETS: u32 = 3;
+/// Time between polls of `/update` when websockets don't work, in milliseconds.
+const POLL_INTERVAL_MS: u32 = 5_000;
+/// How long to wait for qrcode.js to load, in milliseconds.
+const QRCODE_LOAD_TIMEOUT_MS: u32 = 5_000;
+/// Blocks before expiration at which buyers are told to get a new address instead.
+const EXPIRING_SOON_BLOCKS: u64 = 2;
+
+#[wasm_bindgen]
+extern "C" {
+    /// A QR code from the vendored qrcode.js.
+    type QrCode;
+
+    #[wasm_bindgen(catch, js_name = qrcode)]
+    fn new_qrcode(type_number: u32, error_correction_level: &str) -> Result<QrCode, JsValue>;
+
+    #[wasm_bindgen(method, js_name = addData)]
+    fn add_data(this: &QrCode, data: &str);
+
+    #[wasm_bindgen(method)]
+    fn make(this: &QrCode);
+
+    #[wasm_bindgen(method, js_name = createSvgTag)]
+    fn create_svg_tag(this: &QrCode, options: &JsValue) -> String;
+}
+
+thread_local! {
+    /// The catalog, as last fetched from the server.
+    static CATALOG: RefCell<JsValue> = const { RefCell::new(JsValue::NULL) };
+    /// Incremented whenever a different invoice is watched, so that anything still watching the
+    /// previous one stops.
+    static WATCH: Cell<u32> = const { Cell::new(0) };
+    /// The open websocket, if any.
+    static SOCKET: RefCell<Option<WebSocket>> = const { RefCell::new(None) };
+    /// The open server-sent event stream, if any, and the function ending its session.
+    static EVENTS: RefCell<Option<(EventSource, Function)>> = const { RefCell::new(None) };
+}
+
 #[wasm_bindgen]
 pub async fn acceptxmr() {
     // Set active tab.
     active_tab("");
 
+    // Stop watching any invoice from an earlier visit to the page.
+    let generation = next_watch();
+
     // Go to the page.
     goto_page(
         "/projects/acceptxmr",
-        "/api/projects/acceptxmr/acceptxmr.html?ver=vZXIvi27QXk",
+        "/api/projects/acceptxmr/acceptxmr.html?ver=E7dr_ZqHxKY",
         "AcceptXMR",
     )
     .await;
@@ -29,24 +89,728 @@ pub async fn acceptxmr() {
         )
         .expect("Could not set 'src' attribute for qrcode.js.");
 
-    // Load acceptxmr's js.
-    let acceptxmr_js = document
-        .create_element("script")
-        .expect("Could not create Unity Load script element.");
-    acceptxmr_js
-        .set_attribute(
-            "src",
-            "/api/projects/acceptxmr/acceptxmr.js?ver=gKWo5aPbPdQ",
-        )
-        .expect("Could not set 'src' attribute for acceptxmr.js.");
-
     if let Some(head) = document.get_elements_by_tag_name("head").item(0) {
         head.append_with_node_1(&qrcode_js)
             .expect("Could not append qrcode js script to document");
     }
 
-    if let Some(head) = document.get_elements_by_tag_name("head").item(0) {
-        head.append_with_node_1(&acceptxmr_js)
-            .expect("Could not append acceptxmr js script to document");
+    load_catalog(&window, &document).await;
+    qrcode_loaded().await;
+
+    // Pick up where we left off if there is already an invoice.
+    match refresh(&window, &document).await {
+        Refresh::Pending => {
+            show_payment(&document);
+            spawn_local(watch(generation));
+        }
+        Refresh::Finished => show_payment(&document),
+        Refresh::Gone | Refresh::Failed => {}
     }
 }
+
+/// Show the payment details and create an invoice.
+#[wasm_bindgen]
+pub async fn acceptxmr_next() {
+    let window = web_sys::window().expect("No global `window` exists");
+    let document = window.document().expect("Should have a document on window");
+
+    show_payment(&document);
+    set_instruction(&document, "Loading...", false);
+    acceptxmr_new_address().await;
+}
+
+/// Create a new invoice and start watching it.
+#[wasm_bindgen]
+pub async fn acceptxmr_new_address() {
+    let window = web_sys::window().expect("No global `window` exists");
+    let document = window.document().expect("Should have a document on window");
+    let generation = next_watch();
+
+    let req = RequestInit::new();
+    req.set_method("POST");
+    req.set_body(&checkout_json(&document));
+    let request = Request::new_with_str_and_init("/projects/acceptxmr/checkout", &req)
+        .expect("Request could not be created");
+    request
+        .headers()
+        .set("Content-Type", "application/json")
+        .expect("Headers could not be set");
+
+    let error = match JsFuture::from(window.fetch_with_request(&request)).await {
+        Ok(response) => {
+            let resp: Response = response.dyn_into().unwrap();
+            if resp.ok() {
+                None
+            } else {
+                Some(
+                    JsFuture::from(resp.text().unwrap())
+                        .await
+                        .ok()
+                        .and_then(|text| text.as_string())
+                        .unwrap_or_default(),
+                )
+            }
+        }
+        Err(_) => Some("Could not reach the server. Please try again later.".to_string()),
+    };
+    if let Some(error) = error {
+        // Go back to the form, showing what was wrong with it.
+        show_element(&document, "preperation-content", "inherit");
+        show_element(&document, "payment-content", "None");
+        set_instruction(&document, &error, false);
+        return;
+    }
+
+    spawn_local(watch(generation));
+    refresh(&window, &document).await;
+}
+
+/// Show the total price of the selected item and quantity.
+#[wasm_bindgen]
+pub fn acceptxmr_show_total() {
+    let window = web_sys::window().expect("No global `window` exists");
+    let document = window.document().expect("Should have a document on window");
+
+    let item_id = select_value(&document, "item");
+    let quantity = input_value(&document, "quantity").parse::<u64>().ok();
+    let total = CATALOG.with(|catalog| {
+        let catalog = catalog.borrow();
+        let price = catalog_items(&catalog)
+            .iter()
+            .find(|item| get_string(item, "id").as_deref() == Some(item_id.as_str()))
+            .and_then(|item| get_u64(&item, "price_cents"))?;
+        Some(format_cents(
+            price.checked_mul(quantity.filter(|q| *q > 0)?)?,
+        ))
+    });
+    set_text(
+        &document,
+        "total-price",
+        &total.unwrap_or_else(|| "-".to_string()),
+    );
+}
+
+#[wasm_bindgen]
+pub async fn acceptxmr_copy_address() {
+    let window = web_sys::window().expect("No global `window` exists");
+    let document = window.document().expect("Should have a document on window");
+
+    let address = document
+        .get_element_by_id("address")
+        .expect("Could not get element with id 'address'")
+        .text_content()
+        .unwrap_or_default();
+    let mut feedback = "Error!"; // Default to error.
+    if JsFuture::from(window.navigator().clipboard().write_text(&address))
+        .await
+        .is_ok()
+    {
+        feedback = "Copied!";
+    }
+    set_text(&document, "address-copy-btn", feedback);
+
+    window
+        .set_timeout_with_str_and_timeout_and_unused_0(
+            "window.busy.acceptxmr_copy_address_reset()",
+            1000,
+        )
+        .expect("Could not set timeout for copy feedback");
+}
+
+#[wasm_bindgen]
+pub fn acceptxmr_copy_address_reset() {
+    let window = web_sys::window().expect("No global `window` exists");
+    let document = window.document().expect("Should have a document on window");
+    set_text(&document, "address-copy-btn", "Copy");
+}
+
+/// Fill the item selection from the catalog.
+async fn load_catalog(window: &Window, document: &Document) {
+    let Ok(response) = JsFuture::from(window.fetch_with_str("/projects/acceptxmr/catalog")).await
+    else {
+        return;
+    };
+    let resp: Response = response.dyn_into().unwrap();
+    let Ok(catalog) = JsFuture::from(resp.json().unwrap()).await else {
+        return;
+    };
+
+    let currency = get_string(&catalog, "currency").unwrap_or_default();
+    let select = document
+        .get_element_by_id("item")
+        .expect("Could not get element with id 'item'");
+    select.set_inner_html("");
+    for item in catalog_items(&catalog).iter() {
+        let option = document
+            .create_element("option")
+            .expect("Could not create option element");
+        option
+            .set_attribute("value", &get_string(&item, "id").unwrap_or_default())
+            .expect("Could not set option value");
+        option.set_text_content(Some(&format!(
+            "{} ({} {currency})",
+            get_string(&item, "name").unwrap_or_default(),
+            format_cents(get_u64(&item, "price_cents").unwrap_or_default())
+        )));
+        select
+            .append_child(&option)
+            .expect("Could not add item to selection");
+    }
+    if let Some(max_quantity) = get_u64(&catalog, "max_quantity") {
+        document
+            .get_element_by_id("quantity")
+            .expect("Could not get element with id 'quantity'")
+            .set_attribute("max", &max_quantity.to_string())
+            .expect("Could not set maximum quantity");
+    }
+    set_text(document, "currency", &currency);
+
+    CATALOG.with(|stored| *stored.borrow_mut() = catalog);
+    acceptxmr_show_total();
+}
+
+fn catalog_items(catalog: &JsValue) -> Array {
+    Reflect::get(catalog, &JsValue::from_str("items"))
+        .ok()
+        .filter(Array::is_array)
+        .map(|items| Array::from(&items))
+        .unwrap_or_default()
+}
+
+/// The checkout form as JSON.
+fn checkout_json(document: &Document) -> JsValue {
+    let body = Object::new();
+    let set = |key: &str, value: &JsValue| {
+        Reflect::set(&body, &JsValue::from_str(key), value).expect("Could not set field");
+    };
+    set("email", &JsValue::from_str(&input_value(document, "email")));
+    let message = document
+        .get_element_by_id("message")
+        .and_then(|element| element.dyn_into::<HtmlTextAreaElement>().ok())
+        .map(|message| message.value())
+        .unwrap_or_default();
+    set("message", &JsValue::from_str(&message));
+    set(
+        "item_id",
+        &JsValue::from_str(&select_value(document, "item")),
+    );
+    let quantity = input_value(document, "quantity")
+        .parse::<u32>()
+        .unwrap_or(0);
+    set("quantity", &JsValue::from(quantity));
+
+    JSON::stringify(&body)
+        .expect("Checkout form could not be serialized")
+        .into()
+}
+
+/// Start a new watch, stopping anything watching an earlier invoice.
+fn next_watch() -> u32 {
+    close_socket("New Address");
+    WATCH.with(|watch| {
+        watch.set(watch.get().wrapping_add(1));
+        watch.get()
+    })
+}
+
+/// Whether `generation` is still the invoice to watch, and the page is still showing it.
+fn is_current(generation: u32) -> bool {
+    let on_page = web_sys::window()
+        .and_then(|window| window.document())
+        .and_then(|document| document.get_element_by_id("payment-content"))
+        .is_some();
+    on_page && WATCH.with(Cell::get) == generation
+}
+
+fn close_socket(reason: &str) {
+    if let Some(socket) = SOCKET.with(|socket| socket.borrow_mut().take()) {
+        let _ = socket.close_with_code_and_reason(1000, reason);
+    }
+    // Closing an event source fires no events, so end its session directly.
+    if let Some((source, end_session)) = EVENTS.with(|events| events.borrow_mut().take()) {
+        source.close();
+        let _ = end_session.call0(&JsValue::NULL);
+    }
+}
+
+/// How a websocket or event stream connection ended.
+enum SocketEnd {
+    /// The invoice is finished, or a different one is being watched.
+    Done,
+    /// The connection was lost or refused.
+    Dropped { opened: bool },
+}
+
+/// Follow the invoice over a websocket, reconnecting with backoff when the connection drops and
+/// polling instead if websockets never connect at all.
+async fn watch(generation: u32) {
+    let window = web_sys::window().expect("No global `window` exists");
+    let document = window.document().expect("Should have a document on window");
+    show_element(&document, "payment-pending-loader", "Block");
+
+    let mut failures = 0;
+    let mut unopened = 0;
+    while is_current(generation) {
+        match socket_session(generation).await {
+            SocketEnd::Done => break,
+            SocketEnd::Dropped { opened: true } => {
+                failures = 1;
+                unopened = 0;
+            }
+            SocketEnd::Dropped { opened: false } => {
+                failures += 1;
+                unopened += 1;
+            }
+        }
+        if unopened >= MAX_UNOPENED_SOCKETS {
+            web_sys::console::log_1(&"Websockets unavailable, using server-sent events.".into());
+            if let SocketEnd::Dropped { .. } = events_session(generation).await {
+                if is_current(generation) {
+                    web_sys::console::log_1(&"Server-sent events unavailable, polling.".into());
+                    poll(generation).await;
+                }
+            }
+            break;
+        }
+        TimeoutFuture::new(backoff_ms(failures)).await;
+        // Catch up on anything missed while disconnected.
+        if !is_current(generation) {
+            break;
+        }
+        if let Refresh::Finished | Refresh::Gone = refresh(&window, &document).await {
+            break;
+        }
+    }
+
+    if is_current(generation) {
+        show_element(&document, "payment-pending-loader", "None");
+    }
+}
+
+/// Poll `/update` until the invoice is finished.
+async fn poll(generation: u32) {
+    let window = web_sys::window().expect("No global `window` exists");
+    let document = window.document().expect("Should have a document on window");
+
+    let mut failures = 0;
+    while is_current(generation) {
+        let delay = match refresh(&window, &document).await {
+            Refresh::Pending => {
+                failures = 0;
+                POLL_INTERVAL_MS
+            }
+            Refresh::Failed => {
+                failures += 1;
+                backoff_ms(failures).max(POLL_INTERVAL_MS)
+            }
+            Refresh::Finished | Refresh::Gone => return,
+        };
+        TimeoutFuture::new(delay).await;
+    }
+}
+
+/// Delay before the next attempt after `failures` failures in a row: exponential, capped, and
+/// jittered so that clients disconnected together don't all come back at once.
+fn backoff_ms(failures: u32) -> u32 {
+    let ceiling = BACKOFF_BASE_MS
+        .saturating_mul(1 << failures.min(16))
+        .min(BACKOFF_MAX_MS);
+    ceiling / 2 + (Math::random() * f64::from(ceiling / 2)) as u32
+}
+
+/// Open a websocket for the invoice and show its updates until it closes.
+async fn socket_session(generation: u32) -> SocketEnd {
+    let location = web_sys::window()
+        .expect("No global `window` exists")
+        .location();
+    let protocol = match location.protocol().as_deref() {
+        Ok("https:") => "wss:",
+        _ => "ws:",
+    };
+    let url = format!(
+        "{protocol}//{}/projects/acceptxmr/ws/",
+        location.host().unwrap_or_default()
+    );
+    let Ok(socket) = WebSocket::new(&url) else {
+        return SocketEnd::Dropped { opened: false };
+    };
+    SOCKET.with(|stored| *stored.borrow_mut() = Some(socket.clone()));
+
+    let opened = Rc::new(Cell::new(false));
+    let finished = Rc::new(Cell::new(false));
+    let onopen = Closure::<dyn FnMut()>::new({
+        let opened = opened.clone();
+        move || opened.set(true)
+    });
+    let onmessage = Closure::<dyn FnMut(MessageEvent)>::new({
+        let finished = finished.clone();
+        let socket = socket.clone();
+        move |event: MessageEvent| {
+            let Some(text) = event.data().as_string() else {
+                return;
+            };
+            if !is_current(generation) {
+                let _ = socket.close_with_code_and_reason(1000, "Page Left");
+                return;
+            }
+            let document = web_sys::window()
+                .and_then(|window| window.document())
+                .expect("Should have a document on window");
+            if let Some(reason) = parse_update(&text).and_then(|update| display(&document, &update))
+            {
+                finished.set(true);
+                let _ = socket.close_with_code_and_reason(1000, reason);
+            }
+        }
+    });
+    socket.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+    socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+    let closed = Promise::new(&mut |resolve, _reject| socket.set_onclose(Some(&resolve)));
+
+    let close: Option<CloseEvent> = JsFuture::from(closed)
+        .await
+        .ok()
+        .and_then(|event| event.dyn_into().ok());
+    socket.set_onopen(None);
+    socket.set_onmessage(None);
+    socket.set_onclose(None);
+    SOCKET.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        if stored.as_ref() == Some(&socket) {
+            *stored = None;
+        }
+    });
+
+    // Only a finished invoice or a deliberate close ends the connection cleanly.
+    if finished.get() || close.map(|close| close.code()) == Some(1000) {
+        SocketEnd::Done
+    } else {
+        SocketEnd::Dropped {
+            opened: opened.get(),
+        }
+    }
+}
+
+/// Follow the invoice with server-sent events. Browsers reconnect these by themselves, resuming
+/// from the last event ID, so this only returns once the invoice is finished or the stream fails
+/// for good.
+async fn events_session(generation: u32) -> SocketEnd {
+    let Ok(source) = EventSource::new("/projects/acceptxmr/events") else {
+        return SocketEnd::Dropped { opened: false };
+    };
+    let mut end_session = None;
+    let ended = Promise::new(&mut |resolve, _reject| end_session = Some(resolve));
+    let end_session = end_session.expect("Promise executors run immediately");
+    EVENTS.with(|stored| *stored.borrow_mut() = Some((source.clone(), end_session.clone())));
+
+    let opened = Rc::new(Cell::new(false));
+    let finished = Rc::new(Cell::new(false));
+    let end = {
+        let source = source.clone();
+        move || {
+            source.close();
+            let _ = end_session.call0(&JsValue::NULL);
+        }
+    };
+    let onopen = Closure::<dyn FnMut()>::new({
+        let opened = opened.clone();
+        move || opened.set(true)
+    });
+    let onupdate = Closure::<dyn FnMut(MessageEvent)>::new({
+        let end = end.clone();
+        move |event: MessageEvent| {
+            if !is_current(generation) {
+                end();
+                return;
+            }
+            let Some(text) = event.data().as_string() else {
+                return;
+            };
+            let document = web_sys::window()
+                .and_then(|window| window.document())
+                .expect("Should have a document on window");
+            if let Some(update) = parse_update(&text) {
+                display(&document, &update);
+            }
+        }
+    });
+    // The server sends `close` once the invoice is finished, then ends the stream.
+    let onclose = Closure::<dyn FnMut()>::new({
+        let finished = finished.clone();
+        let end = end.clone();
+        move || {
+            finished.set(true);
+            end();
+        }
+    });
+    // Browsers retry on their own unless the stream was refused.
+    let onerror = Closure::<dyn FnMut()>::new({
+        let source = source.clone();
+        move || {
+            if source.ready_state() == EventSource::CLOSED || !is_current(generation) {
+                end();
+            }
+        }
+    });
+    source.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+    source.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+    source
+        .add_event_listener_with_callback("update", onupdate.as_ref().unchecked_ref())
+        .expect("Could not listen for updates");
+    source
+        .add_event_listener_with_callback("close", onclose.as_ref().unchecked_ref())
+        .expect("Could not listen for close");
+
+    let _ = JsFuture::from(ended).await;
+    source.set_onopen(None);
+    source.set_onerror(None);
+    let _ = source.remove_event_listener_with_callback("update", onupdate.as_ref().unchecked_ref());
+    let _ = source.remove_event_listener_with_callback("close", onclose.as_ref().unchecked_ref());
+    EVENTS.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        if stored.as_ref().map(|(stored, _)| stored) == Some(&source) {
+            *stored = None;
+        }
+    });
+
+    if finished.get() || !is_current(generation) {
+        SocketEnd::Done
+    } else {
+        SocketEnd::Dropped {
+            opened: opened.get(),
+        }
+    }
+}
+
+/// What fetching the invoice from `/update` found.
+enum Refresh {
+    /// The invoice is still waiting on something.
+    Pending,
+    /// The invoice is complete.
+    Finished,
+    /// There is no invoice.
+    Gone,
+    Failed,
+}
+
+/// Fetch the invoice from `/update` and show it.
+async fn refresh(window: &Window, document: &Document) -> Refresh {
+    let Ok(response) = JsFuture::from(window.fetch_with_str("/update")).await else {
+        return Refresh::Failed;
+    };
+    let resp: Response = response.dyn_into().unwrap();
+    if resp.status() == 410 {
+        return Refresh::Gone;
+    }
+    if !resp.ok() {
+        return Refresh::Failed;
+    }
+    let Some(text) = JsFuture::from(resp.text().unwrap())
+        .await
+        .ok()
+        .and_then(|text| text.as_string())
+    else {
+        return Refresh::Failed;
+    };
+    match parse_update(&text) {
+        Some(update) => match display(document, &update) {
+            Some(_) => Refresh::Finished,
+            None => Refresh::Pending,
+        },
+        None => Refresh::Failed,
+    }
+}
+
+/// Parse an invoice update, reloading the page if it comes from a newer server.
+fn parse_update(text: &str) -> Option<InvoiceUpdate> {
+    match InvoiceUpdate::from_json(text) {
+        Ok(update) => Some(update),
+        Err(e) => {
+            web_sys::console::log_1(&e.to_string().into());
+            if let UpdateError::UnsupportedVersion(_) = e {
+                close_socket("Reloading");
+                let _ = web_sys::window()
+                    .expect("No global `window` exists")
+                    .location()
+                    .reload();
+            }
+            None
+        }
+    }
+}
+
+/// Show an invoice update. Returns the reason to stop watching if the invoice is finished.
+fn display(document: &Document, update: &InvoiceUpdate) -> Option<&'static str> {
+    // Show paid/due.
+    set_text(document, "paid", &format_xmr_short(update.amount_paid));
+    set_text(document, "due", &format_xmr_short(update.amount_requested));
+
+    // Show confirmations/required.
+    set_text(
+        document,
+        "confirmations",
+        &update.confirmations.unwrap_or_default().to_string(),
+    );
+    set_text(
+        document,
+        "confirmations-required",
+        &update.confirmations_required.to_string(),
+    );
+
+    // Show the exchange rate the invoice was priced at.
+    if let Some(rate) = &update.exchange_rate {
+        set_text(
+            document,
+            "rate",
+            &format!(
+                "{} {currency} at {} {currency}/XMR",
+                format_cents(rate.total_cents),
+                rate.rate,
+                currency = rate.currency
+            ),
+        );
+    }
+
+    // Show instructive text depending on invoice state.
+    let expiring = update.expiration_in <= EXPIRING_SOON_BLOCKS;
+    let (instruction, warning, close_reason) = match update.status {
+        InvoiceStatus::Confirmed => ("Paid! Thank you", false, Some("Confirmed")),
+        InvoiceStatus::Overpaid => ("Overpaid! Thank you", false, Some("Confirmed")),
+        InvoiceStatus::PaidLate => ("Paid After Expiring! Thank you", false, Some("Confirmed")),
+        InvoiceStatus::PaidUnconfirmed => ("Paid! Waiting for Confirmation...", false, None),
+        // The server watches expired invoices a while longer, so late payments still show up.
+        InvoiceStatus::Expired => ("Address Expired!", false, None),
+        InvoiceStatus::ExpiredUnderpaid => (
+            "Expired Before Paid in Full! Send the Rest Soon to Address Below",
+            true,
+            None,
+        ),
+        _ if expiring => ("Address Expiring Soon", true, None),
+        InvoiceStatus::Underpaid => ("Partly Paid, Send the Rest to Address Below", false, None),
+        InvoiceStatus::Pending => ("Send Monero to Address Below", false, None),
+    };
+    set_instruction(document, instruction, warning);
+
+    // Hide address if nearing expiration. A buyer who paid part of an expired invoice can still
+    // send the rest, since late payments count.
+    let show_address = match update.status {
+        InvoiceStatus::Expired => false,
+        InvoiceStatus::Pending | InvoiceStatus::Underpaid => !expiring,
+        _ => true,
+    };
+    set_hidden(document, "new-address-btn", show_address);
+    set_disabled(document, "address-copy-btn", !show_address);
+    let qrcode_container = document
+        .get_element_by_id("qrcode-container")
+        .expect("Could not get element with id 'qrcode-container'");
+    if show_address {
+        set_text(document, "address", &update.address);
+        qrcode_container.set_inner_html(&qrcode_svg(&update.uri).unwrap_or_default());
+    } else {
+        set_text(document, "address", "Expiring or expired...");
+        qrcode_container.set_inner_html("<svg viewBox=\"0 0 100 100\" src=\"\"></svg>");
+    }
+
+    close_reason
+}
+
+/// Draw `data` as an SVG QR code, if qrcode.js has loaded.
+fn qrcode_svg(data: &str) -> Option<String> {
+    let qr = new_qrcode(0, "M").ok()?;
+    qr.add_data(data);
+    qr.make();
+    let options = Object::new();
+    Reflect::set(&options, &JsValue::from_str("scalable"), &JsValue::TRUE)
+        .expect("Could not set QR code options");
+    Some(qr.create_svg_tag(&options))
+}
+
+/// Wait for qrcode.js to load, so the first update shows a QR code.
+async fn qrcode_loaded() {
+    let mut waited = 0;
+    while qrcode_svg("").is_none() && waited < QRCODE_LOAD_TIMEOUT_MS {
+        TimeoutFuture::new(100).await;
+        waited += 100;
+    }
+}
+
+fn show_payment(document: &Document) {
+    show_element(document, "preperation-content", "None");
+    show_element(document, "payment-content", "inherit");
+}
+
+fn set_instruction(document: &Document, text: &str, warning: bool) {
+    let instruction = document
+        .get_element_by_id("instruction")
+        .expect("Could not get element with id 'instruction'");
+    instruction.set_text_content(Some(text));
+    instruction.set_class_name(if warning {
+        "acceptxmr-instruction warning"
+    } else {
+        "acceptxmr-instruction"
+    });
+}
+
+fn show_element(document: &Document, id: &str, display: &str) {
+    if let Some(element) = document.get_element_by_id(id) {
+        element
+            .set_attribute("style", &format!("display: {display}"))
+            .expect("Style attribute could not be set");
+    }
+}
+
+fn set_text(document: &Document, id: &str, text: &str) {
+    if let Some(element) = document.get_element_by_id(id) {
+        element.set_text_content(Some(text));
+    }
+}
+
+fn set_hidden(document: &Document, id: &str, hidden: bool) {
+    set_flag(document, id, "hidden", hidden);
+}
+
+fn set_disabled(document: &Document, id: &str, disabled: bool) {
+    set_flag(document, id, "disabled", disabled);
+}
+
+fn set_flag(document: &Document, id: &str, attribute: &str, on: bool) {
+    if let Some(element) = document.get_element_by_id(id) {
+        if on {
+            element
+                .set_attribute(attribute, "true")
+                .expect("Attribute could not be set");
+        } else {
+            let _ = element.remove_attribute(attribute);
+        }
+    }
+}
+
+fn input_value(document: &Document, id: &str) -> String {
+    document
+        .get_element_by_id(id)
+        .and_then(|element| element.dyn_into::<HtmlInputElement>().ok())
+        .map(|input| input.value())
+        .unwrap_or_default()
+}
+
+fn select_value(document: &Document, id: &str) -> String {
+    document
+        .get_element_by_id(id)
+        .and_then(|element| element.dyn_into::<HtmlSelectElement>().ok())
+        .map(|select| select.value())
+        .unwrap_or_default()
+}
+
+fn get_string(value: &JsValue, key: &str) -> Option<String> {
+    Reflect::get(value, &JsValue::from_str(key))
+        .ok()
+        .and_then(|value| value.as_string())
+}
+
+fn get_u64(value: &JsValue, key: &str) -> Option<u64> {
+    Reflect::get(value, &JsValue::from_str(key))
+        .ok()
+        .and_then(|value| value.as_f64())
+        .filter(|number| number.fract() == 0.0 && *number >= 0.0)
+        .map(|number| number as u64)
+}